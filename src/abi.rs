@@ -0,0 +1,97 @@
+//! Register names and the calling convention.
+//!
+//! Any register can be written `$0` to `$31`. The assembler also knows these
+//! names, which set out how routines share the registers:
+//!
+//! | Name            | Register   | Use                                    | Saved by |
+//! |-----------------|------------|----------------------------------------|----------|
+//! | `$a0` – `$a3`   | `$0`–`$3`  | Arguments; `$a0` holds the result       | caller   |
+//! | `$t0` – `$t9`   | `$4`–`$13` | Temporaries                            | caller   |
+//! | `$s0` – `$s14`  | `$14`–`$28`| Values that must survive calls         | callee   |
+//! | `$fp`           | `$29`      | Frame pointer                          | callee   |
+//! | `$sp`           | `$30`      | Stack pointer                          | callee   |
+//! | `$ra`           | `$31`      | Return address, written by `call`      | caller   |
+//!
+//! `call @routine` puts the offset of the next instruction in `$ra` and jumps;
+//! `ret` jumps back to `$ra`. A routine that calls another must therefore
+//! save `$ra` first.
+//!
+//! The stack lives in the heap and grows down: `push $r` stores `$r` in the
+//! four bytes below `$sp` and lowers `$sp` by four, `pop $r` does the
+//! reverse. A program sets up its stack by growing the heap with `aloc` and
+//! pointing `$sp` at the end of it. A routine saves the callee-saved
+//! registers it writes with `push` on entry and restores them with `pop`
+//! before `ret`, leaving `$sp` as it found it.
+
+/// How many registers there are, `$0` to `$31`.
+pub const REGISTERS: u8 = 32;
+
+/// The register `call` writes the return address to.
+pub const RA: u8 = 31;
+/// The stack pointer, moved by `push` and `pop`.
+pub const SP: u8 = 30;
+pub const FP: u8 = 29;
+
+const ARGUMENTS: u8 = 0;
+const TEMPORARIES: u8 = 4;
+const SAVED: u8 = 14;
+
+/// The register a built-in name such as `sp` or `t3` stands for.
+pub fn register_number(name: &str) -> Option<u8> {
+    let numbered = |prefix: &str, first: u8, count: u8| {
+        let digits = name.strip_prefix(prefix)?;
+        let n: u8 = digits.parse().ok()?;
+        if n < count && digits == n.to_string() {
+            Some(first + n)
+        } else {
+            None
+        }
+    };
+    match name {
+        "ra" => Some(RA),
+        "sp" => Some(SP),
+        "fp" => Some(FP),
+        _ => numbered("a", ARGUMENTS, 4)
+            .or_else(|| numbered("t", TEMPORARIES, 10))
+            .or_else(|| numbered("s", SAVED, 15)),
+    }
+}
+
+/// The built-in name of register `r`, e.g. `s0` for 14.
+pub fn register_name(r: u8) -> String {
+    match r {
+        RA => "ra".to_string(),
+        SP => "sp".to_string(),
+        FP => "fp".to_string(),
+        r if r < TEMPORARIES => format!("a{}", r - ARGUMENTS),
+        r if r < SAVED => format!("t{}", r - TEMPORARIES),
+        r => format!("s{}", r - SAVED),
+    }
+}
+
+/// Whether a routine must leave `r` as it found it.
+pub fn is_callee_saved(r: u8) -> bool {
+    (SAVED..=SP).contains(&r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_names() {
+        assert_eq!(register_number("a0"), Some(0));
+        assert_eq!(register_number("t9"), Some(13));
+        assert_eq!(register_number("s14"), Some(28));
+        assert_eq!(register_number("sp"), Some(30));
+        assert_eq!(register_number("t10"), None);
+        assert_eq!(register_number("a+1"), None);
+        assert_eq!(register_number("a01"), None);
+        assert_eq!(register_number("counter"), None);
+        for r in 0..32 {
+            assert_eq!(register_number(&register_name(r)), Some(r));
+        }
+        assert!(is_callee_saved(register_number("fp").unwrap()));
+        assert!(!is_callee_saved(RA));
+    }
+}
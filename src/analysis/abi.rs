@@ -0,0 +1,160 @@
+//! Checks routines against the calling convention described in `crate::abi`.
+
+use super::cfg::{Cfg, EdgeKind};
+use crate::abi;
+use crate::instruction::Opcode;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum AbiWarning {
+    /// A routine writes a callee-saved register it never pushes.
+    ClobbersCalleeSaved {
+        routine: String,
+        offset: u32,
+        register: u8,
+    },
+    /// A routine calls another without pushing `$ra`, so cannot return.
+    UnsavedReturnAddress { routine: String, offset: u32 },
+}
+
+impl fmt::Display for AbiWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AbiWarning::ClobbersCalleeSaved {
+                routine,
+                offset,
+                register,
+            } => write!(
+                f,
+                "warning: routine `{}` writes callee-saved ${} at {:04X} without saving it",
+                routine,
+                abi::register_name(*register),
+                offset
+            ),
+            AbiWarning::UnsavedReturnAddress { routine, offset } => write!(
+                f,
+                "warning: routine `{}` calls at {:04X} without saving $ra",
+                routine, offset
+            ),
+        }
+    }
+}
+
+/// Blocks making up the routine that starts at `entry`: those reachable
+/// without following calls.
+fn routine_blocks(cfg: &Cfg, entry: usize) -> Vec<usize> {
+    let mut seen = vec![false; cfg.blocks.len()];
+    let mut stack = vec![entry];
+    while let Some(block) = stack.pop() {
+        if seen[block] {
+            continue;
+        }
+        seen[block] = true;
+        stack.extend(
+            cfg.edges
+                .iter()
+                .filter(|e| e.from == block && e.kind != EdgeKind::Call)
+                .map(|e| e.to),
+        );
+    }
+    (0..cfg.blocks.len()).filter(|b| seen[*b]).collect()
+}
+
+/// Warns about routines, the targets of `call`, that do not keep to the
+/// calling convention. A register counts as saved if the routine pushes it
+/// anywhere.
+pub fn check(cfg: &Cfg) -> Vec<AbiWarning> {
+    let mut entries: Vec<usize> = cfg
+        .edges
+        .iter()
+        .filter(|e| e.kind == EdgeKind::Call)
+        .map(|e| e.to)
+        .collect();
+    entries.sort_unstable();
+    entries.dedup();
+
+    let mut warnings = vec![];
+    for entry in entries {
+        let start = &cfg.insns[cfg.blocks[entry].start];
+        let routine = start
+            .label
+            .clone()
+            .unwrap_or_else(|| format!("{:04X}", start.offset));
+        let insns: Vec<_> = routine_blocks(cfg, entry)
+            .into_iter()
+            .flat_map(|b| cfg.block_insns(b))
+            .collect();
+        let pushed: Vec<u8> = insns
+            .iter()
+            .filter(|i| i.opcode == Opcode::PUSH)
+            .flat_map(|i| i.uses())
+            .collect();
+
+        let mut reported = vec![];
+        for i in &insns {
+            if i.opcode == Opcode::CALL
+                && !pushed.contains(&abi::RA)
+                && !reported.contains(&abi::RA)
+            {
+                reported.push(abi::RA);
+                warnings.push(AbiWarning::UnsavedReturnAddress {
+                    routine: routine.clone(),
+                    offset: i.offset,
+                });
+            }
+            let written = i.def().filter(|r| abi::is_callee_saved(*r));
+            if let Some(r) = written.filter(|r| !pushed.contains(r) && !reported.contains(r)) {
+                reported.push(r);
+                warnings.push(AbiWarning::ClobbersCalleeSaved {
+                    routine: routine.clone(),
+                    offset: i.offset,
+                    register: r,
+                });
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program_with_sources;
+
+    fn warnings(source: &str) -> Vec<String> {
+        let (mut p, _) = program_with_sources(source).unwrap();
+        assert!(p.resolve_registers().is_empty());
+        check(&Cfg::from_program(&p))
+            .iter()
+            .map(|w| w.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_routine() {
+        let source = "call @double\nhlt\n\
+                      double: push $s0\nadd $a0 $a0 $s0\nload $t0 #1\nadd $s0 $t0 $a0\npop $s0\nret";
+        assert!(warnings(source).is_empty());
+    }
+
+    #[test]
+    fn test_clobbers_callee_saved() {
+        let source = "call @f\nhlt\nf: load $s1 #1\nload $fp #2\nload $s1 #3\nret";
+        assert_eq!(
+            warnings(source),
+            vec![
+                "warning: routine `f` writes callee-saved $s1 at 0005 without saving it",
+                "warning: routine `f` writes callee-saved $fp at 0009 without saving it",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsaved_return_address() {
+        let source = "call @outer\nhlt\nouter: call @inner\nret\ninner: ret";
+        assert_eq!(
+            warnings(source),
+            vec!["warning: routine `outer` calls at 0005 without saving $ra"]
+        );
+    }
+}
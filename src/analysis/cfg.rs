@@ -0,0 +1,379 @@
+use super::{decode, from_program, Insn, Operand};
+use crate::assembler::program_parsers::Program;
+use crate::instruction::Opcode;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EdgeKind {
+    Fallthrough,
+    /// An unconditional jump.
+    Jump,
+    /// The taken side of a conditional jump.
+    Branch,
+    /// From a `call` to the routine; its `ret` comes back to the fallthrough.
+    Call,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A straight run of instructions, `insns[start..end]` of the graph.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    /// Ends in a jump whose target could not be worked out statically.
+    pub unknown_successor: bool,
+}
+
+/// Where a jump goes, as far as can be told without running the program.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Target {
+    Insn(usize),
+    /// Past the end of the program, where the VM stops.
+    Exit,
+    Unknown,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cfg {
+    pub insns: Vec<Insn>,
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+}
+
+fn is_jump(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::JMPF | Opcode::JMPB | Opcode::RET) || opcode.target_slot().is_some()
+}
+
+/// Control does not fall through to the next instruction.
+fn ends_flow(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::RET | Opcode::HLT | Opcode::IGL
+    )
+}
+
+impl Cfg {
+    pub fn from_bytecode(bytes: &[u8]) -> Cfg {
+        Cfg::new(decode(bytes))
+    }
+
+    pub fn from_program(p: &Program) -> Cfg {
+        Cfg::new(from_program(p))
+    }
+
+    pub fn new(insns: Vec<Insn>) -> Cfg {
+        let by_offset: HashMap<u32, usize> = insns
+            .iter()
+            .enumerate()
+            .map(|(index, i)| (i.offset, index))
+            .collect();
+        let end = insns.last().map_or(0, |i| i.offset + i.len);
+
+        // Jump targets depend on the constants known at each jump, which in
+        // turn reset at block boundaries; iterate until the leaders settle.
+        // Leaders only ever get added, so this terminates.
+        let mut leaders = BTreeSet::new();
+        let mut targets;
+        loop {
+            targets = Cfg::jump_targets(&insns, &leaders, &by_offset, end);
+            let mut next = leaders.clone();
+            if !insns.is_empty() {
+                next.insert(0);
+            }
+            for (index, i) in insns.iter().enumerate() {
+                if i.label.is_some() {
+                    next.insert(index);
+                }
+                if is_jump(i.opcode) || ends_flow(i.opcode) {
+                    next.insert(index + 1);
+                }
+                if let Some(Target::Insn(t)) = targets.get(&index) {
+                    next.insert(*t);
+                }
+            }
+            next.retain(|l| *l < insns.len());
+            if next == leaders {
+                break;
+            }
+            leaders = next;
+        }
+
+        let starts: Vec<usize> = leaders.iter().cloned().collect();
+        let block_of: HashMap<usize, usize> = starts
+            .iter()
+            .enumerate()
+            .map(|(block, start)| (*start, block))
+            .collect();
+        let mut blocks = vec![];
+        let mut edges = vec![];
+        for (block, start) in starts.iter().enumerate() {
+            let end = starts.get(block + 1).cloned().unwrap_or(insns.len());
+            let last = &insns[end - 1];
+            let mut unknown_successor = false;
+            if let Some(target) = targets.get(&(end - 1)) {
+                let kind = if last.opcode.is_conditional_jump() {
+                    EdgeKind::Branch
+                } else if last.opcode == Opcode::CALL {
+                    EdgeKind::Call
+                } else {
+                    EdgeKind::Jump
+                };
+                match target {
+                    Target::Insn(t) => edges.push(Edge {
+                        from: block,
+                        to: block_of[t],
+                        kind,
+                    }),
+                    Target::Exit => {}
+                    Target::Unknown => unknown_successor = true,
+                }
+            }
+            if !ends_flow(last.opcode) && end < insns.len() {
+                edges.push(Edge {
+                    from: block,
+                    to: block + 1,
+                    kind: EdgeKind::Fallthrough,
+                });
+            }
+            blocks.push(BasicBlock {
+                start: *start,
+                end,
+                unknown_successor,
+            });
+        }
+
+        Cfg {
+            insns,
+            blocks,
+            edges,
+        }
+    }
+
+    /// Resolves every jump, tracking `load`ed constants within blocks.
+    fn jump_targets(
+        insns: &[Insn],
+        leaders: &BTreeSet<usize>,
+        by_offset: &HashMap<u32, usize>,
+        end: u32,
+    ) -> HashMap<usize, Target> {
+        let mut known: HashMap<u8, i32> = HashMap::new();
+        let mut targets = HashMap::new();
+        for (index, i) in insns.iter().enumerate() {
+            if leaders.contains(&index) || i.label.is_some() {
+                known.clear();
+            }
+            if is_jump(i.opcode) {
+                // The VM has read the whole instruction when it jumps.
+                let after = i64::from(i.offset + i.len);
+                let offset = match i.operands.get(i.opcode.target_slot().unwrap_or(0)) {
+                    Some(Operand::Label(_, offset)) => offset.map(i64::from),
+                    Some(Operand::Immediate(v)) => Some(i64::from(*v)),
+                    Some(Operand::Relative(d)) => Some(i64::from(i.offset) + i64::from(*d)),
+                    Some(Operand::Register(r)) => known.get(r).map(|v| match i.opcode {
+                        Opcode::JMPF => after + i64::from(*v),
+                        Opcode::JMPB => after - i64::from(*v),
+                        _ => i64::from(*v),
+                    }),
+                    _ => None,
+                };
+                let target = match offset {
+                    Some(o) if o == i64::from(end) => Target::Exit,
+                    Some(o) if o >= 0 => by_offset
+                        .get(&(o as u32))
+                        .map_or(Target::Unknown, |t| Target::Insn(*t)),
+                    _ => Target::Unknown,
+                };
+                targets.insert(index, target);
+            }
+            match (i.opcode, i.def(), i.operands.get(1)) {
+                (Opcode::LOAD, Some(r), Some(Operand::Immediate(v)))
+                | (Opcode::LOAD32, Some(r), Some(Operand::Immediate(v))) => {
+                    known.insert(r, *v);
+                }
+                (_, Some(r), _) => {
+                    known.remove(&r);
+                }
+                _ => {}
+            }
+            if is_jump(i.opcode) || ends_flow(i.opcode) {
+                known.clear();
+            }
+        }
+        targets
+    }
+
+    pub fn block_insns(&self, block: usize) -> &[Insn] {
+        let b = &self.blocks[block];
+        &self.insns[b.start..b.end]
+    }
+
+    pub fn successors(&self, block: usize) -> Vec<usize> {
+        self.edges
+            .iter()
+            .filter(|e| e.from == block)
+            .map(|e| e.to)
+            .collect()
+    }
+
+    pub fn predecessors(&self, block: usize) -> Vec<usize> {
+        self.edges
+            .iter()
+            .filter(|e| e.to == block)
+            .map(|e| e.from)
+            .collect()
+    }
+
+    /// Whether any jump could go somewhere the graph does not know about.
+    pub fn has_unknown_successors(&self) -> bool {
+        self.blocks.iter().any(|b| b.unknown_successor)
+    }
+
+    /// Which blocks can be reached from the entry block.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = if self.blocks.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(block) = stack.pop() {
+            if seen[block] {
+                continue;
+            }
+            seen[block] = true;
+            stack.extend(self.successors(block));
+        }
+        seen
+    }
+
+    /// Renders the graph in Graphviz DOT format.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph cfg {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for (block, _) in self.blocks.iter().enumerate() {
+            let text: String = self
+                .block_insns(block)
+                .iter()
+                .map(|i| format!("{}\\l", i.to_string().replace('"', "\\\"")))
+                .collect();
+            writeln!(dot, "    b{} [label=\"{}\"];", block, text).unwrap();
+            if self.blocks[block].unknown_successor {
+                writeln!(dot, "    b{} -> unknown [style=dashed];", block).unwrap();
+            }
+        }
+        for e in &self.edges {
+            let label = match e.kind {
+                EdgeKind::Fallthrough => "fallthrough",
+                EdgeKind::Jump => "jump",
+                EdgeKind::Branch => "branch",
+                EdgeKind::Call => "call",
+            };
+            writeln!(dot, "    b{} -> b{} [label=\"{}\"];", e.from, e.to, label).unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program;
+    use nom::types::CompleteStr;
+
+    fn cfg(source: &str) -> Cfg {
+        let (_, p) = program(CompleteStr(source)).unwrap();
+        Cfg::from_program(&p)
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let g = cfg("load $0 #1\nload $1 #2\nhlt");
+        assert_eq!(g.blocks.len(), 1);
+        assert!(g.edges.is_empty());
+    }
+
+    #[test]
+    fn test_loop() {
+        let g = cfg("load $0 #0\nload $2 #9\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt");
+        assert_eq!(g.blocks.len(), 3);
+        assert_eq!(g.successors(1), vec![1, 2]);
+        assert_eq!(g.predecessors(1), vec![0, 1]);
+        assert_eq!(
+            g.edges[1],
+            Edge {
+                from: 1,
+                to: 1,
+                kind: EdgeKind::Branch
+            }
+        );
+    }
+
+    #[test]
+    fn test_compare_and_branch() {
+        let g = cfg("load $2 #3\ntop: inc $0\nblt $0 $2 @top\nhlt");
+        assert_eq!(g.successors(1), vec![1, 2]);
+        assert_eq!(g.edges[1].kind, EdgeKind::Branch);
+    }
+
+    #[test]
+    fn test_call() {
+        let g = cfg("call @f\nhlt\nf: inc $0\nret");
+        assert_eq!(g.successors(0), vec![2, 1]);
+        assert_eq!(g.edges[0].kind, EdgeKind::Call);
+        assert!(g.blocks[2].unknown_successor);
+    }
+
+    #[test]
+    fn test_register_jump_with_known_target() {
+        // jmp through $0 lands on the `hlt` at offset 8.
+        let g = Cfg::from_bytecode(&[0, 0, 0, 8, 6, 2, 0, 5, 5]);
+        assert_eq!(g.blocks.len(), 3);
+        assert!(!g.has_unknown_successors());
+        assert_eq!(g.successors(0), vec![2]);
+        assert_eq!(g.reachable(), vec![true, false, true]);
+    }
+
+    #[test]
+    fn test_register_jump_with_unknown_target() {
+        let g = Cfg::from_bytecode(&[6, 2, 3, 5]);
+        assert!(g.has_unknown_successors());
+    }
+
+    #[test]
+    fn test_direct_jumps() {
+        // jmp #5 skips the first hlt.
+        let g = Cfg::from_bytecode(&[6, 0, 0, 5, 5, 5]);
+        assert_eq!(g.successors(0), vec![2]);
+        // jmp -1 goes back to the hlt before it.
+        let g = Cfg::from_bytecode(&[5, 6, 1, 0xff, 0xff]);
+        assert_eq!(g.successors(1), vec![0]);
+    }
+
+    #[test]
+    fn test_relative_jumps() {
+        // jmpf $0 skips the first hlt: 2 + 1 = 3.
+        let g = Cfg::from_bytecode(&[0, 0, 0, 1, 7, 0, 5, 5]);
+        assert_eq!(g.successors(0), vec![2]);
+        // jmpb $0 with $0 = 6 goes back to the start: 6 - 6 = 0.
+        let g = Cfg::from_bytecode(&[0, 0, 0, 6, 8, 0]);
+        assert_eq!(g.successors(0), vec![0]);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = cfg("test: inc $0\njmpe @test\nhlt").to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("b0 [label=\"0000: test: INC $0\\l0004: JMPE @test\\l\"];"));
+        assert!(dot.contains("b0 -> b0 [label=\"branch\"];"));
+        assert!(dot.contains("b0 -> b1 [label=\"fallthrough\"];"));
+    }
+}
@@ -0,0 +1,245 @@
+use super::cfg::Cfg;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// A set of registers, one bit per register.
+pub type RegisterSet = u32;
+
+const ALL_REGISTERS: RegisterSet = !0;
+
+fn bit(r: u8) -> RegisterSet {
+    1u32.checked_shl(u32::from(r)).unwrap_or(0)
+}
+
+/// Registers live on entry to and exit from each block.
+#[derive(Debug, PartialEq)]
+pub struct Liveness {
+    pub live_in: Vec<RegisterSet>,
+    pub live_out: Vec<RegisterSet>,
+}
+
+impl Liveness {
+    pub fn compute(cfg: &Cfg) -> Liveness {
+        let n = cfg.blocks.len();
+        let (mut uses, mut defs) = (vec![0; n], vec![0; n]);
+        for block in 0..n {
+            for i in cfg.block_insns(block) {
+                for r in i.uses() {
+                    if defs[block] & bit(r) == 0 {
+                        uses[block] |= bit(r);
+                    }
+                }
+                if let Some(r) = i.def() {
+                    defs[block] |= bit(r);
+                }
+            }
+        }
+
+        let mut live_in = vec![0; n];
+        let mut live_out = vec![0; n];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..n).rev() {
+                // Anything may be read wherever an unknown jump ends up.
+                let out = if cfg.blocks[block].unknown_successor {
+                    ALL_REGISTERS
+                } else {
+                    cfg.successors(block)
+                        .iter()
+                        .fold(0, |out, s| out | live_in[*s])
+                };
+                let inn = uses[block] | (out & !defs[block]);
+                if out != live_out[block] || inn != live_in[block] {
+                    live_out[block] = out;
+                    live_in[block] = inn;
+                    changed = true;
+                }
+            }
+        }
+        Liveness { live_in, live_out }
+    }
+
+    pub fn is_live_in(&self, block: usize, r: u8) -> bool {
+        self.live_in[block] & bit(r) != 0
+    }
+
+    pub fn is_live_out(&self, block: usize, r: u8) -> bool {
+        self.live_out[block] & bit(r) != 0
+    }
+}
+
+/// Which register definitions, by instruction index, reach each block.
+#[derive(Debug, PartialEq)]
+pub struct ReachingDefinitions {
+    pub reach_in: Vec<BTreeSet<usize>>,
+    pub reach_out: Vec<BTreeSet<usize>>,
+}
+
+impl ReachingDefinitions {
+    pub fn compute(cfg: &Cfg) -> ReachingDefinitions {
+        let n = cfg.blocks.len();
+        let mut reach_in = vec![BTreeSet::new(); n];
+        let mut reach_out = vec![BTreeSet::new(); n];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in 0..n {
+                let inn: BTreeSet<usize> = cfg
+                    .predecessors(block)
+                    .iter()
+                    .flat_map(|p| reach_out[*p].iter().cloned())
+                    .collect();
+                let out = ReachingDefinitions::transfer(cfg, block, inn.clone());
+                if inn != reach_in[block] || out != reach_out[block] {
+                    reach_in[block] = inn;
+                    reach_out[block] = out;
+                    changed = true;
+                }
+            }
+        }
+        ReachingDefinitions {
+            reach_in,
+            reach_out,
+        }
+    }
+
+    /// Runs `defs` through `block`, replacing definitions as registers are written.
+    fn transfer(cfg: &Cfg, block: usize, mut defs: BTreeSet<usize>) -> BTreeSet<usize> {
+        let start = cfg.blocks[block].start;
+        for (index, i) in cfg.block_insns(block).iter().enumerate() {
+            if let Some(r) = i.def() {
+                defs.retain(|d| cfg.insns[*d].def() != Some(r));
+                defs.insert(start + index);
+            }
+        }
+        defs
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    /// A register is read where no write to it can have happened.
+    UninitializedRead {
+        offset: u32,
+        register: u8,
+    },
+    UnreachableBlock {
+        offset: u32,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UninitializedRead { offset, register } => write!(
+                f,
+                "warning: ${} is read at {:04X} but never written before",
+                register, offset
+            ),
+            Warning::UnreachableBlock { offset } => {
+                write!(f, "warning: code at {:04X} is unreachable", offset)
+            }
+        }
+    }
+}
+
+/// Reports reads of never-written registers and unreachable blocks.
+pub fn warnings(cfg: &Cfg) -> Vec<Warning> {
+    let mut warnings = vec![];
+    let reaching = ReachingDefinitions::compute(cfg);
+    // With jumps to unknown places, any block could be reached from anywhere,
+    // carrying any definitions along.
+    let unknown_jumps = cfg.has_unknown_successors();
+    let reachable = if unknown_jumps {
+        vec![true; cfg.blocks.len()]
+    } else {
+        cfg.reachable()
+    };
+
+    for (block, b) in cfg.blocks.iter().enumerate() {
+        if !reachable[block] {
+            warnings.push(Warning::UnreachableBlock {
+                offset: cfg.insns[b.start].offset,
+            });
+            continue;
+        }
+        let mut defs = reaching.reach_in[block].clone();
+        for (index, i) in cfg.block_insns(block).iter().enumerate() {
+            for r in i.uses() {
+                let written = defs.iter().any(|d| cfg.insns[*d].def() == Some(r));
+                if !written && !unknown_jumps {
+                    warnings.push(Warning::UninitializedRead {
+                        offset: i.offset,
+                        register: r,
+                    });
+                }
+            }
+            if let Some(r) = i.def() {
+                defs.retain(|d| cfg.insns[*d].def() != Some(r));
+                defs.insert(b.start + index);
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program;
+    use nom::types::CompleteStr;
+
+    fn cfg(source: &str) -> Cfg {
+        let (_, p) = program(CompleteStr(source)).unwrap();
+        Cfg::from_program(&p)
+    }
+
+    #[test]
+    fn test_liveness() {
+        let g = cfg("load $0 #0\nload $2 #9\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt");
+        let live = Liveness::compute(&g);
+        assert!(live.is_live_in(1, 0));
+        assert!(live.is_live_in(1, 2));
+        assert!(!live.is_live_in(0, 0));
+        assert!(live.is_live_out(0, 2));
+        assert_eq!(live.live_out[2], 0);
+    }
+
+    #[test]
+    fn test_reaching_definitions() {
+        let g = cfg("load $0 #0\ntest: inc $0\njmpe @test\nhlt");
+        let reaching = ReachingDefinitions::compute(&g);
+        // Both the initial load and the increment reach the loop head.
+        assert_eq!(reaching.reach_in[1], vec![0, 1].into_iter().collect());
+        assert_eq!(reaching.reach_out[1], vec![1].into_iter().collect());
+    }
+
+    #[test]
+    fn test_uninitialized_read_warning() {
+        let g = cfg("load $0 #1\nadd $0 $1 $2\nhlt");
+        assert_eq!(
+            warnings(&g),
+            vec![Warning::UninitializedRead {
+                offset: 4,
+                register: 1
+            }]
+        );
+        assert_eq!(
+            warnings(&g)[0].to_string(),
+            "warning: $1 is read at 0004 but never written before"
+        );
+    }
+
+    #[test]
+    fn test_unreachable_block_warning() {
+        let g = cfg("hlt\nload $0 #1\nhlt");
+        assert_eq!(warnings(&g), vec![Warning::UnreachableBlock { offset: 1 }]);
+    }
+
+    #[test]
+    fn test_no_warnings_for_clean_loop() {
+        let g = cfg("load $0 #0\nload $2 #9\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt");
+        assert!(warnings(&g).is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+//! Static analysis of Iridium programs: decoding into a common form, basic
+//! blocks and control-flow graphs, and register dataflow.
+
+pub mod abi;
+pub mod cfg;
+pub mod dataflow;
+
+use super::abi::REGISTERS;
+use super::assembler::layout;
+use super::assembler::program_parsers::Program;
+use super::assembler::Section;
+use super::assembler::Token;
+use super::instruction::{JumpMode, Opcode, OperandRole, Slot};
+use super::vm::VMError;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+    Register(u8),
+    Immediate(i32),
+    /// A jump displacement from the start of the instruction.
+    Relative(i32),
+    /// A label and the code offset it resolved to, if it did.
+    Label(String, Option<u32>),
+}
+
+/// One decoded instruction, whether it came from bytecode or source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Insn {
+    pub offset: u32,
+    pub len: u32,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+    pub label: Option<String>,
+}
+
+impl Insn {
+    fn register_slots(&self) -> impl Iterator<Item = (OperandRole, u8)> + '_ {
+        self.opcode
+            .operand_roles()
+            .to_vec()
+            .into_iter()
+            .zip(self.operands.iter())
+            .filter_map(|(role, operand)| match operand {
+                Operand::Register(r) => Some((role, *r)),
+                _ => None,
+            })
+    }
+
+    /// Registers this instruction reads.
+    pub fn uses(&self) -> Vec<u8> {
+        self.register_slots()
+            .filter(|(role, _)| *role == OperandRole::Read || *role == OperandRole::ReadWrite)
+            .map(|(_, r)| r)
+            .collect()
+    }
+
+    /// The register this instruction writes, if any.
+    pub fn def(&self) -> Option<u8> {
+        self.register_slots()
+            .find(|(role, _)| *role == OperandRole::Write || *role == OperandRole::ReadWrite)
+            .map(|(_, r)| r)
+    }
+}
+
+impl fmt::Display for Insn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}: ", self.offset)?;
+        if let Some(label) = &self.label {
+            write!(f, "{}: ", label)?;
+        }
+        write!(f, "{:?}", self.opcode)?;
+        for operand in &self.operands {
+            match operand {
+                Operand::Register(r) => write!(f, " ${}", r)?,
+                Operand::Immediate(i) => write!(f, " #{}", i)?,
+                Operand::Relative(d) => write!(f, " {:+}", d)?,
+                Operand::Label(name, _) => write!(f, " @{}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes bytecode, reading operands the way the VM does.
+pub fn decode(bytes: &[u8]) -> Vec<Insn> {
+    let mut insns = vec![];
+    let mut pc = 0;
+    while pc < bytes.len() {
+        let opcode = Opcode::from(bytes[pc]);
+        let mut len = 1;
+        let mut operands = vec![];
+        let wide =
+            |at: usize| Some(u16::from(*bytes.get(at)?) << 8 | u16::from(*bytes.get(at + 1)?));
+        for slot in opcode.layout() {
+            let at = pc + len;
+            let (operand, size) = match slot {
+                Slot::Immediate if opcode.signed_immediate() => {
+                    (wide(at).map(|v| Operand::Immediate(i32::from(v as i16))), 2)
+                }
+                Slot::Immediate => (wide(at).map(|v| Operand::Immediate(i32::from(v))), 2),
+                Slot::Wide => (
+                    bytes
+                        .get(at..at + 4)
+                        .map(|b| Operand::Immediate(BigEndian::read_i32(b))),
+                    4,
+                ),
+                Slot::Register => (bytes.get(at).map(|r| Operand::Register(*r)), 1),
+                Slot::Target => match bytes.get(at).and_then(|m| JumpMode::from_u8(*m)) {
+                    Some(JumpMode::Absolute) => {
+                        (wide(at + 1).map(|v| Operand::Immediate(i32::from(v))), 3)
+                    }
+                    Some(JumpMode::Relative) => (
+                        wide(at + 1).map(|v| Operand::Relative(i32::from(v as i16))),
+                        3,
+                    ),
+                    Some(JumpMode::Register) => {
+                        (bytes.get(at + 1).map(|r| Operand::Register(*r)), 2)
+                    }
+                    None => (None, 0),
+                },
+            };
+            match operand {
+                Some(operand) => operands.push(operand),
+                None => return insns,
+            }
+            len += size;
+        }
+        len += opcode.padding() as usize;
+        if pc + len > bytes.len() {
+            return insns;
+        }
+
+        insns.push(Insn {
+            offset: pc as u32,
+            len: len as u32,
+            opcode,
+            operands,
+            label: None,
+        });
+        pc += len;
+    }
+    insns
+}
+
+/// What the VM would trip over in `code` before executing any of it:
+/// unknown opcodes, jump modes and registers, absolute or relative jumps out
+/// of the program, and a last instruction cut short. Checking stops at the
+/// first instruction that cannot be decoded.
+pub fn verify(code: &[u8]) -> Vec<VMError> {
+    let mut errors = vec![];
+    let mut pc = 0;
+    while pc < code.len() {
+        let opcode = Opcode::from(code[pc]);
+        if opcode == Opcode::IGL {
+            errors.push(VMError::IllegalOpcode { pc, byte: code[pc] });
+            return errors;
+        }
+        let insns = decode(&code[pc..]);
+        let insn = match insns.first() {
+            Some(insn) if insn.operands.len() == opcode.layout().len() => insn,
+            _ => {
+                // Only registers, a byte each, come before a jump's mode.
+                let mode = opcode
+                    .target_slot()
+                    .and_then(|slot| code.get(pc + 1 + slot));
+                match mode.filter(|mode| JumpMode::from_u8(**mode).is_none()) {
+                    Some(byte) => errors.push(VMError::IllegalJumpMode { pc, byte: *byte }),
+                    None => errors.push(VMError::UnexpectedEnd { pc }),
+                }
+                return errors;
+            }
+        };
+        for operand in &insn.operands {
+            match operand {
+                Operand::Register(r) if *r >= REGISTERS => {
+                    errors.push(VMError::IllegalRegister { pc, byte: *r })
+                }
+                _ => {}
+            }
+        }
+        let target = match (opcode.target_slot(), insn.operands.last()) {
+            (Some(_), Some(Operand::Immediate(target))) => Some(i64::from(*target)),
+            (Some(_), Some(Operand::Relative(d))) => Some(pc as i64 + i64::from(*d)),
+            _ => None,
+        };
+        if let Some(target) = target.filter(|t| *t < 0 || *t > code.len() as i64) {
+            errors.push(VMError::JumpOutOfRange { pc, target });
+        }
+        pc += insn.len as usize;
+    }
+    errors
+}
+
+/// Converts a parsed program, laying it out the way the assembler emits it.
+pub fn from_program(p: &Program) -> Vec<Insn> {
+    let placed: Vec<_> = p
+        .instructions
+        .iter()
+        .zip(layout::place(p, 0, 0))
+        .filter(|(_, at)| at.section == Section::Code)
+        .collect();
+
+    let mut labels = HashMap::new();
+    for (i, at) in &placed {
+        if let Some(name) = i.label_name() {
+            labels.insert(name, at.offset);
+        }
+    }
+
+    let mut insns = vec![];
+    for (i, at) in placed.into_iter().filter(|(i, _)| !i.is_directive()) {
+        let operands = [i.operand1, i.operand2, i.operand3]
+            .iter()
+            .flatten()
+            .filter_map(|t| match t {
+                Token::Register { reg_num } => Some(Operand::Register(*reg_num)),
+                Token::IntOperand { value } => Some(Operand::Immediate(*value)),
+                Token::LabelUsage { name } => {
+                    Some(Operand::Label(name.to_string(), labels.get(name).cloned()))
+                }
+                _ => None,
+            })
+            .collect();
+        insns.push(Insn {
+            offset: at.offset,
+            len: at.len,
+            opcode: i.encoded_opcode().unwrap_or(Opcode::IGL),
+            operands,
+            label: i.label_name().map(|l| l.to_string()),
+        });
+    }
+    insns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program;
+    use nom::types::CompleteStr;
+
+    #[test]
+    fn test_decode() {
+        let insns = decode(&[0, 1, 1, 244, 1, 0, 1, 2, 5]);
+        assert_eq!(insns.len(), 3);
+        assert_eq!(
+            insns[0].operands,
+            vec![Operand::Register(1), Operand::Immediate(500)]
+        );
+        assert_eq!(insns[1].offset, 4);
+        assert_eq!(insns[2].opcode, Opcode::HLT);
+        assert_eq!(insns[1].to_string(), "0004: ADD $0 $1 $2");
+    }
+
+    #[test]
+    fn test_verify() {
+        assert!(verify(&[0, 1, 1, 244, 6, 0, 0, 8, 5]).is_empty());
+        assert_eq!(
+            verify(&[0, 40, 0, 1, 6, 0, 0, 13, 6, 1, 255, 0]),
+            vec![
+                VMError::IllegalRegister { pc: 0, byte: 40 },
+                VMError::JumpOutOfRange { pc: 4, target: 13 },
+                VMError::JumpOutOfRange {
+                    pc: 8,
+                    target: -248
+                },
+            ]
+        );
+        assert_eq!(
+            verify(&[5, 31, 0, 1, 7, 0, 0]),
+            vec![VMError::IllegalJumpMode { pc: 1, byte: 7 }]
+        );
+        assert_eq!(verify(&[5, 0, 1]), vec![VMError::UnexpectedEnd { pc: 1 }]);
+        assert_eq!(
+            verify(&[5, 200]),
+            vec![VMError::IllegalOpcode { pc: 1, byte: 200 }]
+        );
+    }
+
+    #[test]
+    fn test_decode_immediates() {
+        let insns = decode(&[41, 0, 0xff, 0xff, 0xff, 0xfe, 37, 0, 1, 0xff, 0xff]);
+        let text: Vec<String> = insns.iter().map(|i| i.to_string()).collect();
+        assert_eq!(text, vec!["0000: LOAD32 $0 #-2", "0006: ADDI $0 $1 #-1"]);
+    }
+
+    #[test]
+    fn test_decode_jump_modes() {
+        let insns = decode(&[6, 0, 1, 0, 15, 1, 0xff, 0xfc, 19, 2, 3]);
+        let text: Vec<String> = insns.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            text,
+            vec!["0000: JMP #256", "0004: JMPE -4", "0008: JMPO $3"]
+        );
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        assert_eq!(decode(&[5, 0, 1]).len(), 1);
+    }
+
+    #[test]
+    fn test_from_program() {
+        let (_, p) = program(CompleteStr("load $0 #1\ntest: inc $0\njmpe @test\nhlt")).unwrap();
+        let insns = from_program(&p);
+        assert_eq!(insns[1].offset, 4);
+        assert_eq!(insns[1].label, Some("test".to_string()));
+        assert_eq!(
+            insns[2].operands,
+            vec![Operand::Label("test".to_string(), Some(4))]
+        );
+        assert_eq!(insns[1].uses(), vec![0]);
+        assert_eq!(insns[1].def(), Some(0));
+    }
+}
@@ -0,0 +1,960 @@
+//! Building programs from Rust, without going through source text.
+//!
+//! ```
+//! use iridium::assembler::builder::{ProgramBuilder, Reg};
+//!
+//! let (r0, r1, r2) = (Reg(0), Reg(1), Reg(2));
+//! let bytecode = ProgramBuilder::new()
+//!     .load(r0, 0)
+//!     .load(r1, 1)
+//!     .load(r2, 10)
+//!     .label("loop")
+//!     .inc(r0)
+//!     .neq(r0, r2)
+//!     .jmpe("loop")
+//!     .hlt()
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(bytecode.code.len(), 25);
+//! ```
+//!
+//! A builder makes the same statements the parser does and lays them out
+//! and encodes them the same way, so labels may be used before they are
+//! declared, local (`.loop`) and numeric (`1`, `1f`, `1b`) labels work as in
+//! source, and `.data` statements land in the read-only section.
+//!
+//! Nothing is checked until `build`, which reports every problem found,
+//! each with the index of the statement it is about. Statements are
+//! counted from 0 in the order they were added, `label` calls included.
+
+use super::instruction_parsers::{displacement, AssemblerInstruction};
+use super::program_parsers::Program;
+use super::{label_parsers, layout, layout_labels, Section, SymbolTable, Token};
+use crate::abi;
+use crate::bytecode::Bytecode;
+use crate::instruction::{Opcode, Slot};
+use nom::types::CompleteStr;
+use std::collections::HashSet;
+use std::fmt;
+
+/// A register, `$0` to `$31`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Reg(pub u8);
+
+impl Reg {
+    /// The register a built-in name such as `sp` or `t3` stands for.
+    pub fn named(name: &str) -> Option<Reg> {
+        abi::register_number(name).map(Reg)
+    }
+}
+
+/// An operand of `ProgramBuilder::instruction`, and the target of a jump:
+/// a register, a literal, or the offset of a label.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+    Register(Reg),
+    Immediate(i32),
+    Label(String),
+}
+
+impl From<Reg> for Operand {
+    fn from(r: Reg) -> Operand {
+        Operand::Register(r)
+    }
+}
+
+impl From<i32> for Operand {
+    fn from(value: i32) -> Operand {
+        Operand::Immediate(value)
+    }
+}
+
+impl From<&str> for Operand {
+    fn from(name: &str) -> Operand {
+        Operand::Label(name.to_string())
+    }
+}
+
+impl From<String> for Operand {
+    fn from(name: String) -> Operand {
+        Operand::Label(name)
+    }
+}
+
+/// Something that keeps a built program from being encoded, with the index
+/// of the statement it is about.
+#[derive(Debug, PartialEq, Clone)]
+pub enum BuildError {
+    /// A label name that could not be written in source.
+    InvalidLabel {
+        statement: usize,
+        name: String,
+    },
+    UndefinedLabel {
+        statement: usize,
+        name: String,
+    },
+    DuplicateLabel {
+        statement: usize,
+        name: String,
+    },
+    /// A register above `$31`.
+    InvalidRegister {
+        statement: usize,
+        reg_num: u8,
+    },
+    /// Operands that do not match what the opcode takes.
+    InvalidOperands {
+        statement: usize,
+        opcode: Opcode,
+    },
+    /// A literal too large for the 16-bit immediate of its instruction.
+    ImmediateOutOfRange {
+        statement: usize,
+        value: i32,
+    },
+    /// A jump to `name` is further than a relative jump can reach.
+    JumpOutOfRange {
+        statement: usize,
+        name: String,
+        distance: i64,
+    },
+    /// A value too large for the data directive laying it down.
+    ValueOutOfRange {
+        statement: usize,
+        directive: String,
+        value: i64,
+    },
+    /// A `.space` or `.align` count out of range.
+    InvalidCount {
+        statement: usize,
+        directive: String,
+    },
+}
+
+impl BuildError {
+    /// The index of the statement the error is about.
+    pub fn statement(&self) -> usize {
+        match *self {
+            BuildError::InvalidLabel { statement, .. }
+            | BuildError::UndefinedLabel { statement, .. }
+            | BuildError::DuplicateLabel { statement, .. }
+            | BuildError::InvalidRegister { statement, .. }
+            | BuildError::InvalidOperands { statement, .. }
+            | BuildError::ImmediateOutOfRange { statement, .. }
+            | BuildError::JumpOutOfRange { statement, .. }
+            | BuildError::ValueOutOfRange { statement, .. }
+            | BuildError::InvalidCount { statement, .. } => statement,
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "statement {}: ", self.statement())?;
+        match self {
+            BuildError::InvalidLabel { name, .. } => write!(f, "invalid label name `{}`", name),
+            BuildError::UndefinedLabel { name, .. } => write!(f, "undefined label `{}`", name),
+            BuildError::DuplicateLabel { name, .. } => {
+                write!(f, "label `{}` is already declared", name)
+            }
+            BuildError::InvalidRegister { reg_num, .. } => {
+                write!(f, "there is no register `${}`", reg_num)
+            }
+            BuildError::InvalidOperands { opcode, .. } => {
+                write!(f, "expected `{}`", opcode.summary().0)
+            }
+            BuildError::ImmediateOutOfRange { value, .. } => write!(
+                f,
+                "immediate `{}` does not fit in 16 bits; load it into a register",
+                value
+            ),
+            BuildError::JumpOutOfRange { name, distance, .. } => write!(
+                f,
+                "jump to `{}` is out of range ({} bytes away)",
+                name, distance
+            ),
+            BuildError::ValueOutOfRange {
+                directive, value, ..
+            } => write!(f, "value `{}` does not fit in `.{}`", value, directive),
+            BuildError::InvalidCount { directive, .. } => write!(
+                f,
+                "`.{}` needs a count from {} to 65535",
+                directive,
+                (directive == "align") as u8
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// What a statement does, with everything its tokens borrow.
+#[derive(Debug, Clone)]
+enum Item {
+    Instruction(Opcode, Vec<Operand>),
+    /// A directive whose operand, if any, is the text of an expression
+    /// list or of a string literal.
+    Directive(&'static str, Option<String>),
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    index: usize,
+    /// The label declared on the statement, and the index of its `label`.
+    label: Option<(String, usize)>,
+    item: Item,
+}
+
+/// Builds a program statement by statement; see the module documentation.
+#[derive(Debug, Default, Clone)]
+pub struct ProgramBuilder {
+    statements: Vec<Statement>,
+    /// A label waiting for the statement it names.
+    pending: Option<(String, usize)>,
+    /// How many statements have been added, labels included.
+    count: usize,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder::default()
+    }
+
+    /// Declares `name` on the next statement. A label with no statement
+    /// after it marks the end of the current section.
+    pub fn label(mut self, name: &str) -> ProgramBuilder {
+        // A second label in a row leaves the first one on its own.
+        if let Some(label) = self.pending.take() {
+            self.statements.push(unattached(label));
+        }
+        self.pending = Some((name.to_string(), self.count));
+        self.count += 1;
+        self
+    }
+
+    /// Adds any instruction, checking its operands against the opcode only
+    /// when the program is built.
+    pub fn instruction(mut self, opcode: Opcode, operands: Vec<Operand>) -> ProgramBuilder {
+        let label = self.pending.take();
+        self.push_labelled(label, Item::Instruction(opcode, operands));
+        self
+    }
+
+    fn push_labelled(&mut self, label: Option<(String, usize)>, item: Item) {
+        self.statements.push(Statement {
+            index: self.count,
+            label,
+            item,
+        });
+        self.count += 1;
+    }
+
+    fn directive(mut self, name: &'static str, operand: Option<String>) -> ProgramBuilder {
+        let label = self.pending.take();
+        self.push_labelled(label, Item::Directive(name, operand));
+        self
+    }
+
+    fn values(self, name: &'static str, values: &[i64]) -> ProgramBuilder {
+        let text: Vec<String> = values.iter().map(i64::to_string).collect();
+        self.directive(name, Some(text.join(", ")))
+    }
+
+    fn string_literal(self, name: &'static str, text: &str) -> ProgramBuilder {
+        let escaped = text.replace('\\', "\\\\").replace('"', "\\\"");
+        self.directive(name, Some(format!("\"{}\"", escaped)))
+    }
+
+    fn registers(self, opcode: Opcode, registers: &[Reg]) -> ProgramBuilder {
+        let operands = registers.iter().map(|r| Operand::Register(*r)).collect();
+        self.instruction(opcode, operands)
+    }
+
+    fn jump(self, opcode: Opcode, target: Operand) -> ProgramBuilder {
+        self.instruction(opcode, vec![target])
+    }
+
+    fn branch(self, opcode: Opcode, a: Reg, b: Reg, target: Operand) -> ProgramBuilder {
+        let operands = vec![Operand::Register(a), Operand::Register(b), target];
+        self.instruction(opcode, operands)
+    }
+
+    fn immediate(self, opcode: Opcode, registers: &[Reg], value: i16) -> ProgramBuilder {
+        let mut operands: Vec<Operand> = registers.iter().map(|r| Operand::Register(*r)).collect();
+        operands.push(Operand::Immediate(i32::from(value)));
+        self.instruction(opcode, operands)
+    }
+
+    /// Following statements go in the read-only data section.
+    pub fn data(self) -> ProgramBuilder {
+        self.directive("data", None)
+    }
+
+    /// Following statements go in the code section, the default.
+    pub fn code(self) -> ProgramBuilder {
+        self.directive("code", None)
+    }
+
+    /// `.byte`: one byte per value.
+    pub fn byte(self, values: &[i64]) -> ProgramBuilder {
+        self.values("byte", values)
+    }
+
+    /// `.byte` with arbitrary bytes.
+    pub fn bytes(self, bytes: &[u8]) -> ProgramBuilder {
+        let values: Vec<i64> = bytes.iter().map(|b| i64::from(*b)).collect();
+        self.values("byte", &values)
+    }
+
+    /// `.half`: two big-endian bytes per value.
+    pub fn half(self, values: &[i64]) -> ProgramBuilder {
+        self.values("half", values)
+    }
+
+    /// `.word`: four big-endian bytes per value.
+    pub fn word(self, values: &[i64]) -> ProgramBuilder {
+        self.values("word", values)
+    }
+
+    /// `.word @name`: the offset of a label, as a word.
+    pub fn address(self, name: &str) -> ProgramBuilder {
+        self.directive("word", Some(format!("@{}", name)))
+    }
+
+    /// `.asciiz`: `text` in UTF-8, then a zero byte.
+    pub fn asciiz(self, text: &str) -> ProgramBuilder {
+        self.string_literal("asciiz", text)
+    }
+
+    /// `.string`: the length of `text` in UTF-8, as a word, then the text.
+    pub fn string(self, text: &str) -> ProgramBuilder {
+        self.string_literal("string", text)
+    }
+
+    /// `.space`: `n` zero bytes.
+    pub fn space(self, n: u32) -> ProgramBuilder {
+        self.directive("space", Some(n.to_string()))
+    }
+
+    /// `.align`: zero bytes up to the next multiple of `n`.
+    pub fn align(self, n: u32) -> ProgramBuilder {
+        self.directive("align", Some(n.to_string()))
+    }
+
+    /// `load $r #value`, or `load32` if `value` needs more than 16 bits.
+    pub fn load(self, r: Reg, value: i32) -> ProgramBuilder {
+        self.instruction(Opcode::LOAD, vec![r.into(), value.into()])
+    }
+
+    /// `load $r @name`: the offset of a label.
+    pub fn load_address(self, r: Reg, name: &str) -> ProgramBuilder {
+        self.instruction(Opcode::LOAD, vec![r.into(), name.into()])
+    }
+
+    pub fn add(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::ADD, &[a, b, dest])
+    }
+
+    pub fn sub(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::SUB, &[a, b, dest])
+    }
+
+    pub fn mul(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::MUL, &[a, b, dest])
+    }
+
+    pub fn div(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::DIV, &[a, b, dest])
+    }
+
+    /// `rem $r`: the remainder of the last `div`.
+    #[allow(clippy::should_implement_trait)]
+    pub fn rem(self, r: Reg) -> ProgramBuilder {
+        self.registers(Opcode::REM, &[r])
+    }
+
+    pub fn and(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::AND, &[a, b, dest])
+    }
+
+    pub fn or(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::OR, &[a, b, dest])
+    }
+
+    pub fn xor(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::XOR, &[a, b, dest])
+    }
+
+    pub fn not(self, a: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::NOT, &[a, dest])
+    }
+
+    pub fn shl(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::SHL, &[a, b, dest])
+    }
+
+    pub fn shr(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::SHR, &[a, b, dest])
+    }
+
+    pub fn sar(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::SAR, &[a, b, dest])
+    }
+
+    pub fn rol(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::ROL, &[a, b, dest])
+    }
+
+    pub fn ror(self, a: Reg, b: Reg, dest: Reg) -> ProgramBuilder {
+        self.registers(Opcode::ROR, &[a, b, dest])
+    }
+
+    pub fn addi(self, a: Reg, dest: Reg, value: i16) -> ProgramBuilder {
+        self.immediate(Opcode::ADDI, &[a, dest], value)
+    }
+
+    pub fn subi(self, a: Reg, dest: Reg, value: i16) -> ProgramBuilder {
+        self.immediate(Opcode::SUBI, &[a, dest], value)
+    }
+
+    pub fn muli(self, a: Reg, dest: Reg, value: i16) -> ProgramBuilder {
+        self.immediate(Opcode::MULI, &[a, dest], value)
+    }
+
+    pub fn cmpi(self, r: Reg, value: i16) -> ProgramBuilder {
+        self.immediate(Opcode::CMPI, &[r], value)
+    }
+
+    pub fn eq(self, a: Reg, b: Reg) -> ProgramBuilder {
+        self.registers(Opcode::EQ, &[a, b])
+    }
+
+    pub fn neq(self, a: Reg, b: Reg) -> ProgramBuilder {
+        self.registers(Opcode::NEQ, &[a, b])
+    }
+
+    pub fn gt(self, a: Reg, b: Reg) -> ProgramBuilder {
+        self.registers(Opcode::GT, &[a, b])
+    }
+
+    pub fn gte(self, a: Reg, b: Reg) -> ProgramBuilder {
+        self.registers(Opcode::GTE, &[a, b])
+    }
+
+    pub fn lt(self, a: Reg, b: Reg) -> ProgramBuilder {
+        self.registers(Opcode::LT, &[a, b])
+    }
+
+    pub fn lte(self, a: Reg, b: Reg) -> ProgramBuilder {
+        self.registers(Opcode::LTE, &[a, b])
+    }
+
+    pub fn inc(self, r: Reg) -> ProgramBuilder {
+        self.registers(Opcode::INC, &[r])
+    }
+
+    pub fn aloc(self, r: Reg) -> ProgramBuilder {
+        self.registers(Opcode::ALOC, &[r])
+    }
+
+    pub fn push(self, r: Reg) -> ProgramBuilder {
+        self.registers(Opcode::PUSH, &[r])
+    }
+
+    pub fn pop(self, r: Reg) -> ProgramBuilder {
+        self.registers(Opcode::POP, &[r])
+    }
+
+    /// `jmpf $r`: forwards by the value of `r`.
+    pub fn jmpf(self, r: Reg) -> ProgramBuilder {
+        self.registers(Opcode::JMPF, &[r])
+    }
+
+    /// `jmpb $r`: backwards by the value of `r`.
+    pub fn jmpb(self, r: Reg) -> ProgramBuilder {
+        self.registers(Opcode::JMPB, &[r])
+    }
+
+    /// Jumps to a label, a register's value or an offset.
+    pub fn jmp(self, target: impl Into<Operand>) -> ProgramBuilder {
+        self.jump(Opcode::JMP, target.into())
+    }
+
+    pub fn jmpe(self, target: impl Into<Operand>) -> ProgramBuilder {
+        self.jump(Opcode::JMPE, target.into())
+    }
+
+    pub fn jmpne(self, target: impl Into<Operand>) -> ProgramBuilder {
+        self.jump(Opcode::JMPNE, target.into())
+    }
+
+    pub fn jmpo(self, target: impl Into<Operand>) -> ProgramBuilder {
+        self.jump(Opcode::JMPO, target.into())
+    }
+
+    pub fn call(self, target: impl Into<Operand>) -> ProgramBuilder {
+        self.jump(Opcode::CALL, target.into())
+    }
+
+    pub fn beq(self, a: Reg, b: Reg, target: impl Into<Operand>) -> ProgramBuilder {
+        self.branch(Opcode::BEQ, a, b, target.into())
+    }
+
+    pub fn bne(self, a: Reg, b: Reg, target: impl Into<Operand>) -> ProgramBuilder {
+        self.branch(Opcode::BNE, a, b, target.into())
+    }
+
+    pub fn blt(self, a: Reg, b: Reg, target: impl Into<Operand>) -> ProgramBuilder {
+        self.branch(Opcode::BLT, a, b, target.into())
+    }
+
+    pub fn ble(self, a: Reg, b: Reg, target: impl Into<Operand>) -> ProgramBuilder {
+        self.branch(Opcode::BLE, a, b, target.into())
+    }
+
+    pub fn bgt(self, a: Reg, b: Reg, target: impl Into<Operand>) -> ProgramBuilder {
+        self.branch(Opcode::BGT, a, b, target.into())
+    }
+
+    pub fn bge(self, a: Reg, b: Reg, target: impl Into<Operand>) -> ProgramBuilder {
+        self.branch(Opcode::BGE, a, b, target.into())
+    }
+
+    pub fn ret(self) -> ProgramBuilder {
+        self.registers(Opcode::RET, &[])
+    }
+
+    pub fn hlt(self) -> ProgramBuilder {
+        self.registers(Opcode::HLT, &[])
+    }
+
+    /// Lays out and encodes the program, or reports everything wrong with it.
+    pub fn build(&self) -> Result<Bytecode, Vec<BuildError>> {
+        let mut statements = self.statements.clone();
+        if let Some(label) = self.pending.clone() {
+            statements.push(unattached(label));
+        }
+        let mut errors = vec![];
+        for s in &statements {
+            errors.extend(check(s));
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let p = Program {
+            instructions: statements.iter().map(statement).collect(),
+        };
+        let mut names = vec![];
+        let (p, duplicates) = p.qualify_labels(&mut names);
+        for (index, name) in duplicates {
+            let s = &statements[index];
+            errors.push(BuildError::DuplicateLabel {
+                statement: s.label.as_ref().map_or(s.index, |l| l.1),
+                name: name.to_string(),
+            });
+        }
+        let declared: HashSet<&str> = p
+            .instructions
+            .iter()
+            .filter_map(|i| i.label_name())
+            .collect();
+        for (s, i) in statements.iter().zip(&p.instructions) {
+            for (written, name) in label_names(s).into_iter().zip(label_usages(i)) {
+                if !declared.contains(name) {
+                    errors.push(BuildError::UndefinedLabel {
+                        statement: s.index,
+                        name: written.to_string(),
+                    });
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let placements = layout::place(&p, 0, 0);
+        let mut symbols = SymbolTable::new();
+        for label in layout_labels(&p, &placements) {
+            symbols.add_symbol(label);
+        }
+        for ((s, i), at) in statements.iter().zip(&p.instructions).zip(&placements) {
+            if let (false, Some(Token::LabelUsage { name })) = (i.is_directive(), i.target()) {
+                let distance = displacement(at.offset, symbols.symbol_value(name).unwrap_or(0));
+                if distance < i64::from(i16::MIN) || distance > i64::from(i16::MAX) {
+                    errors.push(BuildError::JumpOutOfRange {
+                        statement: s.index,
+                        name: label_names(s).last().unwrap_or(&name).to_string(),
+                        distance,
+                    });
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut bytecode = Bytecode::default();
+        for (i, at) in p.instructions.iter().zip(placements) {
+            let bytes = layout::emit(i, at, &symbols);
+            match at.section {
+                Section::Code => bytecode.code.extend(bytes),
+                Section::Data => bytecode.ro.extend(bytes),
+            }
+        }
+        Ok(bytecode)
+    }
+}
+
+/// A statement of its own for a label no statement followed. Directives the
+/// assembler does not know lay down nothing.
+fn unattached(label: (String, usize)) -> Statement {
+    Statement {
+        index: label.1,
+        label: Some(label),
+        item: Item::Directive("label", None),
+    }
+}
+
+/// The statement the parser would have made for `s`.
+fn statement(s: &Statement) -> AssemblerInstruction<'_> {
+    let mut i = AssemblerInstruction {
+        label: s.label.as_ref().map(|(name, _)| Token::LabelDecl { name }),
+        directive: None,
+        opcode: None,
+        operand1: None,
+        operand2: None,
+        operand3: None,
+    };
+    match &s.item {
+        Item::Instruction(code, operands) => {
+            i.opcode = Some(Token::Op { code: *code });
+            let mut tokens = operands.iter().map(|operand| match operand {
+                Operand::Register(Reg(reg_num)) => Token::Register { reg_num: *reg_num },
+                Operand::Immediate(value) => Token::IntOperand { value: *value },
+                Operand::Label(name) => Token::LabelUsage { name },
+            });
+            i.operand1 = tokens.next();
+            i.operand2 = tokens.next();
+            i.operand3 = tokens.next();
+        }
+        Item::Directive(name, operand) => {
+            i.directive = Some(Token::Directive { name });
+            i.operand1 = operand.as_ref().map(|text| match *name {
+                "asciiz" | "string" => Token::IrString { text },
+                _ => Token::ExprList { text },
+            });
+        }
+    }
+    i
+}
+
+/// The labels `s` refers to, as they were given.
+fn label_names(s: &Statement) -> Vec<&str> {
+    match &s.item {
+        Item::Instruction(_, operands) => operands
+            .iter()
+            .filter_map(|operand| match operand {
+                Operand::Label(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect(),
+        Item::Directive(_, Some(text)) if text.starts_with('@') => vec![&text[1..]],
+        Item::Directive(..) => vec![],
+    }
+}
+
+/// The labels `i` refers to, in the same order as `label_names`.
+fn label_usages<'a>(i: &AssemblerInstruction<'a>) -> Vec<&'a str> {
+    let operands = [i.operand1, i.operand2, i.operand3];
+    let mut names: Vec<&str> = operands
+        .iter()
+        .filter_map(|operand| match operand {
+            Some(Token::LabelUsage { name }) => Some(*name),
+            _ => None,
+        })
+        .collect();
+    for value in i.values() {
+        names.extend(value.labels());
+    }
+    names
+}
+
+/// Whether `name` could be declared as a label in source.
+fn is_label(name: &str) -> bool {
+    match label_parsers::label_name(CompleteStr(name)) {
+        Ok((rest, _)) => rest.is_empty(),
+        Err(_) => false,
+    }
+}
+
+/// What can be found wrong with `s` on its own.
+fn check(s: &Statement) -> Vec<BuildError> {
+    let statement = s.index;
+    let mut errors = vec![];
+    if let Some((name, index)) = &s.label {
+        if !is_label(name) {
+            errors.push(BuildError::InvalidLabel {
+                statement: *index,
+                name: name.clone(),
+            });
+        }
+    }
+    for name in label_names(s) {
+        let (number, direction) = name.split_at(name.len().saturating_sub(1));
+        let numeric = ["f", "b"].contains(&direction) && super::scope::is_numeric(number);
+        if !is_label(name) && !numeric {
+            errors.push(BuildError::InvalidLabel {
+                statement,
+                name: name.to_string(),
+            });
+        }
+    }
+    match &s.item {
+        Item::Instruction(opcode, operands) => {
+            let i = self::statement(s);
+            let layout = i.encoded_opcode().map_or(&[][..], Opcode::layout);
+            let fits = operands.len() == opcode.layout().len()
+                && operands
+                    .iter()
+                    .zip(layout)
+                    .all(|(operand, slot)| match (operand, slot) {
+                        (Operand::Register(_), Slot::Register) => true,
+                        (Operand::Register(_), _) => *slot == Slot::Target,
+                        (_, Slot::Register) => false,
+                        _ => true,
+                    });
+            if !fits || *opcode == Opcode::IGL {
+                errors.push(BuildError::InvalidOperands {
+                    statement,
+                    opcode: *opcode,
+                });
+                return errors;
+            }
+            for (operand, slot) in operands.iter().zip(layout) {
+                match (operand, slot) {
+                    (Operand::Register(Reg(reg_num)), _) if *reg_num >= abi::REGISTERS => errors
+                        .push(BuildError::InvalidRegister {
+                            statement,
+                            reg_num: *reg_num,
+                        }),
+                    (Operand::Immediate(value), Slot::Immediate)
+                    | (Operand::Immediate(value), Slot::Target) => {
+                        let range = if opcode.signed_immediate() {
+                            i32::from(i16::MIN)..=i32::from(i16::MAX)
+                        } else {
+                            0..=i32::from(u16::MAX)
+                        };
+                        if !range.contains(value) {
+                            errors.push(BuildError::ImmediateOutOfRange {
+                                statement,
+                                value: *value,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Item::Directive(name, Some(text)) => {
+            let i = self::statement(s);
+            match *name {
+                "space" | "align" => {
+                    let least = (*name == "align") as u32;
+                    if i.count().filter(|n| *n >= least).is_none() {
+                        errors.push(BuildError::InvalidCount {
+                            statement,
+                            directive: name.to_string(),
+                        });
+                    }
+                }
+                _ if !text.starts_with('@') => {
+                    let width = layout::width(name).unwrap_or(4);
+                    for value in i.values().iter().filter_map(|v| v.constant()) {
+                        if !layout::value_range(width).contains(&value) {
+                            errors.push(BuildError::ValueOutOfRange {
+                                statement,
+                                directive: name.to_string(),
+                                value,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Item::Directive(_, None) => {}
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    const R0: Reg = Reg(0);
+    const R1: Reg = Reg(1);
+    const R2: Reg = Reg(2);
+
+    #[test]
+    fn test_build_matches_assembler() {
+        let built = ProgramBuilder::new()
+            .data()
+            .label("greeting")
+            .asciiz("say \"hi\"\n")
+            .label("table")
+            .address("main")
+            .half(&[-1, 7])
+            .code()
+            .jmp("main")
+            .label("main")
+            .load(R0, 70000)
+            .load_address(R1, "greeting")
+            .label(".loop")
+            .addi(R0, R0, -1)
+            .cmpi(R0, 0)
+            .jmpne(".loop")
+            .beq(R0, R1, "1f")
+            .label("1")
+            .hlt()
+            .build()
+            .unwrap();
+
+        let mut asm = Assembler::new();
+        let code = asm
+            .try_assemble(
+                ".data\ngreeting: .asciiz \"say \\\"hi\\\"\\n\"\ntable: .word @main\n\
+                 .half -1, 7\n.code\njmp @main\nmain: load $0 #70000\n\
+                 load $1 @greeting\n.loop: addi $0 $0 #-1\ncmpi $0 #0\njmpne @.loop\n\
+                 beq $0 $1 @1f\n1: hlt",
+            )
+            .unwrap();
+        assert_eq!(built.code, code);
+        assert_eq!(built.ro, asm.ro);
+    }
+
+    #[test]
+    fn test_build_and_run() {
+        let bytecode = ProgramBuilder::new()
+            .load(R0, 0)
+            .load(R2, 10)
+            .label("loop")
+            .inc(R0)
+            .neq(R0, R2)
+            .jmpe("loop")
+            .jmp("end")
+            .load(R1, 99)
+            .label("end")
+            .build()
+            .unwrap();
+        let mut vm = VM::new();
+        vm.program = bytecode.code;
+        vm.run();
+        assert_eq!(vm.registers[0], 10);
+        assert_eq!(vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let errors = ProgramBuilder::new()
+            .label("a b")
+            .load(Reg(32), 1)
+            .addi(R0, R1, 1)
+            .instruction(Opcode::ADD, vec![R0.into(), 1.into(), R2.into()])
+            .instruction(Opcode::LOAD, vec![R0.into()])
+            .instruction(Opcode::CMPI, vec![R0.into(), 70000.into()])
+            .byte(&[256])
+            .align(0)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                BuildError::InvalidLabel {
+                    statement: 0,
+                    name: "a b".to_string()
+                },
+                BuildError::InvalidRegister {
+                    statement: 1,
+                    reg_num: 32
+                },
+                BuildError::InvalidOperands {
+                    statement: 3,
+                    opcode: Opcode::ADD
+                },
+                BuildError::InvalidOperands {
+                    statement: 4,
+                    opcode: Opcode::LOAD
+                },
+                BuildError::ImmediateOutOfRange {
+                    statement: 5,
+                    value: 70000
+                },
+                BuildError::ValueOutOfRange {
+                    statement: 6,
+                    directive: "byte".to_string(),
+                    value: 256
+                },
+                BuildError::InvalidCount {
+                    statement: 7,
+                    directive: "align".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            errors[2].to_string(),
+            "statement 3: expected `add $a $b $d`"
+        );
+
+        let errors = ProgramBuilder::new()
+            .label("main")
+            .jmp("nowhere")
+            .label("main")
+            .address(".missing")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                BuildError::DuplicateLabel {
+                    statement: 2,
+                    name: "main".to_string()
+                },
+                BuildError::UndefinedLabel {
+                    statement: 1,
+                    name: "nowhere".to_string()
+                },
+                BuildError::UndefinedLabel {
+                    statement: 3,
+                    name: ".missing".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "statement 1: undefined label `nowhere`"
+        );
+    }
+
+    #[test]
+    fn test_build_jump_out_of_range() {
+        let errors = ProgramBuilder::new()
+            .jmp("far")
+            .bytes(&[0; 40000])
+            .label("far")
+            .hlt()
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![BuildError::JumpOutOfRange {
+                statement: 0,
+                name: "far".to_string(),
+                distance: 40004,
+            }]
+        );
+    }
+}
@@ -1,9 +1,11 @@
+use super::expression::expression_list;
 use super::instruction_parsers::AssemblerInstruction;
 use super::label_parsers::label_declaration;
 use super::operand_parsers::operand;
+use super::register_parsers::register;
 use super::Token;
 use nom::types::CompleteStr;
-use nom::{alpha1, alt, do_parse, opt, tag, ws};
+use nom::{alpha1, alphanumeric, alt, do_parse, opt, recognize, tag, ws};
 
 nom::named!(directive_declaration<CompleteStr, Token>,
   do_parse!(
@@ -37,11 +39,61 @@ nom::named!(directive_combined<CompleteStr, AssemblerInstruction>,
     )
 );
 
+nom::named!(
+    // `.alias counter $3`: `$counter` means `$3` from here on
+    alias_directive<CompleteStr, AssemblerInstruction>,
+    ws!(
+        do_parse!(
+            l: opt!(label_declaration) >>
+            tag!(".alias") >>
+            name: alphanumeric >>
+            r: register >>
+            (
+                AssemblerInstruction{
+                    opcode: None,
+                    directive: Some(Token::Directive { name: "alias" }),
+                    label: l,
+                    operand1: Some(Token::RegisterName { name: &name }),
+                    operand2: Some(r),
+                    operand3: None,
+                }
+            )
+        )
+    )
+);
+
+nom::named!(
+    // `.word 1, 2, @table` or `.space 16`: a directive taking expressions
+    data_directive<CompleteStr, AssemblerInstruction>,
+    ws!(
+        do_parse!(
+            l: opt!(label_declaration) >>
+            name: alt!(
+                tag!(".byte") | tag!(".half") | tag!(".word") |
+                tag!(".space") | tag!(".align") | tag!(".rept")
+            ) >>
+            values: recognize!(expression_list) >>
+            (
+                AssemblerInstruction{
+                    opcode: None,
+                    directive: Some(Token::Directive { name: &name[1..] }),
+                    label: l,
+                    operand1: Some(Token::ExprList { text: values.0.trim() }),
+                    operand2: None,
+                    operand3: None,
+                }
+            )
+        )
+    )
+);
+
 nom::named!(
     // Will try to parse out any of the Directive forms
     pub directive<CompleteStr, AssemblerInstruction>,
     do_parse!(
         ins: alt!(
+            alias_directive |
+            data_directive |
             directive_combined
         ) >>
         (
@@ -68,6 +120,37 @@ mod tests {
         assert_eq!(directive, Token::Directive { name: "data" })
     }
 
+    #[test]
+    fn test_alias_directive() {
+        let (_, directive) = directive(CompleteStr(".alias counter $t0")).unwrap();
+        assert_eq!(directive.directive_name(), Some("alias"));
+        assert_eq!(
+            directive.operand1,
+            Some(Token::RegisterName { name: "counter" })
+        );
+        assert_eq!(directive.operand2, Some(Token::RegisterName { name: "t0" }));
+    }
+
+    #[test]
+    fn test_data_directive() {
+        let (rest, word) = directive(CompleteStr("table: .word 1, @end - 2 ; sizes\nhlt")).unwrap();
+        assert_eq!(rest.0, "; sizes\nhlt");
+        assert_eq!(word.directive_name(), Some("word"));
+        assert_eq!(word.label_name(), Some("table"));
+        assert_eq!(
+            word.operand1,
+            Some(Token::ExprList {
+                text: "1, @end - 2"
+            })
+        );
+        assert_eq!(word.values().len(), 2);
+
+        let (_, space) = directive(CompleteStr(".space 4 * 4")).unwrap();
+        assert_eq!(space.count(), Some(16));
+        let (_, other) = directive(CompleteStr(".wordy")).unwrap();
+        assert_eq!(other.directive_name(), Some("wordy"));
+    }
+
     #[test]
     fn test_string_directive() {
         let result = directive_combined(CompleteStr("test: .asciiz 'Hello'"));
@@ -79,7 +162,7 @@ mod tests {
             opcode: None,
             label: Some(Token::LabelDecl { name: "test" }),
             directive: Some(Token::Directive { name: "asciiz" }),
-            operand1: Some(Token::IrString { name: "Hello" }),
+            operand1: Some(Token::IrString { text: "'Hello'" }),
             operand2: None,
             operand3: None,
         };
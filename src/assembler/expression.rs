@@ -0,0 +1,237 @@
+//! Constant expressions, as used by data directives such as `.word` and by
+//! conditional assembly.
+//!
+//! An expression is built from integers (`10`, `#10`, `0x1f`), label
+//! addresses (`@table`), unary `-`, `+ - * /` with the usual precedence,
+//! comparisons (`== != < <= > >=`, giving 1 or 0) and parentheses.
+
+use super::label_parsers::label_name;
+use nom::types::CompleteStr;
+use nom::{
+    alt, digit, do_parse, hex_digit, many0, map, map_res, opt, pair, preceded,
+    separated_nonempty_list, tag, tag_no_case, value, ws,
+};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr<'a> {
+    Int(i64),
+    Label(&'a str),
+    Neg(Box<Expr<'a>>),
+    /// Two operands joined by an operator such as `+` or `<=`.
+    Binary(Box<Expr<'a>>, &'static str, Box<Expr<'a>>),
+}
+
+/// How tightly `op` binds; higher binds tighter.
+fn precedence(op: &str) -> u8 {
+    match op {
+        "*" | "/" => 2,
+        "+" | "-" => 1,
+        _ => 0,
+    }
+}
+
+impl<'a> Expr<'a> {
+    /// The value, with labels looked up by `label`. `None` if a label is
+    /// unknown, or on overflow or division by zero.
+    pub fn eval(&self, label: &dyn Fn(&str) -> Option<i64>) -> Option<i64> {
+        match self {
+            Expr::Int(value) => Some(*value),
+            Expr::Label(name) => label(name),
+            Expr::Neg(e) => e.eval(label)?.checked_neg(),
+            Expr::Binary(l, op, r) => {
+                let (l, r) = (l.eval(label)?, r.eval(label)?);
+                match *op {
+                    "+" => l.checked_add(r),
+                    "-" => l.checked_sub(r),
+                    "*" => l.checked_mul(r),
+                    "/" => l.checked_div(r),
+                    "==" => Some((l == r) as i64),
+                    "!=" => Some((l != r) as i64),
+                    "<" => Some((l < r) as i64),
+                    "<=" => Some((l <= r) as i64),
+                    ">" => Some((l > r) as i64),
+                    _ => Some((l >= r) as i64),
+                }
+            }
+        }
+    }
+
+    /// The value of an expression that uses no labels.
+    pub fn constant(&self) -> Option<i64> {
+        self.eval(&|_| None)
+    }
+
+    /// Every label the expression refers to, left to right.
+    pub fn labels(&self) -> Vec<&'a str> {
+        match self {
+            Expr::Int(_) => vec![],
+            Expr::Label(name) => vec![name],
+            Expr::Neg(e) => e.labels(),
+            Expr::Binary(l, _, r) => {
+                let mut labels = l.labels();
+                labels.extend(r.labels());
+                labels
+            }
+        }
+    }
+}
+
+impl<'a> fmt::Display for Expr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nested = |e: &Expr| match e {
+            Expr::Binary(..) => format!("({})", e),
+            _ => e.to_string(),
+        };
+        match self {
+            Expr::Int(value) => write!(f, "{}", value),
+            Expr::Label(name) => write!(f, "@{}", name),
+            Expr::Neg(e) => write!(f, "-{}", nested(e)),
+            // Operators bind leftwards, so only a right side of the same
+            // precedence needs brackets.
+            Expr::Binary(l, op, r) => {
+                let left = match &**l {
+                    Expr::Binary(_, inner, _) if precedence(inner) < precedence(op) => nested(l),
+                    _ => l.to_string(),
+                };
+                let right = match &**r {
+                    Expr::Binary(_, inner, _) if precedence(inner) > precedence(op) => {
+                        r.to_string()
+                    }
+                    _ => nested(r),
+                };
+                write!(f, "{} {} {}", left, op, right)
+            }
+        }
+    }
+}
+
+fn fold<'a>(first: Expr<'a>, rest: Vec<(&'static str, Expr<'a>)>) -> Expr<'a> {
+    rest.into_iter().fold(first, |l, (op, r)| {
+        Expr::Binary(Box::new(l), op, Box::new(r))
+    })
+}
+
+nom::named!(number<CompleteStr, Expr>,
+    do_parse!(
+        opt!(tag!("#")) >>
+        value: alt!(
+            map_res!(preceded!(tag_no_case!("0x"), hex_digit), |s: CompleteStr| {
+                i64::from_str_radix(&s, 16)
+            }) |
+            map_res!(digit, |s: CompleteStr| s.parse::<i64>())
+        ) >>
+        (Expr::Int(value))
+    )
+);
+
+nom::named!(label<CompleteStr, Expr>,
+    map!(preceded!(tag!("@"), label_name), |name| Expr::Label(name.0))
+);
+
+nom::named!(factor<CompleteStr, Expr>,
+    ws!(alt!(
+        number |
+        label |
+        map!(preceded!(tag!("-"), factor), |e| Expr::Neg(Box::new(e))) |
+        delimited!(tag!("("), expression, tag!(")"))
+    ))
+);
+
+nom::named!(term<CompleteStr, Expr>,
+    do_parse!(
+        first: factor >>
+        rest: many0!(pair!(
+            ws!(alt!(value!("*", tag!("*")) | value!("/", tag!("/")))),
+            factor
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+nom::named!(sum<CompleteStr, Expr>,
+    do_parse!(
+        first: term >>
+        rest: many0!(pair!(
+            ws!(alt!(value!("+", tag!("+")) | value!("-", tag!("-")))),
+            term
+        )) >>
+        (fold(first, rest))
+    )
+);
+
+nom::named!(comparison<CompleteStr, &'static str>,
+    ws!(alt!(
+        value!("==", tag!("==")) |
+        value!("!=", tag!("!=")) |
+        value!("<=", tag!("<=")) |
+        value!(">=", tag!(">=")) |
+        value!("<", tag!("<")) |
+        value!(">", tag!(">"))
+    ))
+);
+
+nom::named!(pub expression<CompleteStr, Expr>,
+    do_parse!(
+        first: sum >>
+        rest: many0!(pair!(comparison, sum)) >>
+        (fold(first, rest))
+    )
+);
+
+nom::named!(
+    // `1, 2 * 3, @table`
+    pub expression_list<CompleteStr, Vec<Expr>>,
+    separated_nonempty_list!(ws!(tag!(",")), expression)
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Expr<'_> {
+        let (rest, e) = expression(CompleteStr(text)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        e
+    }
+
+    #[test]
+    fn test_eval() {
+        let labels = |name: &str| if name == "table" { Some(16) } else { None };
+        assert_eq!(parse("1 + 2 * 3").constant(), Some(7));
+        assert_eq!(parse("(1 + 2) * 3").constant(), Some(9));
+        assert_eq!(parse("10 - 4 - 3").constant(), Some(3));
+        assert_eq!(parse("-#4 + 0x10").constant(), Some(12));
+        assert_eq!(parse("@table + 4").eval(&labels), Some(20));
+        assert_eq!(parse("@table + 4").constant(), None);
+        assert_eq!(parse("@other").eval(&labels), None);
+        assert_eq!(parse("1 / 0").constant(), None);
+        assert_eq!(parse("@a * (@b - 1)").labels(), vec!["a", "b"]);
+        assert_eq!(parse("2 + 1 > 2").constant(), Some(1));
+        assert_eq!(parse("1 == 2").constant(), Some(0));
+        assert_eq!(parse("3 <= 3").constant(), Some(1));
+    }
+
+    #[test]
+    fn test_expression_list() {
+        let (rest, list) = expression_list(CompleteStr("1,2 ,  @end-@start\nhlt")).unwrap();
+        assert_eq!(rest.trim(), "hlt");
+        assert_eq!(list.len(), 3);
+        assert!(expression_list(CompleteStr(", 1")).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        for text in &[
+            "1 + 2 * 3",
+            "(1 + 2) * 3",
+            "10 - (4 - 3)",
+            "-(@a + 1)",
+            "@a * 2 / 4",
+            "@a + 1 >= 2 * 3",
+        ] {
+            assert_eq!(parse(text).to_string(), *text);
+            assert_eq!(parse(&parse(text).to_string()), parse(text));
+        }
+    }
+}
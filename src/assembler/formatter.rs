@@ -0,0 +1,285 @@
+//! The canonical layout for Iridium assembly, as produced by `iridium fmt`.
+//!
+//! Labels, mnemonics and operands each get their own column, mnemonics are
+//! lowercased, and trailing comments line up. Formatting only ever moves
+//! whitespace around and changes the case of mnemonics, so the assembled
+//! bytes stay the same. Lines inside `.if` blocks that do not parse are left
+//! as they are, since they may never be assembled.
+
+use super::instruction_parsers::AssemblerInstruction;
+use super::preprocessor::conditional;
+use super::program_parsers::{split_comment, statement};
+use super::Token;
+use crate::instruction::Opcode;
+use nom::types::CompleteStr;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub struct FormatError {
+    /// One-based line number.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// A source line broken into its columns.
+#[derive(Debug, Default)]
+struct Line {
+    label: Option<String>,
+    mnemonic: String,
+    operands: String,
+    comment: Option<String>,
+    /// The whole line, kept as written.
+    verbatim: Option<String>,
+}
+
+fn operand_text(t: &Token) -> String {
+    match t {
+        Token::Register { reg_num } => format!("${}", reg_num),
+        Token::RegisterName { name } => format!("${}", name),
+        Token::IntOperand { value } => format!("#{}", value),
+        Token::LabelUsage { name } => format!("@{}", name),
+        Token::IrString { text } => text.to_string(),
+        Token::ExprList { text } => {
+            let values: Vec<&str> = text.split(',').map(str::trim).collect();
+            values.join(", ")
+        }
+        _ => String::new(),
+    }
+}
+
+fn mnemonic_text(ins: &AssemblerInstruction) -> String {
+    match (ins.opcode, ins.directive_name()) {
+        (Some(Token::Op { code }), _) => code.mnemonic().to_string(),
+        (Some(Token::Pseudo { name }), _) => name.mnemonic().to_string(),
+        (_, Some(name)) => format!(".{}", name),
+        (_, None) => String::new(),
+    }
+}
+
+fn parse_line(number: usize, line: &str) -> Result<Option<Line>, FormatError> {
+    let (code, comment) = split_comment(line);
+    let code = code.trim();
+    let comment = comment.map(|c| c.trim_end().to_string());
+    if code.is_empty() {
+        return Ok(comment.map(|comment| Line {
+            comment: Some(comment),
+            ..Line::default()
+        }));
+    }
+
+    if let Some((name, rest)) = conditional(code) {
+        return Ok(Some(Line {
+            mnemonic: format!(".{}", name),
+            operands: rest.split_whitespace().collect::<Vec<_>>().join(" "),
+            comment,
+            ..Line::default()
+        }));
+    }
+
+    let error = |message: String| FormatError {
+        line: number,
+        message,
+    };
+    let ins = match statement(CompleteStr(code)) {
+        Ok((rest, ins)) if rest.trim().is_empty() => ins,
+        Ok((rest, _)) => return Err(error(format!("unexpected `{}`", rest.trim()))),
+        Err(_) => return Err(error("unable to parse statement".to_string())),
+    };
+    if ins.opcode() == Some(Opcode::IGL) {
+        return Err(error("unknown mnemonic".to_string()));
+    }
+
+    let mut operands: Vec<String> = [ins.operand1, ins.operand2, ins.operand3]
+        .iter()
+        .flatten()
+        .map(operand_text)
+        .collect();
+    // `.alias counter $3` names the register without a `$`.
+    if let (Some("alias"), Some(Token::RegisterName { name })) =
+        (ins.directive_name(), ins.operand1)
+    {
+        operands[0] = name.to_string();
+    }
+    Ok(Some(Line {
+        label: ins.label_name().map(|l| format!("{}:", l)),
+        mnemonic: mnemonic_text(&ins),
+        operands: operands.join(" "),
+        comment,
+        verbatim: None,
+    }))
+}
+
+/// Reformats a whole file.
+pub fn format(source: &str) -> Result<String, FormatError> {
+    // `None` is a blank line; runs of them collapse into one.
+    let mut lines = vec![];
+    let mut depth = 0u32;
+    for (index, text) in source.lines().enumerate() {
+        let line = match parse_line(index + 1, text) {
+            Err(_) if depth > 0 => Some(Line {
+                verbatim: Some(text.trim_end().to_string()),
+                ..Line::default()
+            }),
+            line => line?,
+        };
+        match conditional(text) {
+            Some(("if", _)) | Some(("ifdef", _)) | Some(("ifndef", _)) => depth += 1,
+            Some(("endif", _)) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if line.is_some() || lines.last().is_some_and(Option::is_some) {
+            lines.push(line);
+        }
+    }
+    while let Some(None) = lines.last() {
+        lines.pop();
+    }
+
+    let statements = || lines.iter().flatten().filter(|l| !l.mnemonic.is_empty());
+    let label_width = statements()
+        .filter_map(|l| l.label.as_ref().map(|label| label.len() + 1))
+        .max()
+        .unwrap_or(0);
+    let mnemonic_width = statements().map(|l| l.mnemonic.len()).max().unwrap_or(0);
+
+    let mut code = vec![];
+    for line in &lines {
+        let text = match line {
+            Some(l) if !l.mnemonic.is_empty() => {
+                let label = l.label.as_ref().map_or("", String::as_str);
+                let text = if l.operands.is_empty() {
+                    format!("{:lw$}{}", label, l.mnemonic, lw = label_width)
+                } else {
+                    format!(
+                        "{:lw$}{:mw$} {}",
+                        label,
+                        l.mnemonic,
+                        l.operands,
+                        lw = label_width,
+                        mw = mnemonic_width
+                    )
+                };
+                Some(text)
+            }
+            _ => None,
+        };
+        code.push(text);
+    }
+    let comment_column = code.iter().flatten().map(String::len).max().unwrap_or(0) + 1;
+
+    let mut out = String::new();
+    for (line, text) in lines.iter().zip(code) {
+        match (line, text) {
+            (Some(l), Some(text)) => match &l.comment {
+                Some(comment) => {
+                    out.push_str(&format!("{:w$}{}", text, comment, w = comment_column))
+                }
+                None => out.push_str(&text),
+            },
+            (Some(l), None) => {
+                let text = l.verbatim.as_ref().or(l.comment.as_ref());
+                out.push_str(text.map_or("", String::as_str))
+            }
+            (None, _) => {}
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const MESSY: &str = "\n; counts to ten\n.data\nhello:   .asciiz 'a; b'\n.code\n  LOAD $0    #0 ;start\nload $1 #10\n\n\n\nloop: inc  $0   ; bump\nneq $0 $1\n  jmpe @loop\nHLT   \n\n";
+
+    #[test]
+    fn test_format() {
+        let expected = "; counts to ten
+       .data
+hello: .asciiz 'a; b'
+       .code
+       load    $0 #0  ;start
+       load    $1 #10
+
+loop:  inc     $0     ; bump
+       neq     $0 $1
+       jmpe    @loop
+       hlt
+";
+        assert_eq!(format(MESSY).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_register_names() {
+        assert_eq!(
+            format(".alias   counter $3\nINC $counter\npush $sp").unwrap(),
+            ".alias counter $3\ninc    $counter\npush   $sp\n"
+        );
+    }
+
+    #[test]
+    fn test_format_strings() {
+        assert_eq!(
+            format(".asciiz   \"a; \\\"b\\\"\"  ;  note\n.string 'c'").unwrap(),
+            ".asciiz \"a; \\\"b\\\"\" ;  note\n.string 'c'\n"
+        );
+    }
+
+    #[test]
+    fn test_format_data_directives() {
+        assert_eq!(
+            format(".data\ntable: .word 1,2 ,  @end - 0x10\n.space   #4\n.code\nend: hlt").unwrap(),
+            "       .data\ntable: .word  1, 2, @end - 0x10\n       .space #4\n       .code\nend:   hlt\n"
+        );
+    }
+
+    #[test]
+    fn test_format_pseudo_instructions() {
+        assert_eq!(
+            format("MOV $1   $2\nnop\nLi $0 #70000").unwrap(),
+            "mov $1 $2\nnop\nli  $0 #70000\n"
+        );
+    }
+
+    #[test]
+    fn test_format_conditionals() {
+        let source = ".ifdef  DEBUG ; checks\nload $0 #1\n.else\n   not ( yet\n.endif\n\nhlt\n";
+        let expected = ".ifdef DEBUG ; checks\nload   $0 #1\n.else\n   not ( yet\n.endif\n\nhlt\n";
+        assert_eq!(format(source).unwrap(), expected);
+        let err = format("not ( yet\n.if 1\n.endif\n").unwrap_err();
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn test_format_is_idempotent() {
+        let once = format(MESSY).unwrap();
+        assert_eq!(format(&once).unwrap(), once);
+    }
+
+    #[test]
+    fn test_format_keeps_bytes() {
+        let formatted = format(MESSY).unwrap();
+        let before = Assembler::new().assemble(MESSY);
+        let after = Assembler::new().assemble(&formatted);
+        assert!(before.is_some());
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let err = format("load $0 #1\nfrob $0\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown mnemonic");
+        let err = format("hlt\n$$\n").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+}
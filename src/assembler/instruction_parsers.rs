@@ -1,10 +1,13 @@
+use super::expression::{expression_list, Expr};
 use super::label_parsers::label_declaration;
 use super::opcode_parsers::*;
 use super::operand_parsers::{integer_operand, operand};
 use super::register_parsers::register;
+use super::strings;
 use super::SymbolTable;
 use super::Token;
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::instruction::{JumpMode, Opcode, Slot};
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
 use nom::types::CompleteStr;
 use nom::{alt, do_parse, opt};
 
@@ -32,6 +35,106 @@ impl<'a> AssemblerInstruction<'a> {
         None
     }
     }
+
+    pub fn opcode(&self) -> Option<Opcode> {
+        match self.opcode {
+            Some(Token::Op { code }) => Some(code),
+            _ => None,
+        }
+    }
+
+    pub fn is_directive(&self) -> bool {
+        self.directive.is_some()
+    }
+
+    pub fn directive_name(&self) -> Option<&'a str> {
+        match self.directive {
+            Some(Token::Directive { name }) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The opcode `as_bytes` emits: `load` of a literal that does not fit
+    /// in 16 unsigned bits becomes `load32`.
+    pub fn encoded_opcode(&self) -> Option<Opcode> {
+        match (self.opcode()?, self.operand2) {
+            (Opcode::LOAD, Some(Token::IntOperand { value })) if !(0..=0xffff).contains(&value) => {
+                Some(Opcode::LOAD32)
+            }
+            (opcode, _) => Some(opcode),
+        }
+    }
+
+    /// Number of bytes `as_bytes` produces for this instruction.
+    pub fn byte_len(&self) -> u32 {
+        if self.is_directive() {
+            return 0;
+        }
+        let layout = self.encoded_opcode().map_or(&[][..], Opcode::layout);
+        let padding = self.encoded_opcode().map_or(0, Opcode::padding);
+        let operands = [self.operand1, self.operand2, self.operand3];
+        let slots = operands.iter().flatten().enumerate();
+        slots.fold(1 + padding, |len, (slot, t)| match (layout.get(slot), t) {
+            (Some(Slot::Target), Token::Register { .. })
+            | (Some(Slot::Target), Token::RegisterName { .. }) => len + 2,
+            (Some(Slot::Target), _) => len + 3,
+            (Some(Slot::Wide), _) => len + 4,
+            (_, Token::Register { .. }) | (_, Token::RegisterName { .. }) => len + 1,
+            (_, Token::IntOperand { .. }) | (_, Token::LabelUsage { .. }) => len + 2,
+            _ => len,
+        })
+    }
+
+    /// The operand naming where a jump goes, for jumps that take a target.
+    pub fn target(&self) -> Option<Token<'a>> {
+        match self.opcode()?.target_slot()? {
+            0 => self.operand1,
+            1 => self.operand2,
+            _ => self.operand3,
+        }
+    }
+
+    /// Replaces the jump target; does nothing if there is none.
+    pub fn set_target(&mut self, target: Token<'a>) {
+        match self.opcode().and_then(Opcode::target_slot) {
+            Some(0) => self.operand1 = Some(target),
+            Some(1) => self.operand2 = Some(target),
+            Some(_) => self.operand3 = Some(target),
+            None => {}
+        }
+    }
+
+    /// The expressions of a data directive such as `.word`.
+    pub fn values(&self) -> Vec<Expr<'a>> {
+        match self.operand1 {
+            Some(Token::ExprList { text }) => expression_list(CompleteStr(text))
+                .map(|(_, values)| values)
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
+
+    /// The bytes of the string given to a directive such as `.asciiz`, in
+    /// UTF-8. Escapes that do not decode are left out; `check` reports them.
+    pub fn string(&self) -> Option<Vec<u8>> {
+        match self.operand1 {
+            Some(Token::IrString { text }) => Some(strings::decode(text).unwrap_or_default()),
+            _ => None,
+        }
+    }
+
+    /// The count given to `.space`, `.align` or `.rept`: a single constant
+    /// from 0 to 65535.
+    pub fn count(&self) -> Option<u32> {
+        match self.values().as_slice() {
+            [value] => value
+                .constant()
+                .filter(|n| (0..=0xffff).contains(n))
+                .map(|n| n as u32),
+            _ => None,
+        }
+    }
+
     fn extract_operand(t: Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
         match t {
             Token::Op { .. } => {
@@ -65,27 +168,78 @@ impl<'a> AssemblerInstruction<'a> {
         }
     }
 
+    /// Lays down a 32-bit operand.
+    fn extract_wide(t: Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+        let value = match t {
+            Token::IntOperand { value } => value,
+            Token::LabelUsage { name } => symbols
+                .symbol_value(name)
+                .unwrap_or_else(|| panic!("No value found for {:?}", name))
+                as i32,
+            _ => return AssemblerInstruction::extract_operand(t, results, symbols),
+        };
+        results.write_i32::<BigEndian>(value).unwrap();
+    }
+
+    /// Lays down a jump target, in the mode its token calls for.
+    fn extract_target(t: Token, offset: u32, results: &mut Vec<u8>, symbols: &SymbolTable) {
+        match t {
+            Token::LabelUsage { name } => {
+                let target = symbols
+                    .symbol_value(name)
+                    .unwrap_or_else(|| panic!("No value found for {:?}", name));
+                let displacement = displacement(offset, target) as u16;
+                results.push(JumpMode::Relative as u8);
+                results.push((displacement >> 8) as u8);
+                results.push(displacement as u8);
+            }
+            Token::IntOperand { .. } => {
+                results.push(JumpMode::Absolute as u8);
+                AssemblerInstruction::extract_operand(t, results, symbols);
+            }
+            _ => {
+                results.push(JumpMode::Register as u8);
+                AssemblerInstruction::extract_operand(t, results, symbols);
+            }
+        }
+    }
+
     // pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-    pub fn as_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// The encoded instruction, for when it starts at code offset `offset`.
+    pub fn as_bytes(&self, symbols: &SymbolTable, offset: u32) -> Vec<u8> {
         let mut results = Vec::new();
-        match &self.opcode {
-            Some(Token::Op { code }) => results.push(*code as u8),
-            _ => {
+        let code = match self.encoded_opcode() {
+            Some(code) => code,
+            None => {
                 println!("Non-opcode in opcode field");
                 std::process::exit(1);
             }
-        }
-
-        let operands = vec![&self.operand1, &self.operand2, &self.operand3];
+        };
+        results.push(code as u8);
 
-        operands.into_iter().flatten().for_each(|t| {
-            AssemblerInstruction::extract_operand(*t, &mut results, symbols);
-        });
+        let operands = vec![&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten();
+        for (slot, t) in operands.enumerate() {
+            match code.layout().get(slot) {
+                Some(Slot::Target) => {
+                    AssemblerInstruction::extract_target(*t, offset, &mut results, symbols)
+                }
+                Some(Slot::Wide) => AssemblerInstruction::extract_wide(*t, &mut results, symbols),
+                _ => AssemblerInstruction::extract_operand(*t, &mut results, symbols),
+            }
+        }
+        results.resize(results.len() + code.padding() as usize, 0);
 
         results
     }
 }
 
+/// How far a relative jump at `from` must go to reach `to`.
+pub fn displacement(from: u32, to: u32) -> i64 {
+    i64::from(to) - i64::from(from)
+}
+
 nom::named!(
     // Zero args: hlt
     instruction_0<CompleteStr, AssemblerInstruction>,
@@ -148,6 +302,7 @@ nom::named!(instruction_combined<CompleteStr, AssemblerInstruction>,
         o1: opt!(operand) >>
         o2: opt!(operand) >>
         o3: opt!(operand) >>
+        opt!(nom::multispace) >>
         (
             AssemblerInstruction{
                 opcode: Some(o),
@@ -175,7 +330,6 @@ nom::named!(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
 
     #[test]
     fn test_parse_instruction_2() {
@@ -1,13 +1,24 @@
 use super::Token;
 use nom::types::CompleteStr;
-use nom::{alphanumeric, multispace, opt, tag, ws};
+use nom::{
+    alphanumeric1, multispace, opt, pair, recognize, separated_nonempty_list_complete, tag, ws,
+};
+
+nom::named!(
+    // A label's name: `loop`, a local `.loop`, or `main.loop` in full
+    pub label_name<CompleteStr, CompleteStr>,
+    recognize!(pair!(
+        opt!(tag!(".")),
+        separated_nonempty_list_complete!(tag!("."), alphanumeric1)
+    ))
+);
 
 nom::named!(
     // Looks for a user-defined label, such as `label1:`
     pub label_declaration<CompleteStr, Token>,
     ws!(
         do_parse!(
-            name: alphanumeric >>
+            name: label_name >>
             tag!(":") >>
             opt!(multispace) >>
             (
@@ -23,7 +34,7 @@ nom::named!(
     ws!(
         do_parse!(
             tag!("@") >>
-            name: alphanumeric >>
+            name: label_name >>
             opt!(multispace) >>
             (
                 Token::LabelUsage{name: &name}
@@ -44,6 +55,9 @@ mod tests {
         assert_eq!(token, Token::LabelDecl { name: "test" });
         let result = label_declaration(CompleteStr("test"));
         assert_eq!(result.is_ok(), false);
+        let (_, token) = label_declaration(CompleteStr(".loop: inc $0")).unwrap();
+        assert_eq!(token, Token::LabelDecl { name: ".loop" });
+        assert!(label_declaration(CompleteStr(".data")).is_err());
     }
 
     #[test]
@@ -54,5 +68,10 @@ mod tests {
         assert_eq!(token, Token::LabelUsage { name: "test" });
         let result = label_usage(CompleteStr("test"));
         assert_eq!(result.is_ok(), false);
+        for name in &[".loop", "main.loop", "1f"] {
+            let usage = format!("@{}", name);
+            let (_, token) = label_usage(CompleteStr(&usage)).unwrap();
+            assert_eq!(token, Token::LabelUsage { name });
+        }
     }
 }
@@ -0,0 +1,194 @@
+//! Where each statement of a program lands, and the bytes it lays down.
+//!
+//! Instructions always go in the code section. Data directives go in the
+//! section selected by the last `.data` or `.code`, code being the default:
+//!
+//! | Directive             | Lays down                                   |
+//! |-----------------------|---------------------------------------------|
+//! | `.asciiz "text"`      | the text and a terminating zero             |
+//! | `.string "text"`      | the text's length as a word, then the text  |
+//! | `.byte 1, 2, ...`     | one byte per value                          |
+//! | `.half 1, 2, ...`     | two bytes per value, big-endian             |
+//! | `.word 1, 2, ...`     | four bytes per value, big-endian            |
+//! | `.space N`            | `N` zero bytes                              |
+//! | `.align N`            | zero bytes up to the next multiple of `N`   |
+//! | `.rept N` ... `.endr` | the statements between, `N` times           |
+//!
+//! Values are expressions, and may use labels: a code label is the offset
+//! of its instruction in the VM's program, a data label its offset in the
+//! read-only section.
+
+use super::instruction_parsers::AssemblerInstruction;
+use super::program_parsers::Program;
+use super::{Section, SymbolTable};
+use byteorder::{BigEndian, WriteBytesExt};
+
+/// Where a statement's bytes go.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Placement {
+    pub section: Section,
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// Bytes per value of a directive that lays down a list of values.
+pub fn width(directive: &str) -> Option<u32> {
+    match directive {
+        "byte" => Some(1),
+        "half" => Some(2),
+        "word" => Some(4),
+        _ => None,
+    }
+}
+
+/// Whether `i` is a directive that lays down data of its own, so that a
+/// label on it names that data.
+pub fn is_data(i: &AssemblerInstruction) -> bool {
+    match i.directive_name() {
+        Some(name) => ["asciiz", "string", "space"].contains(&name) || width(name).is_some(),
+        None => false,
+    }
+}
+
+/// The values that fit in `width` bytes, signed or unsigned.
+pub fn value_range(width: u32) -> std::ops::RangeInclusive<i64> {
+    let bits = 8 * width;
+    -(1 << (bits - 1))..=(1 << bits) - 1
+}
+
+/// Lays out `p` with its code starting at `code_base` and its data at
+/// `data_base`, one placement per statement.
+pub fn place(p: &Program, code_base: u32, data_base: u32) -> Vec<Placement> {
+    let mut section = Section::Code;
+    let (mut code, mut data) = (code_base, data_base);
+    p.instructions
+        .iter()
+        .map(|i| {
+            match i.directive_name() {
+                Some("data") => section = Section::Data,
+                Some("code") => section = Section::Code,
+                _ => {}
+            }
+            let (section, cursor) = match (i.is_directive(), section) {
+                (true, Section::Data) => (Section::Data, &mut data),
+                _ => (Section::Code, &mut code),
+            };
+            let offset = *cursor;
+            let len = if i.is_directive() {
+                data_len(i, offset)
+            } else {
+                i.byte_len()
+            };
+            *cursor += len;
+            Placement {
+                section,
+                offset,
+                len,
+            }
+        })
+        .collect()
+}
+
+fn data_len(i: &AssemblerInstruction, offset: u32) -> u32 {
+    let name = i.directive_name().unwrap_or_default();
+    match (name, i.string()) {
+        ("asciiz", Some(text)) => text.len() as u32 + 1,
+        ("string", Some(text)) => 4 + text.len() as u32,
+        ("space", _) => i.count().unwrap_or(0),
+        ("align", _) => match i.count() {
+            Some(n) if n > 0 => (n - offset % n) % n,
+            _ => 0,
+        },
+        _ => width(name).map_or(0, |w| w * i.values().len() as u32),
+    }
+}
+
+/// The bytes `i` lays down at `at`, with labels resolved from `symbols`.
+pub fn emit(i: &AssemblerInstruction, at: Placement, symbols: &SymbolTable) -> Vec<u8> {
+    if !i.is_directive() {
+        return i.as_bytes(symbols, at.offset);
+    }
+    let name = i.directive_name().unwrap_or_default();
+    match (name, i.string()) {
+        ("asciiz", Some(mut bytes)) => {
+            bytes.push(0);
+            bytes
+        }
+        ("string", Some(text)) => {
+            let mut bytes = vec![];
+            bytes.write_u32::<BigEndian>(text.len() as u32).unwrap();
+            bytes.extend(text);
+            bytes
+        }
+        ("space", _) | ("align", _) => vec![0; at.len as usize],
+        _ => {
+            let width = match width(name) {
+                Some(width) => width,
+                None => return vec![],
+            };
+            let label = |name: &str| symbols.symbol_value(name).map(i64::from);
+            let mut bytes = vec![];
+            for value in i.values() {
+                let value = value.eval(&label).unwrap_or(0);
+                bytes
+                    .write_uint::<BigEndian>(
+                        value as u64 & (u64::MAX >> (64 - 8 * width)),
+                        width as usize,
+                    )
+                    .unwrap();
+            }
+            bytes
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program;
+    use nom::types::CompleteStr;
+
+    #[test]
+    fn test_place() {
+        let source = ".data\nbytes: .byte 1, 2, 3\n.align 4\nwords: .word 1, 2\n\
+                      .code\nload $0 #1\n.half 7\nhlt";
+        let (_, p) = program(CompleteStr(source)).unwrap();
+        let placed: Vec<(Section, u32, u32)> = place(&p, 100, 0)
+            .iter()
+            .map(|at| (at.section, at.offset, at.len))
+            .collect();
+        assert_eq!(
+            placed,
+            vec![
+                (Section::Data, 0, 0),
+                (Section::Data, 0, 3),
+                (Section::Data, 3, 1),
+                (Section::Data, 4, 8),
+                (Section::Code, 100, 0),
+                (Section::Code, 100, 4),
+                (Section::Code, 104, 2),
+                (Section::Code, 106, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_emit() {
+        let (_, p) = program(CompleteStr(
+            ".byte -1, 255\n.half 0x1234\n.word -2\n.space 2",
+        ))
+        .unwrap();
+        let symbols = SymbolTable::new();
+        let bytes: Vec<u8> = p
+            .instructions
+            .iter()
+            .zip(place(&p, 0, 0))
+            .flat_map(|(i, at)| emit(i, at, &symbols))
+            .collect();
+        assert_eq!(
+            bytes,
+            vec![0xff, 0xff, 0x12, 0x34, 0xff, 0xff, 0xff, 0xfe, 0, 0]
+        );
+        assert_eq!(value_range(1), -128..=255);
+    }
+}
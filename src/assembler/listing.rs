@@ -0,0 +1,179 @@
+use super::instruction_parsers::AssemblerInstruction;
+use super::layout::{self, Placement};
+use super::program_parsers::{Program, Span};
+use super::{Section, SymbolTable, Token};
+use std::fmt;
+
+/// One source statement and what the assembler generated for it.
+#[derive(Debug, PartialEq)]
+pub struct ListingLine {
+    pub section: Section,
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+    /// Empty after the first instruction a pseudo-instruction expands to.
+    pub source: String,
+    /// Labels used by this statement with the values they resolved to.
+    pub resolved: Vec<(String, Option<u32>)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ListingSymbol {
+    pub name: String,
+    pub symbol_type: String,
+    pub offset: u32,
+    pub section: Section,
+}
+
+/// An assembly listing plus the symbol map it was resolved against.
+#[derive(Debug, Default, PartialEq)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    pub symbols: Vec<ListingSymbol>,
+}
+
+impl Listing {
+    /// The listing of `p`, laid out as `placements`.
+    pub fn new(
+        p: &Program,
+        sources: &[Span],
+        placements: &[Placement],
+        symbols: &SymbolTable,
+    ) -> Listing {
+        let mut lines = vec![];
+        let mut previous = None;
+        for ((i, source), at) in p.instructions.iter().zip(sources).zip(placements) {
+            lines.push(ListingLine {
+                section: at.section,
+                offset: at.offset,
+                bytes: layout::emit(i, *at, symbols),
+                source: match previous.replace(source.start) {
+                    Some(start) if start == source.start => String::new(),
+                    _ => source.text.to_string(),
+                },
+                resolved: Listing::label_usages(i)
+                    .into_iter()
+                    .map(|name| (name.to_string(), symbols.symbol_value(name)))
+                    .collect(),
+            });
+        }
+
+        let symbols = symbols
+            .sorted()
+            .into_iter()
+            .map(|s| ListingSymbol {
+                name: s.name().to_string(),
+                symbol_type: s.symbol_type().to_string(),
+                offset: s.offset(),
+                section: s.section(),
+            })
+            .collect();
+
+        Listing { lines, symbols }
+    }
+
+    fn label_usages<'a>(i: &AssemblerInstruction<'a>) -> Vec<&'a str> {
+        let mut usages: Vec<&str> = vec![i.operand1, i.operand2, i.operand3]
+            .into_iter()
+            .filter_map(|t| match t {
+                Some(Token::LabelUsage { name }) => Some(name),
+                _ => None,
+            })
+            .collect();
+        for name in i.values().iter().flat_map(|v| v.labels()) {
+            if !usages.contains(&name) {
+                usages.push(name);
+            }
+        }
+        usages
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<4} {:<6} {:<12} SOURCE", "SECT", "OFFSET", "BYTES")?;
+        for line in &self.lines {
+            let bytes = line
+                .bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            write!(
+                f,
+                "{:<4} {:04X}   {:<12} {}",
+                line.section, line.offset, bytes, line.source
+            )?;
+            for (name, value) in &line.resolved {
+                match value {
+                    Some(v) => write!(f, "  ; @{} = {:04X}", name, v)?,
+                    None => write!(f, "  ; @{} unresolved", name)?,
+                }
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<16} {:<6} {:<6} SECTION", "SYMBOL", "TYPE", "OFFSET")?;
+        for s in &self.symbols {
+            writeln!(
+                f,
+                "{:<16} {:<6} {:04X}   {}",
+                s.name, s.symbol_type, s.offset, s.section
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{Assembler, Section};
+    use crate::instruction::Opcode;
+
+    #[test]
+    fn test_listing_lines() {
+        let mut asm = Assembler::new().with_listing();
+        asm.assemble("load $0 #100\ntest: inc $0\njmpe @test\nhlt")
+            .unwrap();
+        let listing = asm.listing().unwrap();
+        assert_eq!(listing.lines.len(), 4);
+        assert_eq!(listing.lines[0].bytes, vec![0, 0, 0, 100]);
+        assert_eq!(listing.lines[1].offset, 4);
+        assert_eq!(listing.lines[2].source, "jmpe @test");
+        assert_eq!(
+            listing.lines[2].resolved,
+            vec![("test".to_string(), Some(4))]
+        );
+    }
+
+    #[test]
+    fn test_listing_pseudo_instructions() {
+        let mut asm = Assembler::new().with_listing();
+        asm.assemble("neg $1\nhlt").unwrap();
+        let listing = asm.listing().unwrap();
+        let sources: Vec<&str> = listing.lines.iter().map(|l| l.source.as_str()).collect();
+        assert_eq!(sources, vec!["neg $1", "", "hlt"]);
+        assert_eq!(listing.lines[1].bytes, vec![Opcode::INC as u8, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_listing_symbol_map() {
+        let mut asm = Assembler::new().with_listing();
+        asm.assemble(".data\nhello: .asciiz 'Hello'\n.code\nstart: hlt\nend: hlt")
+            .unwrap();
+        let listing = asm.listing().unwrap();
+        let names: Vec<&str> = listing.symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["start", "end", "hello"]);
+        assert_eq!(listing.symbols[2].section, Section::Data);
+        assert!(listing
+            .to_string()
+            .contains("hello            data   0000   data"));
+    }
+
+    #[test]
+    fn test_listing_disabled_by_default() {
+        let mut asm = Assembler::new();
+        asm.assemble("hlt").unwrap();
+        assert!(asm.listing().is_none());
+    }
+}
@@ -1,42 +1,318 @@
+pub mod builder;
 mod directive_parsers;
+pub mod expression;
+pub mod formatter;
 mod instruction_parsers;
 mod label_parsers;
+pub mod layout;
+pub mod listing;
 mod opcode_parsers;
 mod operand_parsers;
+pub mod optimizer;
+pub mod preprocessor;
 pub mod program_parsers;
+pub mod pseudo;
 mod register_parsers;
+pub mod scope;
+pub mod strings;
 
-use self::program_parsers::Program;
-use super::instruction::Opcode;
-use nom::types::CompleteStr;
+use self::expression::Expr;
+use self::instruction_parsers::displacement;
+use self::layout::Placement;
+use self::listing::Listing;
+use self::preprocessor::Constant;
+use self::program_parsers::{filler, Program, Span};
+use self::scope::Scope;
+use super::debug_info::{line_column, DebugInfo};
+use super::instruction::{Opcode, Slot};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::str;
 
 #[derive(Debug, Default)]
 pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
+    /// Read-only data collected from directives such as `.asciiz`.
+    pub ro: Vec<u8>,
+    listing: Option<Listing>,
+    debug_info: Option<DebugInfo>,
+    optimize: bool,
+    /// Where the code will be placed in the VM's program.
+    base: u32,
+    /// Names for conditional assembly, as given by `-D NAME=value`.
+    defines: HashMap<String, i64>,
 }
 
-#[derive(Debug)]
+/// Something wrong with the source, at a 1-based line and column.
+#[derive(Debug, PartialEq, Clone)]
+pub enum AssemblerError {
+    ParseError {
+        line: u32,
+        column: u32,
+        text: String,
+    },
+    UnknownMnemonic {
+        line: u32,
+        column: u32,
+        mnemonic: String,
+    },
+    UndefinedLabel {
+        line: u32,
+        column: u32,
+        name: String,
+    },
+    /// A `$name` that is neither a built-in register name nor an `.alias`.
+    UnknownRegister {
+        line: u32,
+        column: u32,
+        name: String,
+    },
+    /// A literal too large for the 16-bit immediate of its instruction.
+    ImmediateOutOfRange { line: u32, column: u32, value: i32 },
+    /// A jump to `name` is further than a relative jump can reach.
+    JumpOutOfRange {
+        line: u32,
+        column: u32,
+        name: String,
+        distance: i64,
+    },
+    /// A value too large for the data directive laying it down.
+    ValueOutOfRange {
+        line: u32,
+        column: u32,
+        directive: String,
+        value: i64,
+    },
+    /// An expression that overflows or divides by zero.
+    InvalidExpression {
+        line: u32,
+        column: u32,
+        text: String,
+    },
+    /// `.space`, `.align` or `.rept` without a usable constant count.
+    InvalidCount {
+        line: u32,
+        column: u32,
+        directive: String,
+    },
+    /// A label declared twice in the same scope, or already in the table.
+    DuplicateLabel {
+        line: u32,
+        column: u32,
+        name: String,
+    },
+    /// A backslash escape in a string that stands for nothing.
+    InvalidEscape {
+        line: u32,
+        column: u32,
+        escape: String,
+    },
+    /// A `.rept` without an `.endr`, or the other way round.
+    UnmatchedRepeat {
+        line: u32,
+        column: u32,
+        directive: String,
+    },
+    /// The message of an `.error` directive that was not excluded.
+    UserError {
+        line: u32,
+        column: u32,
+        message: String,
+    },
+    /// A name given a second definition, by `.equ` or as a label.
+    Redefined {
+        line: u32,
+        column: u32,
+        name: String,
+    },
+    /// A name in a condition that has not been defined.
+    UndefinedName {
+        line: u32,
+        column: u32,
+        name: String,
+    },
+    /// An `.if` without an `.endif`, or an `.elif`, `.else` or `.endif`
+    /// without an `.if`.
+    UnmatchedConditional {
+        line: u32,
+        column: u32,
+        directive: String,
+    },
+    /// An `.elif` or a second `.else` following an `.else`.
+    ConditionalAfterElse {
+        line: u32,
+        column: u32,
+        directive: String,
+    },
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::ParseError { line, column, text } => {
+                write!(f, "{}:{}: unable to parse `{}`", line, column, text)
+            }
+            AssemblerError::UnknownMnemonic {
+                line,
+                column,
+                mnemonic,
+            } => write!(f, "{}:{}: unknown mnemonic `{}`", line, column, mnemonic),
+            AssemblerError::UndefinedLabel { line, column, name } => {
+                write!(f, "{}:{}: undefined label `{}`", line, column, name)
+            }
+            AssemblerError::DuplicateLabel { line, column, name } => {
+                write!(
+                    f,
+                    "{}:{}: label `{}` is already declared",
+                    line, column, name
+                )
+            }
+            AssemblerError::UnknownRegister { line, column, name } => {
+                write!(f, "{}:{}: unknown register `${}`", line, column, name)
+            }
+            AssemblerError::ImmediateOutOfRange {
+                line,
+                column,
+                value,
+            } => write!(
+                f,
+                "{}:{}: immediate `{}` does not fit in 16 bits; load it into a register",
+                line, column, value
+            ),
+            AssemblerError::JumpOutOfRange {
+                line,
+                column,
+                name,
+                distance,
+            } => write!(
+                f,
+                "{}:{}: jump to `{}` is out of range ({} bytes away)",
+                line, column, name, distance
+            ),
+            AssemblerError::ValueOutOfRange {
+                line,
+                column,
+                directive,
+                value,
+            } => write!(
+                f,
+                "{}:{}: value `{}` does not fit in `.{}`",
+                line, column, value, directive
+            ),
+            AssemblerError::InvalidExpression { line, column, text } => {
+                write!(f, "{}:{}: cannot evaluate `{}`", line, column, text)
+            }
+            AssemblerError::InvalidEscape {
+                line,
+                column,
+                escape,
+            } => write!(f, "{}:{}: invalid escape `{}`", line, column, escape),
+            AssemblerError::InvalidCount {
+                line,
+                column,
+                directive,
+            } => write!(
+                f,
+                "{}:{}: `.{}` needs a constant count from {} to 65535",
+                line,
+                column,
+                directive,
+                (directive == "align") as u8
+            ),
+            AssemblerError::UnmatchedRepeat {
+                line,
+                column,
+                directive,
+            } => {
+                let other = if directive == "rept" { "endr" } else { "rept" };
+                write!(
+                    f,
+                    "{}:{}: `.{}` without `.{}`",
+                    line, column, directive, other
+                )
+            }
+            AssemblerError::UserError {
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}: {}", line, column, message),
+            AssemblerError::Redefined { line, column, name } => {
+                write!(f, "{}:{}: `{}` is already defined", line, column, name)
+            }
+            AssemblerError::UndefinedName { line, column, name } => {
+                write!(f, "{}:{}: `{}` is not defined", line, column, name)
+            }
+            AssemblerError::UnmatchedConditional {
+                line,
+                column,
+                directive,
+            } => {
+                let other = if directive.starts_with("if") {
+                    "endif"
+                } else {
+                    "if"
+                };
+                write!(
+                    f,
+                    "{}:{}: `.{}` without `.{}`",
+                    line, column, directive, other
+                )
+            }
+            AssemblerError::ConditionalAfterElse {
+                line,
+                column,
+                directive,
+            } => write!(f, "{}:{}: `.{}` after `.else`", line, column, directive),
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+#[derive(Debug, Default)]
 pub enum AssemblerPhase {
+    #[default]
     First,
     Second,
 }
 
-impl Default for AssemblerPhase {
-    fn default() -> Self {
-        AssemblerPhase::First
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum SymbolType {
+    /// A place in the code.
+    #[default]
+    Label,
+    /// A label on data laid down by a directive such as `.word`.
+    Data,
+    /// A value rather than a place, defined by `.equ`.
+    Constant,
+}
+
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolType::Label => f.pad("label"),
+            SymbolType::Data => f.pad("data"),
+            SymbolType::Constant => f.pad("const"),
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum SymbolType {
-    Label,
+/// Where a symbol's offset points into: executable code or read-only data.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Section {
+    #[default]
+    Code,
+    Data,
 }
 
-impl Default for SymbolType {
-    fn default() -> Self {
-        SymbolType::Label
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Section::Code => f.pad("code"),
+            Section::Data => f.pad("data"),
+        }
     }
 }
 
@@ -45,6 +321,7 @@ pub struct Symbol {
     name: String,
     offset: u32,
     symbol_type: SymbolType,
+    section: Section,
 }
 
 impl Symbol {
@@ -53,13 +330,38 @@ impl Symbol {
             name: name.to_string(),
             symbol_type,
             offset,
+            section: Section::Code,
         }
     }
+
+    pub fn in_section(mut self, section: Section) -> Self {
+        self.section = section;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Where a label is, or a constant's value as two's complement.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> SymbolType {
+        self.symbol_type
+    }
+
+    pub fn section(&self) -> Section {
+        self.section
+    }
 }
 
+/// Symbols in the order they were added, indexed by name.
 #[derive(Debug, Default)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
+    index: HashMap<String, usize>,
 }
 
 impl SymbolTable {
@@ -67,17 +369,33 @@ impl SymbolTable {
         SymbolTable::default()
     }
 
-    pub fn add_symbol(&mut self, s: Symbol) {
+    /// Adds `s`, unless a symbol of the same name is already in the table.
+    /// Returns whether it was added.
+    pub fn add_symbol(&mut self, s: Symbol) -> bool {
+        if self.index.contains_key(&s.name) {
+            return false;
+        }
+        self.index.insert(s.name.clone(), self.symbols.len());
         self.symbols.push(s);
+        true
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.index.get(name).map(|&at| &self.symbols[at])
     }
 
+    /// The offset or value of a symbol.
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return Some(symbol.offset);
-            }
-        }
-        None
+        self.symbol(s).map(|symbol| symbol.offset)
+    }
+
+    /// Symbols ordered the way a map file lists them: by section, then offset.
+    pub fn sorted(&self) -> Vec<&Symbol> {
+        let mut sorted: Vec<&Symbol> = self.symbols.iter().collect();
+        sorted.sort_by(|a, b| {
+            (a.section as u8, a.offset, &a.name).cmp(&(b.section as u8, b.offset, &b.name))
+        });
+        sorted
     }
 }
 
@@ -86,51 +404,512 @@ impl Assembler {
         Assembler::default()
     }
 
+    /// Also record a listing of what gets generated.
+    pub fn with_listing(mut self) -> Assembler {
+        self.listing = Some(Listing::default());
+        self
+    }
+
+    /// Also record debug info mapping code offsets back into `file`.
+    pub fn with_debug_info(mut self, file: &str) -> Assembler {
+        self.debug_info = Some(DebugInfo::new(file));
+        self
+    }
+
+    /// Run the peephole optimizer between parsing and label resolution.
+    pub fn with_optimizations(mut self) -> Assembler {
+        self.optimize = true;
+        self
+    }
+
+    /// Lay code out to be appended at `base`, after code already in the VM.
+    /// Labels already in `symbols` may be referenced from the new code.
+    pub fn with_base_offset(mut self, base: u32) -> Assembler {
+        self.base = base;
+        self
+    }
+
+    /// Define `name` as `value` for `.if`, `.ifdef` and `.ifndef`.
+    pub fn with_define(mut self, name: &str, value: i64) -> Assembler {
+        self.defines.insert(name.to_string(), value);
+        self
+    }
+
+    /// The listing of the last `assemble` call, if listing was enabled.
+    pub fn listing(&self) -> Option<&Listing> {
+        self.listing.as_ref()
+    }
+
+    /// The debug info of the last `assemble` call, if it was enabled.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
     pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        match program_parsers::program(CompleteStr(raw)) {
-            Ok((_rest, p)) => {
-                self.phase1_extract_labels(&p);
-                self.phase = AssemblerPhase::Second;
-                Some(self.phase2_process(&p))
-            }
-            Err(e) => {
-                println!("Error assembling the code: {:?}", e);
+        match self.try_assemble(raw) {
+            Ok(assembled) => Some(assembled),
+            Err(errors) => {
+                for e in errors {
+                    println!("Error assembling the code: {}", e);
+                }
                 None
             }
         }
     }
 
-    fn phase1_extract_labels(&mut self, p: &Program) {
-        let mut c = 0;
-        for i in &p.instructions {
+    /// Like `assemble`, but hands back every problem found in the source.
+    pub fn try_assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let (text, constants) = preprocessor::preprocess_with_constants(raw, &self.defines)?;
+        let raw = text.as_str();
+        let (mut p, sources) = match program_parsers::program_with_sources(raw) {
+            Ok(parsed) => parsed,
+            Err(_) => return Err(vec![parse_error(raw, 0)]),
+        };
+        let unknown = p.resolve_registers();
+        let errors = check(raw, &p, &sources, &self.symbols, &unknown);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let (p, sources) = p.expand_repeats(sources);
+        let mut names = vec![];
+        let (p, duplicates) = p.qualify_labels(&mut names);
+        let errors = self.check_duplicates(raw, &p, &sources, &duplicates);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let (p, sources) = if self.optimize {
+            optimizer::optimize(p, sources)
+        } else {
+            (p, sources)
+        };
+        let placements = self.place(&p);
+        let labels = layout_labels(&p, &placements);
+        let mut errors = self.check_jumps(raw, &p, &sources, &placements, &labels);
+        errors.extend(self.check_data(raw, &p, &sources, &labels));
+        errors.extend(self.check_constants(&constants, &labels));
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        for label in labels {
+            self.symbols.add_symbol(label);
+        }
+        for constant in constants {
+            let value = constant.value as i32 as u32;
+            let symbol = Symbol::new(&constant.name, SymbolType::Constant, value);
+            self.symbols.add_symbol(symbol);
+            self.defines.insert(constant.name, constant.value);
+        }
+        self.phase = AssemblerPhase::Second;
+        let assembled = self.phase2_process(&p, &placements);
+        if self.listing.is_some() {
+            self.listing = Some(Listing::new(&p, &sources, &placements, &self.symbols));
+        }
+        if let Some(debug) = self.debug_info.take() {
+            let debug = self.collect_debug_info(&debug.file, &p, &sources, &placements, raw);
+            self.debug_info = Some(debug);
+        }
+        Ok(assembled)
+    }
+
+    /// Fills the symbol table with every label in `p`.
+    pub fn phase1_extract_labels(&mut self, p: &Program) {
+        for label in layout_labels(p, &self.place(p)) {
+            self.symbols.add_symbol(label);
+        }
+    }
+
+    /// Lays `p` out after the code and data assembled so far.
+    fn place(&self, p: &Program) -> Vec<Placement> {
+        layout::place(p, self.base, self.ro.len() as u32)
+    }
+
+    /// Finds labels declared twice, as reported by `qualify_labels`, or
+    /// declared already by code assembled before.
+    fn check_duplicates(
+        &self,
+        raw: &str,
+        p: &Program,
+        sources: &[Span],
+        duplicates: &[(usize, &str)],
+    ) -> Vec<AssemblerError> {
+        let known = p
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, i)| Some((index, i.label_name()?)))
+            .filter(|(_, name)| self.symbols.symbol(name).is_some());
+        let mut found: Vec<(usize, &str)> = duplicates.iter().cloned().chain(known).collect();
+        found.sort_unstable();
+        found.dedup();
+        found
+            .into_iter()
+            .map(|(index, name)| {
+                // A statement's label is the first thing in it.
+                let (line, column) = line_column(raw, sources[index].start);
+                AssemblerError::DuplicateLabel {
+                    line,
+                    column,
+                    name: name.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// Finds constants named the same as a label, here or in the table.
+    fn check_constants(&self, constants: &[Constant], labels: &[Symbol]) -> Vec<AssemblerError> {
+        constants
+            .iter()
+            .filter(|c| {
+                self.symbols.symbol(&c.name).is_some() || labels.iter().any(|l| l.name == c.name)
+            })
+            .map(|c| AssemblerError::Redefined {
+                line: c.line,
+                column: c.column,
+                name: c.name.clone(),
+            })
+            .collect()
+    }
+
+    /// The value of a label, looking in `labels` as well as the table.
+    fn label_value(&self, labels: &[Symbol], name: &str) -> Option<u32> {
+        self.symbols
+            .symbol_value(name)
+            .or_else(|| labels.iter().find(|s| s.name == name).map(|s| s.offset))
+    }
+
+    /// Finds relative jumps whose label is out of reach of an `i16`.
+    fn check_jumps(
+        &self,
+        raw: &str,
+        p: &Program,
+        sources: &[Span],
+        placements: &[Placement],
+        labels: &[Symbol],
+    ) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        for ((i, span), at) in p.instructions.iter().zip(sources).zip(placements) {
+            if i.is_directive() {
+                continue;
+            }
+            if let Some(Token::LabelUsage { name }) = i.target() {
+                if let Some(target) = self.label_value(labels, name) {
+                    let distance = displacement(at.offset, target);
+                    if distance < i64::from(i16::MIN) || distance > i64::from(i16::MAX) {
+                        let at = span.text.find(&format!("@{}", name)).unwrap_or(0);
+                        let (line, column) = line_column(raw, span.start + at);
+                        errors.push(AssemblerError::JumpOutOfRange {
+                            line,
+                            column,
+                            name: name.to_string(),
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    /// Finds data directive values that cannot be computed or do not fit.
+    fn check_data(
+        &self,
+        raw: &str,
+        p: &Program,
+        sources: &[Span],
+        labels: &[Symbol],
+    ) -> Vec<AssemblerError> {
+        let label = |name: &str| self.label_value(labels, name).map(i64::from);
+        let mut errors = vec![];
+        for (i, span) in p.instructions.iter().zip(sources) {
+            let directive = i.directive_name().unwrap_or_default();
+            let width = match layout::width(directive) {
+                Some(width) => width,
+                None => continue,
+            };
+            for value in i.values() {
+                let result = value.eval(&label);
+                if result.is_some_and(|v| layout::value_range(width).contains(&v)) {
+                    continue;
+                }
+                let text = value.to_string();
+                let (line, column) = match (span.text.contains(&text), i.operand1) {
+                    (false, Some(Token::ExprList { text })) => position(raw, span, text),
+                    _ => position(raw, span, &text),
+                };
+                errors.push(match result {
+                    Some(value) => AssemblerError::ValueOutOfRange {
+                        line,
+                        column,
+                        directive: directive.to_string(),
+                        value,
+                    },
+                    None => AssemblerError::InvalidExpression { line, column, text },
+                });
+            }
+        }
+        errors
+    }
+
+    fn collect_debug_info(
+        &self,
+        file: &str,
+        p: &Program,
+        sources: &[Span],
+        placements: &[Placement],
+        raw: &str,
+    ) -> DebugInfo {
+        let mut debug = DebugInfo::new(file);
+        for ((i, span), at) in p.instructions.iter().zip(sources).zip(placements) {
+            if i.is_directive() {
+                continue;
+            }
             if let Some(label_name) = i.label_name() {
-                let s = Symbol::new(label_name, SymbolType::Label, c);
-                self.symbols.symbols.push(s);
+                debug.add_label(label_name, at.offset);
             }
-            c += 4;
+            debug.add_line(at.offset, raw, span.start);
         }
+        debug
     }
 
-    fn phase2_process(&mut self, p: &Program) -> Vec<u8> {
+    fn phase2_process(&mut self, p: &Program, placements: &[Placement]) -> Vec<u8> {
         let mut assembled = Vec::new();
-        for i in &p.instructions {
-            let mut instruction = i.as_bytes(&self.symbols);
-            assembled.append(&mut instruction);
+        for (i, at) in p.instructions.iter().zip(placements) {
+            let mut bytes = layout::emit(i, *at, &self.symbols);
+            match at.section {
+                Section::Code => assembled.append(&mut bytes),
+                Section::Data => self.ro.append(&mut bytes),
+            }
         }
         assembled
     }
 }
 
+/// Where each label in `p` lands, given the placement of its statements.
+fn layout_labels(p: &Program, placements: &[Placement]) -> Vec<Symbol> {
+    p.instructions
+        .iter()
+        .zip(placements)
+        .filter_map(|(i, at)| {
+            let symbol_type = if layout::is_data(i) {
+                SymbolType::Data
+            } else {
+                SymbolType::Label
+            };
+            let s = Symbol::new(i.label_name()?, symbol_type, at.offset);
+            Some(s.in_section(at.section))
+        })
+        .collect()
+}
+
+/// The line and column of `needle` within the statement at `span`, or of
+/// the statement itself if `needle` is not in it.
+fn position(raw: &str, span: &Span, needle: &str) -> (u32, u32) {
+    let at = span.text.find(needle).unwrap_or(0);
+    line_column(raw, span.start + at)
+}
+
+/// Reports the first line at or after `from` that is not whitespace or comments.
+fn parse_error(raw: &str, from: usize) -> AssemblerError {
+    let rest = filler(nom::types::CompleteStr(&raw[from..])).unwrap().0;
+    let start = raw.len() - rest.len();
+    let (line, column) = line_column(raw, start);
+    AssemblerError::ParseError {
+        line,
+        column,
+        text: rest.lines().next().unwrap_or("").trim_end().to_string(),
+    }
+}
+
+/// Finds what parsed but cannot be assembled, plus anything left unparsed.
+fn check(
+    raw: &str,
+    p: &Program,
+    sources: &[Span],
+    known: &SymbolTable,
+    unknown_registers: &[(usize, &str)],
+) -> Vec<AssemblerError> {
+    let mut errors = vec![];
+    let mut scope = Scope::new();
+    let declared: HashSet<Cow<str>> = p
+        .instructions
+        .iter()
+        .filter_map(|i| Some(scope.declare(i.label_name()?)))
+        .collect();
+    let mut scope = Scope::new();
+    let undefined = |scope: &Scope, name| {
+        let name = scope.resolve(name);
+        !declared.contains(&name) && known.symbol_value(&name).is_none()
+    };
+    // Spans of the `.rept`s still waiting for their `.endr`.
+    let mut repeats = vec![];
+    for (index, (i, span)) in p.instructions.iter().zip(sources).enumerate() {
+        if let Some(name) = i.label_name() {
+            scope.declare(name);
+        }
+        if i.opcode() == Some(Opcode::IGL) {
+            let after_label = span.text.find(':').map_or(0, |at| at + 1);
+            let text = &span.text[after_label..];
+            let start = span.start + after_label + text.len() - text.trim_start().len();
+            let mnemonic = raw[start..].split_whitespace().next().unwrap_or("");
+            let (line, column) = line_column(raw, start);
+            errors.push(AssemblerError::UnknownMnemonic {
+                line,
+                column,
+                mnemonic: mnemonic.to_string(),
+            });
+        }
+        for (_, name) in unknown_registers.iter().filter(|(at, _)| *at == index) {
+            let at = span.text.find(&format!("${}", name)).unwrap_or(0);
+            let (line, column) = line_column(raw, span.start + at);
+            errors.push(AssemblerError::UnknownRegister {
+                line,
+                column,
+                name: name.to_string(),
+            });
+        }
+        let layout = i.encoded_opcode().map_or(&[][..], Opcode::layout);
+        let operands = [i.operand1, i.operand2, i.operand3];
+        for (slot, operand) in operands.iter().flatten().enumerate() {
+            // Absolute jump targets are unsigned 16-bit offsets, like immediates.
+            if let (Some(Slot::Immediate) | Some(Slot::Target), Token::IntOperand { value }) =
+                (layout.get(slot), operand)
+            {
+                let range = if i.opcode().is_some_and(Opcode::signed_immediate) {
+                    i32::from(i16::MIN)..=i32::from(i16::MAX)
+                } else {
+                    0..=i32::from(u16::MAX)
+                };
+                if !range.contains(value) {
+                    let at = span.text.find(&format!("#{}", value)).unwrap_or(0);
+                    let (line, column) = line_column(raw, span.start + at);
+                    errors.push(AssemblerError::ImmediateOutOfRange {
+                        line,
+                        column,
+                        value: *value,
+                    });
+                }
+            }
+            if let Token::IrString { text } = operand {
+                if let Err(e) = strings::decode(text) {
+                    let at = span.text.find(text).unwrap_or(0) + e.at;
+                    let (line, column) = line_column(raw, span.start + at);
+                    errors.push(AssemblerError::InvalidEscape {
+                        line,
+                        column,
+                        escape: e.escape,
+                    });
+                }
+            }
+            if let Token::LabelUsage { name } = operand {
+                if undefined(&scope, name) {
+                    let at = span.text.find(&format!("@{}", name)).unwrap_or(0);
+                    let (line, column) = line_column(raw, span.start + at);
+                    errors.push(AssemblerError::UndefinedLabel {
+                        line,
+                        column,
+                        name: name.to_string(),
+                    });
+                }
+            }
+        }
+        for name in i.values().iter().flat_map(Expr::labels) {
+            if undefined(&scope, name) {
+                let (line, column) = position(raw, span, &format!("@{}", name));
+                errors.push(AssemblerError::UndefinedLabel {
+                    line,
+                    column,
+                    name: name.to_string(),
+                });
+            }
+        }
+        let directive = i.directive_name().unwrap_or_default();
+        match directive {
+            "space" | "align" | "rept" => {
+                let least = (directive == "align") as u32;
+                if i.count().filter(|n| *n >= least).is_none() {
+                    let (line, column) = position(raw, span, &format!(".{}", directive));
+                    errors.push(AssemblerError::InvalidCount {
+                        line,
+                        column,
+                        directive: directive.to_string(),
+                    });
+                }
+                if directive == "rept" {
+                    repeats.push(span);
+                }
+            }
+            // Closes the innermost `.rept`, if there is one.
+            "endr" if repeats.pop().is_none() => {
+                let (line, column) = position(raw, span, ".endr");
+                errors.push(AssemblerError::UnmatchedRepeat {
+                    line,
+                    column,
+                    directive: directive.to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+    for span in repeats {
+        let (line, column) = position(raw, span, ".rept");
+        errors.push(AssemblerError::UnmatchedRepeat {
+            line,
+            column,
+            directive: "rept".to_string(),
+        });
+    }
+    let end = sources.last().map_or(0, |s| s.start + s.text.len());
+    if !filler(nom::types::CompleteStr(&raw[end..]))
+        .unwrap()
+        .0
+        .is_empty()
+    {
+        errors.push(parse_error(raw, end));
+    }
+    // A pseudo-instruction repeats its operands across its expansion.
+    errors.dedup();
+    errors
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 // #[derive(Debug, PartialEq, Clone)]
 pub enum Token<'a> {
-    Op { code: Opcode },
-    Register { reg_num: u8 },
-    IntOperand { value: i32 },
-    LabelDecl { name: &'a str },
-    LabelUsage { name: &'a str },
-    Directive { name: &'a str },
-    IrString { name: &'a str },
+    Op {
+        code: Opcode,
+    },
+    /// A pseudo-instruction, until `pseudo::expand` replaces it.
+    Pseudo {
+        name: pseudo::Pseudo,
+    },
+    Register {
+        reg_num: u8,
+    },
+    /// A register written by name, before `Program::resolve_registers`.
+    RegisterName {
+        name: &'a str,
+    },
+    IntOperand {
+        value: i32,
+    },
+    LabelDecl {
+        name: &'a str,
+    },
+    LabelUsage {
+        name: &'a str,
+    },
+    Directive {
+        name: &'a str,
+    },
+    /// A string literal as written, quotes included; see `strings`.
+    IrString {
+        text: &'a str,
+    },
+    /// The comma-separated expressions of a data directive, as written;
+    /// `AssemblerInstruction::values` parses them.
+    ExprList {
+        text: &'a str,
+    },
 }
 
 #[cfg(test)]
@@ -142,8 +921,12 @@ mod tests {
     fn test_symbol_table() {
         let mut sym = SymbolTable::new();
         let new_symbol = Symbol::new("test", SymbolType::Label, 12);
-        sym.add_symbol(new_symbol);
-        assert_eq!(sym.symbols.len(), 1);
+        assert!(sym.add_symbol(new_symbol));
+        assert!(!sym.add_symbol(Symbol::new("test", SymbolType::Constant, 7)));
+        assert!(sym.add_symbol(Symbol::new("SIZE", SymbolType::Constant, 4)));
+        assert_eq!(sym.symbols.len(), 2);
+        assert_eq!(sym.symbol("test").unwrap().symbol_type(), SymbolType::Label);
+        assert_eq!(sym.symbol_value("SIZE"), Some(4));
         let v = sym.symbol_value("test");
         assert_eq!(true, v.is_some());
         let v = v.unwrap();
@@ -160,8 +943,416 @@ mod tests {
             "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
         let program = asm.assemble(test_string).unwrap();
         let mut vm = VM::new();
-        assert_eq!(program.len(), 21);
+        assert_eq!(program.len(), 25);
+        vm.add_bytes(program);
+        assert_eq!(vm.program.len(), 25);
+    }
+
+    #[test]
+    fn test_assemble_label_loop() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #0\nload $2 #10\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers[0], 10);
+    }
+
+    #[test]
+    fn test_assemble_branch_loops() {
+        let programs = [
+            // The `neq`/`jmpe` loop above, with the other flag sense.
+            ("load $0 #0\nload $2 #10\ntest: inc $0\neq $0 $2\njmpne @test\nhlt", 0, 10),
+            ("load $0 #0\nload $2 #10\ntest: inc $0\nbne $0 $2 @test\nhlt", 0, 10),
+            ("load $0 #0\nload $2 #10\ntest: inc $0\nblt $0 $2 @test\nhlt", 0, 10),
+            // while ($0 < 5) { $1 += $0; $0++ }
+            (
+                "load $2 #5\ntop: bge $0 $2 @done\nadd $1 $0 $1\ninc $0\njmp @top\ndone: hlt",
+                1,
+                10,
+            ),
+            // if ($0 <= $1) { $2 = 2 } else { $2 = 1 }
+            (
+                "load $0 #3\nload $1 #7\nble $0 $1 @then\nload $2 #1\njmp @end\nthen: load $2 #2\nend: hlt",
+                2,
+                2,
+            ),
+            (
+                "load $0 #5\nload $3 #1\nloop: sub $0 $3 $0\nbgt $0 $1 @loop\nbeq $0 $1 @zero\nhlt\nzero: load $2 #9\nhlt",
+                2,
+                9,
+            ),
+        ];
+        for (source, register, expected) in programs.iter() {
+            let program = Assembler::new().assemble(source).unwrap();
+            let mut vm = VM::new();
+            vm.add_bytes(program);
+            vm.run();
+            assert_eq!(vm.registers[*register], *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_assemble_jump_modes() {
+        let mut asm = Assembler::new();
+        let bytes = asm.assemble("jmp #300\njmp $3\nback: jmp @back").unwrap();
+        assert_eq!(bytes, vec![6, 0, 1, 44, 6, 2, 3, 6, 1, 0, 0]);
+        let bytes = asm.assemble("top: blt $1 $2 @top").unwrap();
+        assert_eq!(bytes, vec![Opcode::BLT as u8, 1, 2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_named_registers() {
+        let source = "
+            .alias n $a0
+                    load $t0 #256
+                    aloc $t0
+                    load $sp #256
+                    load $n #5
+                    call @fact
+                    hlt
+            fact:   push $ra
+                    push $s0
+                    load $t0 #1
+                    bgt $n $t0 @recurse
+                    load $a0 #1
+                    jmp @done
+            recurse: addi $n $s0 #0
+                    subi $n $n #1
+                    call @fact
+                    mul $a0 $s0 $a0
+            done:   pop $s0
+                    pop $ra
+                    ret";
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.registers[0], 120);
+        assert_eq!(vm.registers[30], 256);
+    }
+
+    #[test]
+    fn test_assemble_unknown_register() {
+        let errors = Assembler::new()
+            .try_assemble(
+                "inc $count\n.alias count $t1\ninc $count\nadd $s0 $x $t10\nneg $y\n\
+                 load $40 #1\ninc $300\n",
+            )
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "1:5: unknown register `$count`",
+                "4:9: unknown register `$x`",
+                "4:12: unknown register `$t10`",
+                "5:5: unknown register `$y`",
+                "6:6: unknown register `$40`",
+                "7:5: unknown register `$300`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_jump_target_out_of_range() {
+        let errors = Assembler::new()
+            .try_assemble("jmp #65535\njmp #70000\nhlt")
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec!["2:5: immediate `70000` does not fit in 16 bits; load it into a register"]
+        );
+    }
+
+    #[test]
+    fn test_assemble_wide_constants() {
+        let source = "load $0 #70000\nload $1 #-1\nsubi $0 $2 #-30\nmuli $1 $3 #300\njmp @end\nload $4 #1\nend: hlt";
+        let program = Assembler::new().assemble(source).unwrap();
+        assert_eq!(program.len(), 31);
+        assert_eq!(&program[..6], &[Opcode::LOAD32 as u8, 0, 0, 1, 0x11, 0x70]);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(&vm.registers[..5], &[70000, -1, 70030, -300, 0]);
+    }
+
+    #[test]
+    fn test_assemble_local_labels() {
+        let source = "main: load $0 #0\n.loop: inc $0\ncmpi $0 #3\njne @.loop\n\
+                      .rept 2\njmp @1f\ninc $3\n1: inc $2\n.endr\n\
+                      other: load $4 #0\n.loop: inc $4\ncmpi $4 #2\njne @.loop\n\
+                      jmp @1f\n1: hlt";
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        let mut vm = VM::new();
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 21);
+        vm.run();
+        assert_eq!(&vm.registers[..5], &[3, 0, 2, 0, 2]);
+        let offsets: Vec<Option<u32>> = ["main.loop", "other.loop", "1.1", "1.2", "1.3"]
+            .iter()
+            .map(|name| asm.symbols.symbol_value(name))
+            .collect();
+        assert_eq!(
+            offsets,
+            vec![Some(4), Some(44), Some(24), Some(36), Some(60)]
+        );
+    }
+
+    #[test]
+    fn test_assemble_label_errors() {
+        let messages = |source: &str| -> Vec<String> {
+            let errors = Assembler::new().try_assemble(source).unwrap_err();
+            errors.iter().map(|e| e.to_string()).collect()
+        };
+        assert_eq!(
+            messages("a: hlt\n.x: jmp @.y\nb: jmp @.x\njmp @1b\njmp @2f\n1: hlt"),
+            vec![
+                "2:9: undefined label `.y`",
+                "3:8: undefined label `.x`",
+                "4:5: undefined label `1b`",
+                "5:5: undefined label `2f`",
+            ]
+        );
+        assert_eq!(
+            messages("a: hlt\n.x: hlt\nb: hlt\n.x: hlt\n  a: hlt\n.rept 2\nc: hlt\n.endr"),
+            vec![
+                "5:3: label `a` is already declared",
+                "7:1: label `c` is already declared",
+            ]
+        );
+
+        // Labels already in the table, as in the REPL, count too.
+        let mut asm = Assembler::new();
+        asm.assemble("start: hlt").unwrap();
+        let errors = asm.try_assemble("start: hlt").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "1:1: label `start` is already declared"
+        );
+    }
+
+    #[test]
+    fn test_assemble_pseudo_instructions() {
+        let source = "li $t0 #100000\nmov $t0 $t1\nneg $t1\nli $t2 #0\nli $t3 #3\n\
+                      loop: inc $t2\nnop\ncmpi $t2 #3\njne @loop\nnot $t3\nclr $t0\nhlt";
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.len(), 49);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(25));
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(&vm.registers[4..8], &[0, -100000, 3, -4]);
+    }
+
+    #[test]
+    fn test_assemble_strings() {
+        let source = r#".data
+plain: .asciiz 'a\n'
+escaped: .asciiz "tab\t\"q\"\x00\u{e9}"
+counted: .string "\xff\u{1F600}"
+empty: .string ""
+.code
+hlt"#;
+        let mut asm = Assembler::new();
+        asm.assemble(source).unwrap();
+        let mut ro = b"a\\n\0".to_vec();
+        ro.extend(b"tab\t\"q\"\0\xc3\xa9\0");
+        ro.extend(&[0, 0, 0, 5, 0xff, 0xf0, 0x9f, 0x98, 0x80]);
+        ro.extend(&[0, 0, 0, 0]);
+        assert_eq!(asm.ro, ro);
+        let offsets: Vec<Option<u32>> = ["escaped", "counted", "empty"]
+            .iter()
+            .map(|name| asm.symbols.symbol_value(name))
+            .collect();
+        assert_eq!(offsets, vec![Some(4), Some(15), Some(24)]);
+
+        let errors = Assembler::new()
+            .try_assemble(".asciiz \"ok\"\n.string \"bad \\q\" ; \"\\z\"")
+            .unwrap_err();
+        assert_eq!(errors[0].to_string(), "2:14: invalid escape `\\q`");
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_assemble_data_directives() {
+        let source = ".data\nsquares: .byte 0, 1, 4, 9, 16\n.align 4\n\
+                      table: .word @first, @second, @end - @first\nbuffer: .space 8\n\
+                      .rept 2\n.half 0xbeef\n.endr\n\
+                      .code\nfirst: load $0 #1\nsecond: jmp @end\ninline: .byte 7\nend: hlt";
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.len(), 4 + 4 + 1 + 1);
+        assert_eq!(program[8], 7);
+        let offsets: Vec<Option<u32>> = ["squares", "table", "buffer", "inline", "end"]
+            .iter()
+            .map(|name| asm.symbols.symbol_value(name))
+            .collect();
+        assert_eq!(offsets, vec![Some(0), Some(8), Some(20), Some(8), Some(9)]);
+        let mut ro = vec![0, 1, 4, 9, 16, 0, 0, 0];
+        ro.extend(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 9]);
+        ro.extend(&[0; 8]);
+        ro.extend(&[0xbe, 0xef, 0xbe, 0xef]);
+        assert_eq!(asm.ro, ro);
+    }
+
+    #[test]
+    fn test_assemble_directive_errors() {
+        let messages = |source: &str| -> Vec<String> {
+            let errors = Assembler::new().try_assemble(source).unwrap_err();
+            errors.iter().map(|e| e.to_string()).collect()
+        };
+        assert_eq!(
+            messages(".space @x\n.rept 2\n.endr\n.endr\n.align 0\n.rept 1\n.word @missing"),
+            vec![
+                "1:8: undefined label `x`",
+                "1:1: `.space` needs a constant count from 0 to 65535",
+                "4:1: `.endr` without `.rept`",
+                "5:1: `.align` needs a constant count from 1 to 65535",
+                "7:7: undefined label `missing`",
+                "6:1: `.rept` without `.endr`",
+            ]
+        );
+        assert_eq!(
+            messages(".byte 256, -129, 255\n.word 1/0\n.half -32768"),
+            vec![
+                "1:7: value `256` does not fit in `.byte`",
+                "1:12: value `-129` does not fit in `.byte`",
+                "2:7: cannot evaluate `1 / 0`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_conditionals() {
+        let source = ".ifdef DEBUG\nload $0 #1\n.elif LEVEL >= 2\nload $0 #2\n\
+                      .else\nthis is { not assembly\n.endif\nhlt\n.if LEVEL > 9\n\
+                      .error \"LEVEL is at most 9\"\n.endif\nbad\n";
+        let assemble = |asm: Assembler| asm.with_define("LEVEL", 2).try_assemble(source);
+        let debug = assemble(Assembler::new().with_define("DEBUG", 0)).unwrap_err();
+        // The lines that are left still report their own positions.
+        assert_eq!(debug[0].to_string(), "12:1: unknown mnemonic `bad`");
+
+        let source = source.replace("bad\n", "");
+        let assemble = |mut asm: Assembler| asm.try_assemble(&source);
+        let debug = Assembler::new()
+            .with_define("DEBUG", 1)
+            .with_define("LEVEL", 0);
+        let debug = assemble(debug).unwrap();
+        let production = assemble(Assembler::new().with_define("LEVEL", 2)).unwrap();
+        assert_eq!(&debug[..4], &[Opcode::LOAD as u8, 0, 0, 1]);
+        assert_eq!(&production[..4], &[Opcode::LOAD as u8, 0, 0, 2]);
+        assert_eq!(
+            assemble(Assembler::new().with_define("LEVEL", 10)).unwrap_err(),
+            vec![AssemblerError::UserError {
+                line: 10,
+                column: 1,
+                message: "LEVEL is at most 9".to_string(),
+            }]
+        );
+        assert_eq!(
+            assemble(Assembler::new()).unwrap_err()[0].to_string(),
+            "3:7: `LEVEL` is not defined"
+        );
+    }
+
+    #[test]
+    fn test_assemble_constants() {
+        let mut asm = Assembler::new();
+        asm.try_assemble(".equ SIZE 4\n.equ DOWN -1\nhlt").unwrap();
+        let size = asm.symbols.symbol("SIZE").unwrap();
+        assert_eq!(size.symbol_type(), SymbolType::Constant);
+        assert_eq!(size.offset(), 4);
+        assert_eq!(asm.symbols.symbol_value("DOWN"), Some(u32::MAX));
+
+        // Later sources see the constant, and may not define it again.
+        let bytes = asm
+            .try_assemble(".if SIZE == 4\nload $0 #1\n.endif")
+            .unwrap();
+        assert_eq!(bytes, vec![Opcode::LOAD as u8, 0, 0, 1]);
+        let errors = asm.try_assemble(".equ SIZE 8").unwrap_err();
+        assert_eq!(errors[0].to_string(), "1:6: `SIZE` is already defined");
+        let errors = asm.try_assemble(".equ start 1\nstart: hlt").unwrap_err();
+        assert_eq!(errors[0].to_string(), "1:6: `start` is already defined");
+        assert!(asm.symbols.symbol("start").is_none());
+    }
+
+    #[test]
+    fn test_assemble_immediate_out_of_range() {
+        let errors = Assembler::new()
+            .try_assemble("addi $0 $1 #40000\ncmpi $0 #-40000\nload $0 #-5\nhlt")
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "1:12: immediate `40000` does not fit in 16 bits; load it into a register",
+                "2:9: immediate `-40000` does not fit in 16 bits; load it into a register",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_jump_out_of_range() {
+        let far = "load $0 #0\n".repeat(8200);
+        let source = format!("jmp @end\n{}end: hlt", far);
+        let errors = Assembler::new().try_assemble(&source).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "1:5: jump to `end` is out of range (32804 bytes away)"
+        );
+        let source = format!("start: {}jmp @start", far);
+        assert!(Assembler::new().try_assemble(&source).is_err());
+    }
+
+    #[test]
+    fn test_assemble_debug_info() {
+        let mut asm = Assembler::new().with_debug_info("loop.iasm");
+        asm.assemble("load $0 #100\ntest: inc $0\njmpe @test\nhlt")
+            .unwrap();
+        let debug = asm.debug_info().unwrap();
+        assert_eq!(debug.labels, vec![("test".to_string(), 4)]);
+        assert_eq!(
+            debug.location(9).unwrap().to_string(),
+            "loop.iasm:3:1 (in label `test`)"
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let mut asm = Assembler::new();
+        let errors = asm
+            .try_assemble("load $0 #1\n  frob $0\njmpe @nowhere\nhlt\n$$ ; junk\n")
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "2:3: unknown mnemonic `frob`",
+                "3:6: undefined label `nowhere`",
+                "5:1: unable to parse `$$ ; junk`",
+            ]
+        );
+        assert!(Assembler::new().try_assemble("; nothing\n").is_err());
+        assert_eq!(Assembler::new().assemble("hlt\n$$"), None);
+    }
+
+    #[test]
+    fn test_assemble_at_base_offset() {
+        let mut asm = Assembler::new().with_base_offset(8).with_debug_info("repl");
+        asm.assemble("hlt\nagain: hlt").unwrap();
+        assert_eq!(asm.symbols.symbol_value("again"), Some(9));
+        assert_eq!(asm.debug_info().unwrap().lines[0].offset, 8);
+
+        let mut more = Assembler::new().with_base_offset(10);
+        more.symbols = asm.symbols;
+        let bytes = more.assemble("jmpe @again").unwrap();
+        // Relative to the jump at 10: 9 - 10 = -1.
+        assert_eq!(bytes, vec![Opcode::JMPE as u8, 1, 0xff, 0xff]);
     }
 }
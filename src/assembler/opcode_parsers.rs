@@ -1,13 +1,15 @@
+use super::pseudo::Pseudo;
 use super::Token;
 use crate::instruction::Opcode;
 use nom::types::CompleteStr;
-use nom::{alpha1, do_parse, tag_no_case};
+use nom::{alphanumeric1, do_parse, tag_no_case};
 
 nom::named!(pub opcode<CompleteStr, Token>,
     do_parse!(
-        opcode: alpha1 >>
-        ({Token::Op {
-            code: Opcode::from(opcode)}
+        opcode: alphanumeric1 >>
+        (match (Opcode::from(opcode), Pseudo::from_word(&opcode)) {
+            (Opcode::IGL, Some(name)) => Token::Pseudo { name },
+            (code, _) => Token::Op { code },
         })
     )
 );
@@ -53,5 +55,7 @@ mod tests {
         let result = opcode(CompleteStr("aold"));
         let (_, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::IGL });
+        let (_, token) = opcode(CompleteStr("Mov")).unwrap();
+        assert_eq!(token, Token::Pseudo { name: Pseudo::Mov });
     }
 }
@@ -1,17 +1,17 @@
 use super::label_parsers::label_usage;
 use super::register_parsers::register;
+use super::strings::double_quoted;
 use super::Token;
 use nom::types::CompleteStr;
-use nom::{alt, digit, do_parse, tag, take_until, ws};
+use nom::{alt, delimited, digit, map, map_res, opt, pair, recognize, tag, take_until, ws};
 
 nom::named!(pub irstring<CompleteStr, Token>,
-    do_parse!(
-        tag!("'") >>
-        content: take_until!("'") >>
-        tag!("'") >>
-        (Token::IrString {
-            name: &content
-        })
+    map!(
+        alt!(
+            recognize!(delimited!(tag!("'"), take_until!("'"), tag!("'"))) |
+            double_quoted
+        ),
+        |text| Token::IrString { text: text.0 }
     )
 );
 
@@ -28,10 +28,11 @@ nom::named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
-            reg_num: digit >>
-            (Token::IntOperand {
-                value: reg_num.parse::<i32>().unwrap()
-            })
+            value: map_res!(
+                recognize!(pair!(opt!(tag!("-")), digit)),
+                |s: CompleteStr| s.parse::<i32>()
+            ) >>
+            (Token::IntOperand { value })
         )
     )
 );
@@ -40,6 +41,16 @@ nom::named!(pub integer_operand<CompleteStr, Token>,
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_irstring() {
+        for text in &["'say \"hi\"'", r#""it's \"quoted\"""#, r#""""#] {
+            let (rest, token) = irstring(CompleteStr(text)).unwrap();
+            assert_eq!(rest, CompleteStr(""));
+            assert_eq!(token, Token::IrString { text });
+        }
+        assert!(irstring(CompleteStr("'open")).is_err());
+    }
+
     #[test]
     fn test_parse_integer_operand() {
         let result = integer_operand(CompleteStr("#10"));
@@ -51,5 +62,9 @@ mod tests {
 
         let result = integer_operand(CompleteStr("10"));
         assert_eq!(result.is_ok(), false);
+
+        let result = integer_operand(CompleteStr("#-70000"));
+        assert_eq!(result.unwrap().1, Token::IntOperand { value: -70000 });
+        assert!(integer_operand(CompleteStr("#4294967296")).is_err());
     }
 }
@@ -0,0 +1,462 @@
+use super::instruction_parsers::AssemblerInstruction;
+use super::program_parsers::{Program, Span};
+use super::Token;
+use crate::instruction::{Opcode, OperandRole};
+use crate::vm;
+use std::collections::HashMap;
+
+/// A statement together with the source it came from, so that listings and
+/// debug info stay aligned with the optimized program.
+type Statement<'a> = (AssemblerInstruction<'a>, Span<'a>);
+
+/// What an instruction does to the registers, as far as the optimizer cares.
+struct Effect {
+    reads: Vec<u8>,
+    writes: Option<u8>,
+    /// The write is the only effect, so the instruction can go if it is dead.
+    pure: bool,
+    /// Control may leave the straight line after this instruction.
+    ends_block: bool,
+}
+
+fn register(t: Option<Token>) -> Option<u8> {
+    match t {
+        Some(Token::Register { reg_num }) => Some(reg_num),
+        _ => None,
+    }
+}
+
+fn label_usage<'a>(t: Option<Token<'a>>) -> Option<&'a str> {
+    match t {
+        Some(Token::LabelUsage { name }) => Some(name),
+        _ => None,
+    }
+}
+
+fn effect(i: &AssemblerInstruction) -> Effect {
+    // `ret` jumps through `$ra`, and `push` and `pop` also move `$sp`.
+    let opaque = |opcode| {
+        matches!(
+            opcode,
+            Opcode::HLT | Opcode::IGL | Opcode::RET | Opcode::PUSH | Opcode::POP
+        )
+    };
+    let opcode = match i.opcode() {
+        Some(opcode) if !opaque(opcode) && !is_jump(i) => opcode,
+        _ => {
+            return Effect {
+                reads: (0..32).collect(),
+                writes: None,
+                pure: false,
+                ends_block: true,
+            }
+        }
+    };
+
+    let mut e = Effect {
+        reads: vec![],
+        writes: None,
+        // Bitwise results depend on the operands alone; arithmetic also sets
+        // the overflow flag, and may trap.
+        pure: matches!(opcode, Opcode::LOAD | Opcode::LOAD32)
+            || vm::bitwise(opcode, 0, 0).is_some(),
+        ends_block: false,
+    };
+    let operands = [i.operand1, i.operand2, i.operand3];
+    for (role, operand) in opcode.operand_roles().iter().zip(operands.iter()) {
+        if let Some(r) = register(*operand) {
+            match role {
+                OperandRole::Read => e.reads.push(r),
+                OperandRole::Write => e.writes = Some(r),
+                OperandRole::ReadWrite => {
+                    e.reads.push(r);
+                    e.writes = Some(r);
+                }
+                OperandRole::Unused => {}
+            }
+        }
+    }
+    e
+}
+
+fn is_jump(i: &AssemblerInstruction) -> bool {
+    i.opcode().is_some_and(|opcode| {
+        matches!(opcode, Opcode::JMPF | Opcode::JMPB) || opcode.target_slot().is_some()
+    })
+}
+
+/// Arithmetic, which sets or clears the overflow flag.
+fn writes_flag(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::INC
+            | Opcode::ADDI
+            | Opcode::SUBI
+            | Opcode::MULI
+    )
+}
+
+/// For each statement, whether the overflow flag it leaves behind is
+/// overwritten before `jmpo` or anything past a jump could read it.
+fn flag_dead_after(statements: &[Statement]) -> Vec<bool> {
+    // Running off the end halts.
+    let mut dead = true;
+    let mut after = vec![false; statements.len()];
+    for (index, (i, _)) in statements.iter().enumerate().rev() {
+        after[index] = dead;
+        dead = match i.opcode() {
+            Some(Opcode::HLT) => true,
+            Some(opcode) if writes_flag(opcode) => true,
+            _ => dead && !effect(i).ends_block && !i.is_directive(),
+        };
+    }
+    after
+}
+
+fn is_unconditional(i: &AssemblerInstruction) -> bool {
+    match i.opcode() {
+        Some(Opcode::HLT) | Some(Opcode::RET) => true,
+        Some(Opcode::JMP) => label_usage(i.operand1).is_some(),
+        _ => false,
+    }
+}
+
+fn load<'a>(label: Option<Token<'a>>, reg_num: u8, value: i32) -> AssemblerInstruction<'a> {
+    AssemblerInstruction {
+        label,
+        directive: None,
+        opcode: Some(Token::Op { code: Opcode::LOAD }),
+        operand1: Some(Token::Register { reg_num }),
+        operand2: Some(Token::IntOperand { value }),
+        operand3: None,
+    }
+}
+
+/// Runs the peephole passes over `p` until none of them changes anything.
+///
+/// Jumps through registers reach raw offsets that the passes would shift, so
+/// programs containing them are returned unchanged.
+pub fn optimize<'a>(p: Program<'a>, sources: Vec<Span<'a>>) -> (Program<'a>, Vec<Span<'a>>) {
+    let computed_jump = p
+        .instructions
+        .iter()
+        .any(|i| is_jump(i) && label_usage(i.target()).is_none());
+    if computed_jump {
+        return (p, sources);
+    }
+
+    let mut statements: Vec<Statement> = p.instructions.into_iter().zip(sources).collect();
+    loop {
+        let before = statements.clone();
+        statements = remove_unreachable(statements);
+        statements = thread_jumps(statements);
+        statements = fold_constants(statements);
+        statements = eliminate_dead_stores(statements);
+        if statements == before {
+            break;
+        }
+    }
+
+    let (instructions, sources) = statements.into_iter().unzip();
+    (Program { instructions }, sources)
+}
+
+/// Drops code after `hlt` or an unconditional jump up to the next label.
+fn remove_unreachable(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut reachable = true;
+    let mut kept = vec![];
+    for (i, span) in statements {
+        if i.label_name().is_some() || i.is_directive() {
+            reachable = true;
+        }
+        if reachable {
+            reachable = !is_unconditional(&i);
+            kept.push((i, span));
+        }
+    }
+    kept
+}
+
+/// Retargets jumps whose target is itself an unconditional jump, and drops
+/// jumps to the instruction that follows them anyway.
+fn thread_jumps(statements: Vec<Statement>) -> Vec<Statement> {
+    let forwards: HashMap<&str, &str> = statements
+        .iter()
+        .filter(|(i, _)| i.opcode() == Some(Opcode::JMP))
+        .filter_map(|(i, _)| Some((i.label_name()?, label_usage(i.operand1)?)))
+        .collect();
+    let mut threaded: Vec<Statement> = vec![];
+    for (index, (mut i, span)) in statements.iter().cloned().enumerate() {
+        if is_jump(&i) {
+            if let Some(target) = label_usage(i.target()) {
+                let target = resolve(&forwards, target);
+                i.set_target(Token::LabelUsage { name: target });
+                let next_label = statements.get(index + 1).and_then(|(n, _)| n.label_name());
+                let call = i.opcode() == Some(Opcode::CALL);
+                if next_label == Some(target) && i.label_name().is_none() && !call {
+                    continue;
+                }
+            }
+        }
+        threaded.push((i, span));
+    }
+    threaded
+}
+
+/// Follows a chain of label-to-label jumps to its final target.
+fn resolve<'a>(forwards: &HashMap<&'a str, &'a str>, mut target: &'a str) -> &'a str {
+    // Bounded so that jump cycles cannot hang the assembler.
+    for _ in 0..forwards.len() {
+        match forwards.get(target) {
+            Some(next) if *next != target => target = next,
+            _ => break,
+        }
+    }
+    target
+}
+
+/// Replaces arithmetic on registers with known contents by a `load`.
+fn fold_constants(statements: Vec<Statement>) -> Vec<Statement> {
+    let flag_dead = flag_dead_after(&statements);
+    let mut known: HashMap<u8, i32> = HashMap::new();
+    let mut folded = vec![];
+    for (index, (mut i, span)) in statements.into_iter().enumerate() {
+        if i.label_name().is_some() {
+            known.clear();
+        }
+        let (r1, r2) = (register(i.operand1), register(i.operand2));
+        let operands = |a: Option<u8>, b: Option<u8>| Some((*known.get(&a?)?, *known.get(&b?)?));
+        let value = match i.opcode() {
+            Some(Opcode::LOAD) | Some(Opcode::LOAD32) => match i.operand2 {
+                Some(Token::IntOperand { value }) => Some(value),
+                _ => None,
+            },
+            Some(opcode @ Opcode::ADDI)
+            | Some(opcode @ Opcode::SUBI)
+            | Some(opcode @ Opcode::MULI) => match (r1.and_then(|r| known.get(&r)), i.operand3) {
+                (Some(a), Some(Token::IntOperand { value: b })) => match opcode {
+                    Opcode::ADDI => a.checked_add(b),
+                    Opcode::SUBI => a.checked_sub(b),
+                    _ => a.checked_mul(b),
+                },
+                _ => None,
+            },
+            Some(Opcode::ADD) => operands(r1, r2).and_then(|(a, b)| a.checked_add(b)),
+            Some(Opcode::SUB) => operands(r1, r2).and_then(|(a, b)| a.checked_sub(b)),
+            Some(Opcode::MUL) => operands(r1, r2).and_then(|(a, b)| a.checked_mul(b)),
+            Some(Opcode::INC) => r1.and_then(|r| known.get(&r)?.checked_add(1)),
+            Some(Opcode::NOT) => r1.and_then(|r| vm::bitwise(Opcode::NOT, *known.get(&r)?, 0)),
+            Some(opcode) => operands(r1, r2).and_then(|(a, b)| vm::bitwise(opcode, a, b)),
+            None => None,
+        };
+        // Results too wide for the short `load` are not tracked.
+        let short = |v: i32| (0..=i32::from(u16::MAX)).contains(&v);
+        let loads = matches!(i.opcode(), Some(Opcode::LOAD) | Some(Opcode::LOAD32));
+        let value = value.filter(|v| loads || short(*v));
+
+        let e = effect(&i);
+        match (e.writes, value) {
+            (Some(dest), Some(value)) => {
+                // Arithmetic that is folded did not overflow, so it cleared
+                // the flag, which a `load` leaves as it was.
+                let flag_kept = !i.opcode().is_some_and(writes_flag) || flag_dead[index];
+                let replacement = load(i.label, dest, value);
+                // `not` is shorter than any `load`.
+                let shorter = replacement.byte_len() <= i.byte_len();
+                if i.opcode() != Some(Opcode::LOAD) && short(value) && flag_kept && shorter {
+                    i = replacement;
+                }
+                known.insert(dest, value);
+            }
+            (Some(dest), None) => {
+                known.remove(&dest);
+            }
+            _ => {}
+        }
+        if e.ends_block {
+            known.clear();
+        }
+        folded.push((i, span));
+    }
+    folded
+}
+
+/// Removes pure register writes that are overwritten before anything reads them.
+fn eliminate_dead_stores(statements: Vec<Statement>) -> Vec<Statement> {
+    // Registers that will be overwritten before being read, scanning backwards.
+    let mut overwritten: Vec<u8> = vec![];
+    let mut kept = vec![];
+    for (i, span) in statements.into_iter().rev() {
+        let e = effect(&i);
+        if e.ends_block || i.is_directive() {
+            overwritten.clear();
+        }
+        let dead = e.pure && e.writes.is_some_and(|r| overwritten.contains(&r));
+        if dead && i.label_name().is_none() {
+            continue;
+        }
+        if let Some(r) = e.writes {
+            overwritten.push(r);
+        }
+        overwritten.retain(|r| !e.reads.contains(r));
+        if i.label_name().is_some() {
+            overwritten.clear();
+        }
+        kept.push((i, span));
+    }
+    kept.reverse();
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::program_with_sources;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn optimized(source: &str) -> Vec<String> {
+        let (p, sources) = program_with_sources(source).unwrap();
+        let (_, sources) = optimize(p, sources);
+        sources.iter().map(|s| s.text.to_string()).collect()
+    }
+
+    fn run(bytecode: Vec<u8>) -> [i32; 32] {
+        let mut vm = VM::new();
+        vm.add_bytes(bytecode);
+        vm.run();
+        vm.registers
+    }
+
+    /// Runs `source` with and without optimization and compares the results.
+    fn assert_same_behaviour(source: &str) {
+        let plain = Assembler::new().assemble(source).unwrap();
+        let optimized = Assembler::new()
+            .with_optimizations()
+            .assemble(source)
+            .unwrap();
+        assert!(optimized.len() <= plain.len(), "{}", source);
+        assert_eq!(run(plain), run(optimized), "{}", source);
+    }
+
+    #[test]
+    fn test_differential() {
+        let programs = [
+            "load $0 #0\nload $0 #5\nhlt",
+            "load $0 #2\nload $1 #3\nadd $0 $1 $2\nhlt",
+            "load $0 #2\nload $1 #3\nmul $0 $1 $2\nsub $2 $0 $3\nhlt",
+            "load $0 #7\nload $1 #2\ndiv $0 $1 $2\nload $2 #1\nhlt",
+            "load $0 #1\nadd $0 $0 $1\nadd $1 $1 $1\nadd $1 $1 $1\nhlt",
+            "load $0 #1\nhlt\nload $0 #2\nhlt",
+            "load $0 #65535\nadd $0 $0 $1\nhlt",
+            "load $5 #9\nload $5 #9\nadd $5 $5 $6\nload $5 #1\nhlt",
+            "load $0 #240\nload $1 #4\nshl $0 $1 $2\nror $2 $1 $3\nxor $2 $3 $4\nhlt",
+            "load $0 #12\nnot $0 $1\nload $2 #40\nsar $1 $2 $3\nand $0 $3 $4\nhlt",
+            "load $2 #4\ntop: inc $0\nblt $0 $2 @mid\njmp @out\nmid: jmp @top\nout: hlt",
+            "load $0 #70000\naddi $0 $1 #-5\nsubi $1 $2 #100\nmuli $2 $3 #-2\nhlt",
+            "load $0 #3\naddi $0 $1 #4\nload32 $2 #9\nmuli $1 $1 #-1\nhlt",
+            "load32 $0 #2147483647\ninc $0\nload $1 #1\nload $2 #2\nadd $1 $2 $3\n\
+             jmpo @over\nload $4 #1\nhlt\nover: load $4 #2\nhlt",
+        ];
+        for source in programs.iter() {
+            assert_same_behaviour(source);
+        }
+    }
+
+    #[test]
+    fn test_thread_branch() {
+        let (p, sources) =
+            program_with_sources("top: inc $0\nbne $0 $1 @mid\nhlt\nmid: jmp @top").unwrap();
+        let (p, _) = optimize(p, sources);
+        assert_eq!(
+            p.instructions[1].target(),
+            Some(Token::LabelUsage { name: "top" })
+        );
+    }
+
+    #[test]
+    fn test_dead_store_elimination() {
+        assert_eq!(
+            optimized("load $0 #0\nload $0 #5\nhlt"),
+            vec!["load $0 #5", "hlt"]
+        );
+        // Reads in between keep the store alive.
+        assert_eq!(optimized("load $0 #0\neq $0 $1\nload $0 #5\nhlt").len(), 4);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let (p, sources) =
+            program_with_sources("load $0 #2\nload $1 #3\nadd $0 $1 $2\nhlt").unwrap();
+        let (p, _) = optimize(p, sources);
+        assert_eq!(p.instructions[2], load(None, 2, 5));
+        // Results that do not fit a `load` immediate are left alone.
+        let (p, sources) = program_with_sources("load $0 #65535\nadd $0 $0 $1\nhlt").unwrap();
+        let (p, _) = optimize(p, sources);
+        assert_eq!(p.instructions[1].opcode(), Some(Opcode::ADD));
+        // Nor is arithmetic whose cleared overflow flag `jmpo` could see.
+        let source = "load $0 #2\nadd $0 $0 $1\njmpo @out\nload $2 #1\nout: hlt";
+        let (p, sources) = program_with_sources(source).unwrap();
+        let (p, _) = optimize(p, sources);
+        assert_eq!(p.instructions[1].opcode(), Some(Opcode::ADD));
+        // Nor anything a `load` would be longer than.
+        assert_eq!(
+            optimized("load $0 #-1\nnot $0 $1\nhlt"),
+            vec!["load $0 #-1", "not $0 $1", "hlt"]
+        );
+    }
+
+    #[test]
+    fn test_fold_bitwise() {
+        let (p, sources) =
+            program_with_sources("load $0 #12\nload $1 #10\nxor $0 $1 $2\nhlt").unwrap();
+        let (p, _) = optimize(p, sources);
+        assert_eq!(p.instructions[2], load(None, 2, 6));
+        // `not` of a small value is negative, which `load` cannot carry.
+        let (p, sources) = program_with_sources("load $0 #1\nnot $0 $1\nhlt").unwrap();
+        let (p, _) = optimize(p, sources);
+        assert_eq!(p.instructions[1].opcode(), Some(Opcode::NOT));
+    }
+
+    #[test]
+    fn test_unreachable_after_hlt() {
+        assert_eq!(
+            optimized("hlt\nload $0 #1\nend: hlt\nhlt"),
+            vec!["hlt", "end: hlt"]
+        );
+    }
+
+    #[test]
+    fn test_jump_threading() {
+        let source = "jmp @a\nhlt\na: jmp @b\nb: jmpe @c\nc: hlt";
+        let (p, sources) = program_with_sources(source).unwrap();
+        let (p, _) = optimize(p, sources);
+        assert_eq!(
+            p.instructions[0].operand1,
+            Some(Token::LabelUsage { name: "b" })
+        );
+        // The jump to the very next instruction disappears.
+        assert_eq!(optimized("jmpe @next\nnext: hlt"), vec!["next: hlt"]);
+        // A call still has to set `$ra`.
+        assert_eq!(
+            optimized("call @next\nnext: ret"),
+            vec!["call @next", "next: ret"]
+        );
+    }
+
+    #[test]
+    fn test_labels_re_resolved() {
+        let mut asm = Assembler::new().with_optimizations();
+        asm.assemble("load $0 #1\nload $0 #2\nend: hlt").unwrap();
+        assert_eq!(asm.symbols.symbol_value("end"), Some(4));
+    }
+
+    #[test]
+    fn test_computed_jumps_disable_optimizer() {
+        assert_eq!(optimized("load $0 #0\nload $0 #8\njmp $0\nhlt").len(), 4);
+    }
+}
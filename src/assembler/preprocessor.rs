@@ -0,0 +1,491 @@
+//! Conditional assembly, worked out on the source text before parsing.
+//!
+//! | Directive        | Meaning                                           |
+//! |------------------|---------------------------------------------------|
+//! | `.if EXPR`       | assemble what follows if `EXPR` is not zero       |
+//! | `.ifdef NAME`    | ... if `NAME` is defined                          |
+//! | `.ifndef NAME`   | ... if it is not                                  |
+//! | `.elif EXPR`     | otherwise, if `EXPR` is not zero                  |
+//! | `.else`          | otherwise                                         |
+//! | `.endif`         | ends the innermost `.if`                          |
+//! | `.error "text"`  | stops assembly with `text`, unless excluded       |
+//! | `.equ NAME EXPR` | defines `NAME` as the value of `EXPR`             |
+//!
+//! Conditions are constant expressions, as in `crate::assembler::expression`,
+//! in which a bare name stands for its definition, e.g. `.if LEVEL >= 2`.
+//! Names are defined with `Assembler::with_define`, `-D NAME=value` or
+//! `.equ`, which may not define a name twice; `.ifndef` guards a default.
+//!
+//! Excluded lines, and the directives themselves, are blanked out with
+//! spaces rather than removed, so that they need not parse and every other
+//! line keeps its line and column.
+
+use super::expression::expression;
+use super::program_parsers::split_comment;
+use super::strings;
+use super::AssemblerError;
+use crate::debug_info::line_column;
+use nom::types::CompleteStr;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+const DIRECTIVES: [&str; 8] = [
+    "if", "ifdef", "ifndef", "elif", "else", "endif", "error", "equ",
+];
+
+/// A name defined by `.equ`, and where the name is.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Constant {
+    pub name: String,
+    pub value: i64,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// The preprocessor directive on `line`, if any, with the rest of the line
+/// up to any comment.
+pub fn conditional(line: &str) -> Option<(&str, &str)> {
+    let code = split_comment(line).0.trim();
+    let rest = code.strip_prefix('.')?;
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let (name, rest) = rest.split_at(end);
+    let separated = rest.is_empty() || rest.starts_with(char::is_whitespace);
+    if separated && DIRECTIVES.contains(&name) {
+        Some((name, rest.trim()))
+    } else {
+        None
+    }
+}
+
+/// A `NAME` or `NAME=value` definition, as given to `-D`. A bare name is 1.
+pub fn parse_define(definition: &str) -> Result<(String, i64), String> {
+    let (name, value) = match definition.find('=') {
+        Some(at) => (&definition[..at], Some(&definition[at + 1..])),
+        None => (definition, None),
+    };
+    if !is_name(name) {
+        return Err(format!("`{}` is not a valid name", name));
+    }
+    let value = match value {
+        Some(text) => match expression(CompleteStr(text)) {
+            Ok((rest, e)) if rest.is_empty() => e.constant(),
+            _ => None,
+        }
+        .ok_or_else(|| format!("`{}` is not a constant", text))?,
+        None => 1,
+    };
+    Ok((name.to_string(), value))
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// One open `.if`.
+struct Block {
+    /// Where the `.if` is, for reporting it unclosed.
+    start: usize,
+    directive: &'static str,
+    /// Whether the lines surrounding the block are assembled.
+    outer: bool,
+    /// Whether the current branch is assembled.
+    active: bool,
+    /// Whether some branch has been, so later ones are not.
+    taken: bool,
+    seen_else: bool,
+}
+
+/// `raw` with excluded lines and directives blanked out, or every problem
+/// with its directives.
+pub fn preprocess(
+    raw: &str,
+    defines: &HashMap<String, i64>,
+) -> Result<String, Vec<AssemblerError>> {
+    preprocess_with_constants(raw, defines).map(|(text, _)| text)
+}
+
+/// `preprocess`, also handing back the constants `raw` defines.
+pub fn preprocess_with_constants(
+    raw: &str,
+    defines: &HashMap<String, i64>,
+) -> Result<(String, Vec<Constant>), Vec<AssemblerError>> {
+    // Fast path: most sources have no directives at all.
+    if !raw.lines().any(|line| conditional(line).is_some()) {
+        return Ok((raw.to_string(), vec![]));
+    }
+    let mut defines = Cow::Borrowed(defines);
+    let mut constants = vec![];
+    let mut out = String::with_capacity(raw.len());
+    let mut errors = vec![];
+    let mut blocks: Vec<Block> = vec![];
+    let mut start = 0;
+    for line in raw.split_inclusive('\n') {
+        let at = |text: &str| line_column(raw, start + line.find(text).unwrap_or(0));
+        let active = blocks.last().is_none_or(|b| b.active);
+        let keep = match conditional(line) {
+            None => active,
+            Some((directive, rest)) => {
+                let directive_at = at(&format!(".{}", directive));
+                let mut test = |expected: bool| {
+                    test_condition(directive, rest, &defines, &at).unwrap_or_else(|e| {
+                        errors.push(e);
+                        false
+                    }) == expected
+                };
+                match directive {
+                    "if" | "ifdef" | "ifndef" => {
+                        let value = active && test(directive != "ifndef");
+                        blocks.push(Block {
+                            start: start + line.find('.').unwrap_or(0),
+                            directive: match directive {
+                                "if" => "if",
+                                "ifdef" => "ifdef",
+                                _ => "ifndef",
+                            },
+                            outer: active,
+                            active: value,
+                            taken: value,
+                            seen_else: false,
+                        });
+                    }
+                    "elif" | "else" => match blocks.last_mut() {
+                        Some(block) if block.seen_else => {
+                            let (line, column) = directive_at;
+                            errors.push(AssemblerError::ConditionalAfterElse {
+                                line,
+                                column,
+                                directive: directive.to_string(),
+                            });
+                        }
+                        Some(block) => {
+                            let open = block.outer && !block.taken;
+                            block.active = open && (directive == "else" || test(true));
+                            block.taken |= block.active;
+                            block.seen_else = directive == "else";
+                        }
+                        None => {
+                            let (line, column) = directive_at;
+                            errors.push(AssemblerError::UnmatchedConditional {
+                                line,
+                                column,
+                                directive: directive.to_string(),
+                            });
+                        }
+                    },
+                    "endif" => {
+                        if blocks.pop().is_none() {
+                            let (line, column) = directive_at;
+                            errors.push(AssemblerError::UnmatchedConditional {
+                                line,
+                                column,
+                                directive: directive.to_string(),
+                            });
+                        }
+                    }
+                    "equ" => {
+                        if active {
+                            match equate(rest, &defines, &at) {
+                                Ok((name, value)) => {
+                                    let (line, column) = at(name);
+                                    defines.to_mut().insert(name.to_string(), value);
+                                    constants.push(Constant {
+                                        name: name.to_string(),
+                                        value,
+                                        line,
+                                        column,
+                                    });
+                                }
+                                Err(e) => errors.push(e),
+                            }
+                        }
+                    }
+                    _ => {
+                        if active {
+                            let (line, column) = directive_at;
+                            errors.push(AssemblerError::UserError {
+                                line,
+                                column,
+                                message: message(rest),
+                            });
+                        }
+                    }
+                }
+                false
+            }
+        };
+        if keep {
+            out.push_str(line);
+        } else {
+            let text = line.trim_end_matches(['\n', '\r']);
+            out.push_str(&" ".repeat(text.len()));
+            out.push_str(&line[text.len()..]);
+        }
+        start += line.len();
+    }
+    for block in blocks {
+        let (line, column) = line_column(raw, block.start);
+        errors.push(AssemblerError::UnmatchedConditional {
+            line,
+            column,
+            directive: block.directive.to_string(),
+        });
+    }
+    if errors.is_empty() {
+        Ok((out, constants))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Whether the condition of `.if`, `.elif`, `.ifdef` or `.ifndef` holds.
+/// `.ifndef` is tested as `.ifdef`; the caller inverts it.
+fn test_condition(
+    directive: &str,
+    rest: &str,
+    defines: &HashMap<String, i64>,
+    at: &dyn Fn(&str) -> (u32, u32),
+) -> Result<bool, AssemblerError> {
+    if directive == "ifdef" || directive == "ifndef" {
+        if !is_name(rest) {
+            let (line, column) = at(&format!(".{}", directive));
+            return Err(AssemblerError::ParseError {
+                line,
+                column,
+                text: format!(".{} {}", directive, rest).trim_end().to_string(),
+            });
+        }
+        return Ok(defines.contains_key(rest));
+    }
+    evaluate(rest, defines, at).map(|value| value != 0)
+}
+
+/// The name and value defined by `.equ NAME EXPR`.
+fn equate<'a>(
+    rest: &'a str,
+    defines: &HashMap<String, i64>,
+    at: &dyn Fn(&str) -> (u32, u32),
+) -> Result<(&'a str, i64), AssemblerError> {
+    let (name, text) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let text = text.trim();
+    if !is_name(name) || text.is_empty() {
+        let (line, column) = at(".equ");
+        return Err(AssemblerError::ParseError {
+            line,
+            column,
+            text: format!(".equ {}", rest).trim_end().to_string(),
+        });
+    }
+    if defines.contains_key(name) {
+        let (line, column) = at(name);
+        return Err(AssemblerError::Redefined {
+            line,
+            column,
+            name: name.to_string(),
+        });
+    }
+    let value = evaluate(text, defines, at)?;
+    if i32::try_from(value).is_err() {
+        let (line, column) = at(text);
+        return Err(AssemblerError::ValueOutOfRange {
+            line,
+            column,
+            directive: "equ".to_string(),
+            value,
+        });
+    }
+    Ok((name, value))
+}
+
+/// The value of the constant expression `rest`, names substituted.
+fn evaluate(
+    rest: &str,
+    defines: &HashMap<String, i64>,
+    at: &dyn Fn(&str) -> (u32, u32),
+) -> Result<i64, AssemblerError> {
+    let text = substitute(rest, defines).map_err(|name| {
+        let (line, column) = at(name);
+        AssemblerError::UndefinedName {
+            line,
+            column,
+            name: name.to_string(),
+        }
+    })?;
+    let (line, column) = at(rest);
+    match expression(CompleteStr(&text)) {
+        Ok((remaining, e)) if remaining.trim().is_empty() => match e.constant() {
+            Some(value) => Ok(value),
+            None => Err(AssemblerError::InvalidExpression {
+                line,
+                column,
+                text: rest.to_string(),
+            }),
+        },
+        _ => Err(AssemblerError::ParseError {
+            line,
+            column,
+            text: rest.to_string(),
+        }),
+    }
+}
+
+/// `text` with each bare name replaced by its definition, or the first name
+/// that has none.
+fn substitute<'a>(text: &'a str, defines: &HashMap<String, i64>) -> Result<String, &'a str> {
+    let mut out = String::new();
+    let mut rest = text;
+    let mut previous = None;
+    while let Some(c) = rest.chars().next() {
+        let starts_name = (c.is_ascii_alphabetic() || c == '_')
+            && !previous.is_some_and(|p: char| p.is_ascii_alphanumeric() || "_@#".contains(p));
+        if starts_name {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            let value = defines.get(name).ok_or(name)?;
+            out.push_str(&format!("({})", value));
+            previous = name.chars().last();
+            rest = &rest[end..];
+        } else {
+            out.push(c);
+            previous = Some(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(out)
+}
+
+/// The text of a `.error` message, which may be a string literal.
+fn message(text: &str) -> String {
+    let quoted = text.len() >= 2
+        && (text.starts_with('"') && text.ends_with('"')
+            || text.starts_with('\'') && text.ends_with('\''));
+    if quoted {
+        strings::text(text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defines(list: &[(&str, i64)]) -> HashMap<String, i64> {
+        list.iter().map(|(n, v)| (n.to_string(), *v)).collect()
+    }
+
+    /// The lines left in after preprocessing, trimmed and without blanks.
+    fn kept(source: &str, list: &[(&str, i64)]) -> Vec<String> {
+        let out = preprocess(source, &defines(list)).unwrap();
+        assert_eq!(out.len(), source.len());
+        out.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_conditional() {
+        assert_eq!(conditional("  .if DEBUG ; why"), Some(("if", "DEBUG")));
+        assert_eq!(conditional(".endif"), Some(("endif", "")));
+        assert_eq!(conditional(".iffy"), None);
+        assert_eq!(conditional("load $0 #1"), None);
+        assert_eq!(conditional("; .if"), None);
+    }
+
+    #[test]
+    fn test_branches() {
+        let source = ".if LEVEL > 1\nhigh\n.elif LEVEL == 1\none\n.else\nnone\n.endif\nafter";
+        assert_eq!(kept(source, &[("LEVEL", 2)]), vec!["high", "after"]);
+        assert_eq!(kept(source, &[("LEVEL", 1)]), vec!["one", "after"]);
+        assert_eq!(kept(source, &[("LEVEL", 0)]), vec!["none", "after"]);
+    }
+
+    #[test]
+    fn test_nesting_and_ifdef() {
+        let source = ".ifdef DEBUG\n.ifndef QUIET\nlog\n.endif\ncheck\n.else\nfast ??? {\n.endif";
+        assert_eq!(kept(source, &[("DEBUG", 1)]), vec!["log", "check"]);
+        assert_eq!(kept(source, &[("DEBUG", 1), ("QUIET", 1)]), vec!["check"]);
+        assert_eq!(kept(source, &[]), vec!["fast ??? {"]);
+        // Excluded conditions are not evaluated, so may use undefined names.
+        assert_eq!(kept(".if 0\n.if NOPE\n.endif\n.endif\nx", &[]), vec!["x"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let messages = |source: &str| -> Vec<String> {
+            let errors = preprocess(source, &defines(&[("ONE", 1)])).unwrap_err();
+            errors.iter().map(|e| e.to_string()).collect()
+        };
+        assert_eq!(
+            messages(
+                ".if ONE\n.error \"not \\\"ready\\\"\"\n.endif\n.if 0\n.error 'skipped'\n.endif"
+            ),
+            vec!["2:1: not \"ready\""]
+        );
+        assert_eq!(
+            messages(".else\n.if TWO + 1\n.endif\n.endif\n.ifdef 1\n.if ONE\n.else\n.elif 1"),
+            vec![
+                "1:1: `.else` without `.if`",
+                "2:5: `TWO` is not defined",
+                "4:1: `.endif` without `.if`",
+                "5:1: unable to parse `.ifdef 1`",
+                "8:1: `.elif` after `.else`",
+                "5:1: `.ifdef` without `.endif`",
+                "6:1: `.if` without `.endif`",
+            ]
+        );
+        assert_eq!(
+            messages(".if 1 +\n.endif"),
+            vec!["1:5: unable to parse `1 +`"]
+        );
+    }
+
+    #[test]
+    fn test_equ() {
+        let source = ".ifndef SIZE\n.equ SIZE 4\n.endif\n.equ WORDS SIZE / 2 ; two\n\
+                      .if WORDS == 2\ntwo\n.else\nmore\n.endif";
+        assert_eq!(kept(source, &[]), vec!["two"]);
+        assert_eq!(kept(source, &[("SIZE", 8)]), vec!["more"]);
+        // Excluded definitions do not count.
+        assert!(kept(".if 0\n.equ A 1\n.endif\n.ifdef A\nx\n.endif", &[]).is_empty());
+
+        let (_, constants) = preprocess_with_constants(source, &HashMap::new()).unwrap();
+        let names: Vec<_> = constants
+            .iter()
+            .map(|c| (&c.name[..], c.value, c.line))
+            .collect();
+        assert_eq!(names, vec![("SIZE", 4, 2), ("WORDS", 2, 4)]);
+
+        let source = ".equ A 1\n.equ A 2\n.equ B\n.equ C 1 / 0\n.equ D 0x80000000";
+        let errors = preprocess(source, &HashMap::new()).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "2:6: `A` is already defined",
+                "3:1: unable to parse `.equ B`",
+                "4:8: cannot evaluate `1 / 0`",
+                "5:8: value `2147483648` does not fit in `.equ`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_define() {
+        assert_eq!(parse_define("DEBUG"), Ok(("DEBUG".to_string(), 1)));
+        assert_eq!(parse_define("LEVEL=0x10"), Ok(("LEVEL".to_string(), 16)));
+        assert_eq!(parse_define("N=-2"), Ok(("N".to_string(), -2)));
+        assert!(parse_define("2X=1").is_err());
+        assert!(parse_define("X=@a").is_err());
+    }
+}
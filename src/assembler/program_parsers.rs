@@ -1,7 +1,21 @@
+use super::directive_parsers::directive;
 use super::instruction_parsers::{instruction, AssemblerInstruction};
-use super::SymbolTable;
+use super::pseudo::expand;
+use super::scope::Scope;
+use super::{SymbolTable, Token};
+use crate::abi;
 use nom::types::CompleteStr;
-use nom::{do_parse, many1};
+use nom::{alt, call, do_parse, many1, IResult};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// Where a statement sits in the source it was parsed from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span<'a> {
+    /// Byte offset of the first character of `text` in the source.
+    pub start: usize,
+    pub text: &'a str,
+}
 
 #[derive(Debug, PartialEq)]
 pub struct Program<'a> {
@@ -13,25 +27,236 @@ impl<'a> Program<'a> {
         let instructions = self.instructions.clone();
         instructions
             .iter()
+            .filter(|instruction| !instruction.is_directive())
             .fold(Vec::new(), |mut acc, instruction| {
-                acc.append(&mut instruction.as_bytes(symbols));
+                let offset = acc.len() as u32;
+                acc.append(&mut instruction.as_bytes(symbols, offset));
                 acc
             })
     }
+
+    /// Replaces register names with numbers: the names from `abi`, and those
+    /// given by `.alias name $r` from that directive on. Returns the names it
+    /// did not know, each with the index of its statement.
+    pub fn resolve_registers(&mut self) -> Vec<(usize, &'a str)> {
+        let mut aliases: HashMap<&str, u8> = HashMap::new();
+        let mut unknown = vec![];
+        for (index, i) in self.instructions.iter_mut().enumerate() {
+            let alias = i.directive_name() == Some("alias");
+            let mut operands = [&mut i.operand1, &mut i.operand2, &mut i.operand3];
+            // The first operand of `.alias` is the name being defined.
+            for operand in operands.iter_mut().skip(alias as usize) {
+                if let Some(Token::RegisterName { name }) = **operand {
+                    // A number here is one past the last register.
+                    let reg_num = if name.bytes().all(|b| b.is_ascii_digit()) {
+                        None
+                    } else {
+                        aliases
+                            .get(name)
+                            .cloned()
+                            .or_else(|| abi::register_number(name))
+                    };
+                    match reg_num {
+                        Some(reg_num) => **operand = Some(Token::Register { reg_num }),
+                        None => unknown.push((index, name)),
+                    }
+                }
+            }
+            if let (true, Some(Token::RegisterName { name }), Some(Token::Register { reg_num })) =
+                (alias, i.operand1, i.operand2)
+            {
+                aliases.insert(name, reg_num);
+            }
+        }
+        unknown
+    }
+
+    /// Repeats the statements between each `.rept N` and its `.endr` `N`
+    /// times. The directives stay, laying down nothing, so that labels on
+    /// them still resolve. Counts and nesting are assumed to be checked.
+    pub fn expand_repeats(self, sources: Vec<Span<'a>>) -> (Program<'a>, Vec<Span<'a>>) {
+        type Statement<'a> = (AssemblerInstruction<'a>, Span<'a>);
+        // The body of each open `.rept`, with its count and the directive.
+        let mut open: Vec<(u32, Statement, Vec<Statement>)> = vec![];
+        let mut expanded = vec![];
+        for statement in self.instructions.into_iter().zip(sources) {
+            match statement.0.directive_name() {
+                Some("rept") => open.push((statement.0.count().unwrap_or(0), statement, vec![])),
+                Some("endr") if !open.is_empty() => {
+                    let (count, rept, body) = open.pop().unwrap();
+                    let out = open.last_mut().map_or(&mut expanded, |(_, _, body)| body);
+                    out.push(rept);
+                    for _ in 0..count {
+                        out.extend(body.iter().cloned());
+                    }
+                    out.push(statement);
+                }
+                _ => open
+                    .last_mut()
+                    .map_or(&mut expanded, |(_, _, body)| body)
+                    .push(statement),
+            }
+        }
+        // Only an unchecked program leaves a `.rept` open; keep its body once.
+        while let Some((_, rept, body)) = open.pop() {
+            let out = open.last_mut().map_or(&mut expanded, |(_, _, body)| body);
+            out.push(rept);
+            out.extend(body);
+        }
+        let (instructions, sources) = expanded.into_iter().unzip();
+        (Program { instructions }, sources)
+    }
+
+    /// Gives local and numeric labels their unique names, as `Scope` works
+    /// them out, keeping the new names in `names`. Returns the labels that
+    /// are declared more than once, each with the index of its statement.
+    pub fn qualify_labels<'b>(
+        self,
+        names: &'b mut Vec<String>,
+    ) -> (Program<'b>, Vec<(usize, &'b str)>)
+    where
+        'a: 'b,
+    {
+        let mut scope = Scope::new();
+        let mut declared = HashSet::new();
+        let mut duplicates = vec![];
+        // Each name to replace: statement, slot (the label, then operands)
+        // and where in `names` the new name is.
+        let mut renamed = vec![];
+        for (index, i) in self.instructions.iter().enumerate() {
+            let mut slots = [i.label, i.operand1, i.operand2, i.operand3];
+            for (slot, token) in slots.iter_mut().enumerate() {
+                let name = match *token {
+                    Some(Token::LabelDecl { name }) => {
+                        let qualified = scope.declare(name);
+                        if !declared.insert(qualified.clone()) {
+                            duplicates.push((index, name));
+                        }
+                        qualified
+                    }
+                    Some(Token::LabelUsage { name }) => scope.resolve(name),
+                    Some(Token::ExprList { text }) => scope.resolve_text(text),
+                    _ => continue,
+                };
+                if let Cow::Owned(name) = name {
+                    names.push(name);
+                    renamed.push((index, slot, names.len() - 1));
+                }
+            }
+        }
+
+        let names: &'b Vec<String> = names;
+        let mut instructions: Vec<AssemblerInstruction<'b>> = self.instructions;
+        for (index, slot, name) in renamed {
+            let i = &mut instructions[index];
+            let token = match slot {
+                0 => &mut i.label,
+                1 => &mut i.operand1,
+                2 => &mut i.operand2,
+                _ => &mut i.operand3,
+            };
+            let name = names[name].as_str();
+            *token = match *token {
+                Some(Token::LabelDecl { .. }) => Some(Token::LabelDecl { name }),
+                Some(Token::LabelUsage { .. }) => Some(Token::LabelUsage { name }),
+                _ => Some(Token::ExprList { text: name }),
+            };
+        }
+        (Program { instructions }, duplicates)
+    }
 }
+
+nom::named!(
+    // One line of a program: an instruction or a directive
+    pub statement<CompleteStr, AssemblerInstruction>,
+    alt!(
+        instruction |
+        directive
+    )
+);
+
+/// Skips whitespace and `;` comments, which may appear between statements.
+pub fn filler(input: CompleteStr) -> IResult<CompleteStr, ()> {
+    let mut rest = input.0.trim_start();
+    while rest.starts_with(';') {
+        rest = rest[rest.find('\n').unwrap_or(rest.len())..].trim_start();
+    }
+    Ok((CompleteStr(rest), ()))
+}
+
+/// Splits off a `;` comment, ignoring any inside a quoted string.
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (at, c) in line.char_indices() {
+        match (c, quote) {
+            _ if escaped => escaped = false,
+            ('\'', None) | ('"', None) => quote = Some(c),
+            ('\\', Some('"')) => escaped = true,
+            (_, Some(q)) if c == q => quote = None,
+            (';', None) => return (&line[..at], Some(&line[at..])),
+            _ => {}
+        }
+    }
+    (line, None)
+}
+
 nom::named!(
     pub program<CompleteStr, Program>,
     do_parse!(
-        instructions: many1!(instruction) >>
+        instructions: many1!(
+            do_parse!(
+                call!(filler) >>
+                s: statement >>
+                call!(filler) >>
+                (s)
+            )
+        ) >>
         (Program {
-            instructions,
+            instructions: instructions.into_iter().flat_map(expand).collect(),
         })
     )
 );
 
+/// Parses like `program`, but also returns the span each statement was parsed
+/// from, in the same order as `Program::instructions`. The instructions a
+/// pseudo-instruction expands to share its span.
+pub fn program_with_sources<'a>(
+    raw: &'a str,
+) -> Result<(Program<'a>, Vec<Span<'a>>), nom::Err<CompleteStr<'a>>> {
+    let mut instructions = vec![];
+    let mut sources = vec![];
+    let mut rest = CompleteStr(raw);
+    loop {
+        rest = filler(rest)?.0;
+        if rest.is_empty() && !instructions.is_empty() {
+            break;
+        }
+        match statement(rest) {
+            Ok((remaining, ins)) => {
+                let consumed = &rest.0[..rest.len() - remaining.len()];
+                let leading = consumed.len() - consumed.trim_start().len();
+                let span = Span {
+                    start: raw.len() - rest.len() + leading,
+                    text: consumed.trim(),
+                };
+                for ins in expand(ins) {
+                    instructions.push(ins);
+                    sources.push(span);
+                }
+                rest = remaining;
+            }
+            Err(e) if instructions.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok((Program { instructions }, sources))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::expression::Expr;
 
     #[test]
     fn test_parse_program() {
@@ -52,4 +277,79 @@ mod tests {
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
     }
+
+    #[test]
+    fn test_parse_comments() {
+        let source = "; a loop\n  load $0 #100 ; counter\n\n; done\nhlt\n";
+        let (rest, p) = program(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(p.instructions.len(), 2);
+        let (p, sources) = program_with_sources(source).unwrap();
+        assert_eq!(p.instructions.len(), 2);
+        assert_eq!(sources[0].text, "load $0 #100");
+        assert_eq!(sources[1].start, 42);
+        assert!(program_with_sources("; nothing here").is_err());
+    }
+
+    #[test]
+    fn test_expand_repeats() {
+        let source = ".rept 2\ninc $0\n.rept 3\n.byte 1\n.endr\n.endr\nhlt";
+        let (p, sources) = program_with_sources(source).unwrap();
+        let (p, sources) = p.expand_repeats(sources);
+        let texts: Vec<&str> = sources.iter().map(|s| s.text).collect();
+        let body = [
+            "inc $0", ".rept 3", ".byte 1", ".byte 1", ".byte 1", ".endr",
+        ];
+        let mut expected = vec![".rept 2"];
+        expected.extend(&body);
+        expected.extend(&body);
+        expected.extend(&[".endr", "hlt"]);
+        assert_eq!(texts, expected);
+        assert_eq!(p.instructions.len(), texts.len());
+    }
+
+    #[test]
+    fn test_qualify_labels() {
+        let source = "main: .rept 2\n1: inc $0\njmpe @1b\n.endr\n.loop: jmp @1f\n\
+                      1: .word @.loop\nother: hlt\n.loop: jmp @main.loop\nmain: hlt";
+        let (p, sources) = program_with_sources(source).unwrap();
+        let (p, _) = p.expand_repeats(sources);
+        let mut names = vec![];
+        let (p, duplicates) = p.qualify_labels(&mut names);
+        let labels: Vec<&str> = p
+            .instructions
+            .iter()
+            .filter_map(|i| i.label_name())
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                "main",
+                "1.1",
+                "1.2",
+                "main.loop",
+                "1.3",
+                "other",
+                "other.loop",
+                "main"
+            ]
+        );
+        let targets: Vec<Token> = p.instructions.iter().filter_map(|i| i.target()).collect();
+        let usage = |name| Token::LabelUsage { name };
+        assert_eq!(
+            targets,
+            vec![usage("1.1"), usage("1.2"), usage("1.3"), usage("main.loop")]
+        );
+        assert_eq!(p.instructions[7].values()[0], Expr::Label("main.loop"));
+        assert_eq!(duplicates, vec![(p.instructions.len() - 1, "main")]);
+    }
+
+    #[test]
+    fn test_program_with_sources() {
+        let (p, sources) = program_with_sources("load $0 #100\ntest: inc $0\n").unwrap();
+        assert_eq!(p.instructions.len(), 2);
+        let texts: Vec<&str> = sources.iter().map(|s| s.text).collect();
+        assert_eq!(texts, vec!["load $0 #100", "test: inc $0"]);
+        assert_eq!(sources[1].start, 13);
+    }
 }
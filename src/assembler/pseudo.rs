@@ -0,0 +1,175 @@
+//! Pseudo-instructions: mnemonics the assembler accepts but the VM has no
+//! opcode for. Each expands to one or more real instructions as the program
+//! is parsed, so every later pass only sees real opcodes.
+//!
+//! | Pseudo       | Expands to                  |
+//! |--------------|-----------------------------|
+//! | `mov $a $d`  | `or $a $a $d`               |
+//! | `nop`        | `or $0 $0 $0`               |
+//! | `clr $r`     | `xor $r $r $r`              |
+//! | `not $r`     | `not $r $r`                 |
+//! | `neg $a $d`  | `not $a $d` then `inc $d`   |
+//! | `neg $r`     | `not $r $r` then `inc $r`   |
+//! | `jne @label` | `jmpne @label`              |
+//! | `li $r #imm` | `load $r #imm`, or `load32` |
+
+use super::instruction_parsers::AssemblerInstruction;
+use super::Token;
+use crate::instruction::Opcode;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Pseudo {
+    Mov,
+    Nop,
+    Clr,
+    Neg,
+    Jne,
+    Li,
+}
+
+/// Every pseudo-instruction, for completion lists.
+pub const PSEUDOS: [Pseudo; 6] = [
+    Pseudo::Mov,
+    Pseudo::Nop,
+    Pseudo::Clr,
+    Pseudo::Neg,
+    Pseudo::Jne,
+    Pseudo::Li,
+];
+
+impl Pseudo {
+    /// The pseudo-instruction spelled `word`, in any case.
+    pub fn from_word(word: &str) -> Option<Pseudo> {
+        let word = word.to_lowercase();
+        PSEUDOS.iter().cloned().find(|p| p.mnemonic() == word)
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Pseudo::Mov => "mov",
+            Pseudo::Nop => "nop",
+            Pseudo::Clr => "clr",
+            Pseudo::Neg => "neg",
+            Pseudo::Jne => "jne",
+            Pseudo::Li => "li",
+        }
+    }
+
+    /// Operand syntax and a one-line description, as `Opcode::summary`.
+    pub fn summary(self) -> (&'static str, &'static str) {
+        match self {
+            Pseudo::Mov => ("mov $a $d", "$d = $a, as or $a $a $d"),
+            Pseudo::Nop => ("nop", "Do nothing, as or $0 $0 $0"),
+            Pseudo::Clr => ("clr $r", "$r = 0, as xor $r $r $r"),
+            Pseudo::Neg => (
+                "neg $a [$d]",
+                "$d = -$a, as not then inc; $d defaults to $a",
+            ),
+            Pseudo::Jne => (
+                "jne @label|#offset|$r",
+                "Jump if the equal flag is clear, as jmpne",
+            ),
+            Pseudo::Li => ("li $r #imm", "Load any 32-bit immediate, as load or load32"),
+        }
+    }
+}
+
+/// The real instructions `i` stands for, or just `i` if it is one already.
+/// A label on `i` goes on the first of them.
+pub fn expand(i: AssemblerInstruction) -> Vec<AssemblerInstruction> {
+    let (a, b) = (i.operand1, i.operand2);
+    let zero = Some(Token::Register { reg_num: 0 });
+    let expansion = match i.opcode {
+        Some(Token::Pseudo { name }) => match name {
+            Pseudo::Mov => vec![(Opcode::OR, [a, a, b])],
+            Pseudo::Nop => vec![(Opcode::OR, [zero, zero, zero])],
+            Pseudo::Clr => vec![(Opcode::XOR, [a, a, a])],
+            Pseudo::Neg => {
+                let d = b.or(a);
+                vec![(Opcode::NOT, [a, d, None]), (Opcode::INC, [d, None, None])]
+            }
+            Pseudo::Jne => vec![(Opcode::JMPNE, [a, b, i.operand3])],
+            Pseudo::Li => vec![(Opcode::LOAD, [a, b, i.operand3])],
+        },
+        // `not $r` inverts in place.
+        Some(Token::Op { code: Opcode::NOT }) if b.is_none() => vec![(Opcode::NOT, [a, a, None])],
+        _ => return vec![i],
+    };
+    expansion
+        .into_iter()
+        .enumerate()
+        .map(
+            |(n, (code, [operand1, operand2, operand3]))| AssemblerInstruction {
+                label: if n == 0 { i.label } else { None },
+                directive: None,
+                opcode: Some(Token::Op { code }),
+                operand1,
+                operand2,
+                operand3,
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::statement;
+    use nom::types::CompleteStr;
+
+    fn expanded(source: &str) -> Vec<(Opcode, Vec<Token<'_>>)> {
+        let (_, i) = statement(CompleteStr(source)).unwrap();
+        expand(i)
+            .iter()
+            .map(|i| {
+                let operands = [i.operand1, i.operand2, i.operand3];
+                (
+                    i.opcode().unwrap(),
+                    operands.iter().flatten().cloned().collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn r(reg_num: u8) -> Token<'static> {
+        Token::Register { reg_num }
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expanded("mov $1 $2"),
+            vec![(Opcode::OR, vec![r(1), r(1), r(2)])]
+        );
+        assert_eq!(expanded("NOP"), vec![(Opcode::OR, vec![r(0), r(0), r(0)])]);
+        assert_eq!(
+            expanded("clr $4"),
+            vec![(Opcode::XOR, vec![r(4), r(4), r(4)])]
+        );
+        assert_eq!(expanded("not $4"), vec![(Opcode::NOT, vec![r(4), r(4)])]);
+        assert_eq!(expanded("not $4 $5"), vec![(Opcode::NOT, vec![r(4), r(5)])]);
+        assert_eq!(
+            expanded("neg $3"),
+            vec![(Opcode::NOT, vec![r(3), r(3)]), (Opcode::INC, vec![r(3)])]
+        );
+        assert_eq!(
+            expanded("jne @top"),
+            vec![(Opcode::JMPNE, vec![Token::LabelUsage { name: "top" }])]
+        );
+        assert_eq!(
+            expanded("li $0 #70000"),
+            vec![(Opcode::LOAD, vec![r(0), Token::IntOperand { value: 70000 }])]
+        );
+        assert_eq!(
+            expanded("add $1 $2 $3"),
+            vec![(Opcode::ADD, vec![r(1), r(2), r(3)])]
+        );
+    }
+
+    #[test]
+    fn test_expand_keeps_label() {
+        let (_, i) = statement(CompleteStr("flip: neg $1 $2")).unwrap();
+        let labels: Vec<_> = expand(i).iter().map(|i| i.label_name()).collect();
+        assert_eq!(labels, vec![Some("flip"), None]);
+    }
+}
@@ -1,16 +1,30 @@
 use super::Token;
+use crate::abi;
 use nom::types::CompleteStr;
-use nom::{digit, tag, ws};
+use nom::{alphanumeric, digit, tag, ws};
 
 nom::named!(
     pub register<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("$") >>
-            reg_num: digit >>
-            (Token::Register {
-                reg_num: reg_num.parse::<u8>().unwrap()
-            })
+            register: alt!(
+                // A number past the last register is left for
+                // `Program::resolve_registers` to report as unknown.
+                do_parse!(
+                    reg_num: digit >>
+                    (match reg_num.parse::<u8>() {
+                        Ok(reg_num) if reg_num < abi::REGISTERS => Token::Register { reg_num },
+                        _ => Token::RegisterName { name: &reg_num },
+                    })
+                ) |
+                // `$sp`, `$t0` or an `.alias`, resolved once the program is parsed
+                do_parse!(
+                    name: alphanumeric >>
+                    (Token::RegisterName { name: &name })
+                )
+            ) >>
+            (register)
         )
     )
 );
@@ -26,8 +40,10 @@ mod tests {
         assert_eq!(result.is_ok(), true);
         let result = register(CompleteStr("0"));
         assert_eq!(result.is_ok(), false);
-        let result = register(CompleteStr("$a"));
-        assert_eq!(result.is_ok(), false);
+        let result = register(CompleteStr("$sp"));
+        assert_eq!(result.unwrap().1, Token::RegisterName { name: "sp" });
+        let result = register(CompleteStr("$40"));
+        assert_eq!(result.unwrap().1, Token::RegisterName { name: "40" });
         let result = register(CompleteStr("$"));
         assert_eq!(result.is_ok(), false);
     }
@@ -0,0 +1,137 @@
+//! Local and numeric labels, and the unique names they are given.
+//!
+//! | Label    | Scope                        | Referred to as                     |
+//! |----------|------------------------------|------------------------------------|
+//! | `main:`  | the whole program            | `@main`                            |
+//! | `.loop:` | up to the next global label  | `@.loop`, or `@main.loop` anywhere |
+//! | `1:`     | none; may be declared again  | `@1f` for the next, `@1b` the last |
+//!
+//! Once repeats are expanded, `Program::qualify_labels` renames local labels
+//! to `main.loop` and numeric ones to `1.1`, `1.2` and so on, so that every
+//! later pass deals with unique, global names only.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Whether `name` is local to the global label before it.
+pub fn is_local(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Whether `name` is a numeric label, such as `1`.
+pub fn is_numeric(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The label `1f` or `1b` refers to, and whether it looks forwards.
+fn numeric_reference(name: &str) -> Option<(&str, bool)> {
+    let (number, direction) = name.split_at(name.len().checked_sub(1)?);
+    match direction {
+        "f" if is_numeric(number) => Some((number, true)),
+        "b" if is_numeric(number) => Some((number, false)),
+        _ => None,
+    }
+}
+
+/// What labels mean at some point in a program, worked out statement by
+/// statement.
+#[derive(Debug, Default)]
+pub struct Scope<'a> {
+    /// The last global label declared.
+    global: Option<&'a str>,
+    /// How many times each numeric label has been declared.
+    numeric: HashMap<&'a str, u32>,
+}
+
+impl<'a> Scope<'a> {
+    pub fn new() -> Scope<'a> {
+        Scope::default()
+    }
+
+    /// The unique name of the label declared as `name`. A global label also
+    /// starts a new scope for local ones.
+    pub fn declare(&mut self, name: &'a str) -> Cow<'a, str> {
+        if is_numeric(name) {
+            let count = self.numeric.entry(name).or_insert(0);
+            *count += 1;
+            Cow::Owned(format!("{}.{}", name, count))
+        } else if is_local(name) {
+            self.resolve(name)
+        } else {
+            self.global = Some(name);
+            Cow::Borrowed(name)
+        }
+    }
+
+    /// The unique name of the label `name` refers to from here.
+    pub fn resolve(&self, name: &'a str) -> Cow<'a, str> {
+        if let Some((number, forward)) = numeric_reference(name) {
+            let declared = self.numeric.get(number).cloned().unwrap_or(0);
+            // `1b` before any `1:` becomes `1.0`, which is never declared.
+            return Cow::Owned(format!("{}.{}", number, declared + forward as u32));
+        }
+        match self.global {
+            Some(global) if is_local(name) => Cow::Owned(format!("{}{}", global, name)),
+            _ => Cow::Borrowed(name),
+        }
+    }
+
+    /// `text` with each `@label` in it resolved, if any needed to be.
+    pub fn resolve_text(&self, text: &'a str) -> Cow<'a, str> {
+        let is_name = |c: char| c.is_ascii_alphanumeric() || c == '.';
+        let mut out = String::new();
+        let mut rest = text;
+        let mut changed = false;
+        while let Some(at) = rest.find('@') {
+            let start = at + 1;
+            let end = rest[start..]
+                .find(|c: char| !is_name(c))
+                .map_or(rest.len(), |end| start + end);
+            let name = self.resolve(&rest[start..end]);
+            changed |= matches!(name, Cow::Owned(_));
+            out.push_str(&rest[..start]);
+            out.push_str(&name);
+            rest = &rest[end..];
+        }
+        if !changed {
+            return Cow::Borrowed(text);
+        }
+        out.push_str(rest);
+        Cow::Owned(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope() {
+        let mut scope = Scope::new();
+        assert_eq!(scope.resolve(".loop"), ".loop");
+        assert_eq!(scope.declare("main"), "main");
+        assert_eq!(scope.declare(".loop"), "main.loop");
+        assert_eq!(scope.resolve("other.loop"), "other.loop");
+        assert_eq!(scope.resolve("1b"), "1.0");
+        assert_eq!(scope.resolve("1f"), "1.1");
+        assert_eq!(scope.declare("1"), "1.1");
+        assert_eq!(scope.resolve("1b"), "1.1");
+        assert_eq!(scope.resolve("1f"), "1.2");
+        // Numeric labels do not start a scope.
+        assert_eq!(scope.resolve(".loop"), "main.loop");
+        assert_eq!(scope.declare("other"), "other");
+        assert_eq!(scope.resolve(".loop"), "other.loop");
+        assert_eq!(scope.resolve("1fx"), "1fx");
+    }
+
+    #[test]
+    fn test_resolve_text() {
+        let mut scope = Scope::new();
+        scope.declare("table");
+        assert_eq!(scope.resolve_text("@end - @table"), "@end - @table");
+        assert_eq!(
+            scope.resolve_text("@.end - @table, @1f*2"),
+            "@table.end - @table, @1.1*2"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! String literals, as taken by `.asciiz`, `.string` and `.error`.
+//!
+//! A single-quoted string, `'like this'`, is taken as written. A
+//! double-quoted one may use escapes:
+//!
+//! | Escape              | Stands for                            |
+//! |---------------------|---------------------------------------|
+//! | `\n` `\r` `\t` `\0` | newline, return, tab and a zero byte  |
+//! | `\\` `\"` `\'`      | the character itself                  |
+//! | `\x41`              | the byte 0x41; any byte, UTF-8 or not |
+//! | `\u{1F600}`         | the character U+1F600, in UTF-8       |
+//!
+//! Strings are laid down in UTF-8.
+
+use nom::types::CompleteStr;
+use nom::{error_position, ErrorKind, IResult};
+
+/// An escape that does not stand for anything.
+#[derive(Debug, PartialEq)]
+pub struct EscapeError {
+    /// Byte offset of the escape in the literal.
+    pub at: usize,
+    pub escape: String,
+}
+
+/// Recognizes a double-quoted literal, quotes and escapes included.
+pub fn double_quoted(input: CompleteStr) -> IResult<CompleteStr, CompleteStr> {
+    let error = || Err(nom::Err::Error(error_position!(input, ErrorKind::Tag)));
+    if !input.starts_with('"') {
+        return error();
+    }
+    let mut escaped = false;
+    for (at, c) in input.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Ok((CompleteStr(&input[at + 1..]), CompleteStr(&input[..=at]))),
+            _ => {}
+        }
+    }
+    error()
+}
+
+/// The bytes a literal, quotes included, stands for.
+pub fn decode(literal: &str) -> Result<Vec<u8>, EscapeError> {
+    let body = &literal[1..literal.len() - 1];
+    if !literal.starts_with('"') {
+        return Ok(body.as_bytes().to_vec());
+    }
+    let mut bytes = vec![];
+    let mut chars = body.char_indices();
+    while let Some((at, c)) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        // Everything up to `end` is part of this escape.
+        let mut end = at + 1;
+        let mut take = |n: usize| {
+            let mut taken = String::new();
+            for _ in 0..n {
+                match chars.next() {
+                    Some((i, c)) => {
+                        taken.push(c);
+                        end = i + c.len_utf8();
+                    }
+                    None => break,
+                }
+            }
+            taken
+        };
+        let kind = take(1);
+        let decoded = match kind.as_str() {
+            "n" => Some(vec![b'\n']),
+            "r" => Some(vec![b'\r']),
+            "t" => Some(vec![b'\t']),
+            "0" => Some(vec![0]),
+            "\\" | "\"" | "'" => Some(kind.clone().into_bytes()),
+            "x" => {
+                let hex = take(2);
+                match hex.len() {
+                    2 => u8::from_str_radix(&hex, 16).ok().map(|b| vec![b]),
+                    _ => None,
+                }
+            }
+            "u" => {
+                let mut code = take(1);
+                if code == "{" {
+                    while !code.ends_with('}') && code.len() < 9 {
+                        let next = take(1);
+                        if next.is_empty() {
+                            break;
+                        }
+                        code.push_str(&next);
+                    }
+                }
+                code.strip_prefix('{')
+                    .and_then(|c| c.strip_suffix('}'))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                    .map(|c| c.to_string().into_bytes())
+            }
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => bytes.extend(decoded),
+            None => {
+                return Err(EscapeError {
+                    // The offset in `literal`, past the opening quote.
+                    at: at + 1,
+                    escape: body[at..end].to_string(),
+                });
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// The text of a literal, for messages rather than data: undecodable
+/// escapes are kept as written and invalid UTF-8 is replaced.
+pub fn text(literal: &str) -> String {
+    match decode(literal) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => literal[1..literal.len() - 1].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_quoted() {
+        let (rest, literal) = double_quoted(CompleteStr(r#""a \"b\" ; c" ; d"#)).unwrap();
+        assert_eq!(literal, CompleteStr(r#""a \"b\" ; c""#));
+        assert_eq!(rest, CompleteStr(" ; d"));
+        assert_eq!(
+            double_quoted(CompleteStr(r#""""#)).unwrap().1,
+            CompleteStr(r#""""#)
+        );
+        assert!(double_quoted(CompleteStr(r#""open \""#)).is_err());
+        assert!(double_quoted(CompleteStr("'single'")).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(r"'a\n'").unwrap(), b"a\\n");
+        assert_eq!(
+            decode(r#""tab\tnl\n\\ \"q\" \x41\xff\0""#).unwrap(),
+            b"tab\tnl\n\\ \"q\" A\xff\0"
+        );
+        assert_eq!(decode(r#""\u{1F600}é""#).unwrap(), "😀é".as_bytes());
+        assert_eq!(decode(r#""""#).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let error = |literal: &str| decode(literal).unwrap_err();
+        assert_eq!(
+            error(r#""ok \q""#),
+            EscapeError {
+                at: 4,
+                escape: r"\q".to_string()
+            }
+        );
+        assert_eq!(error(r#""\x4""#).escape, r"\x4");
+        assert_eq!(error(r#""\xzz""#).escape, r"\xzz");
+        assert_eq!(error(r#""\u{110000}""#).escape, r"\u{110000}");
+        assert_eq!(error(r#""\u{12""#).escape, r"\u{12");
+        assert_eq!(error(r#""\u41""#).escape, r"\u4");
+    }
+}
@@ -0,0 +1,11 @@
+use iridium::lsp;
+use std::io;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(e) = lsp::serve(stdin.lock(), stdout.lock()) {
+        eprintln!("iridium-lsp: {}", e);
+        std::process::exit(1);
+    }
+}
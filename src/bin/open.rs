@@ -2,70 +2,422 @@ extern crate clap;
 extern crate nom;
 
 #[allow(unused_imports)]
-use clap::{load_yaml, App, Arg, SubCommand};
-use iridium::{assembler, repl, vm};
+use clap::{load_yaml, App, Arg, ArgMatches, SubCommand};
+use iridium::bytecode::Bytecode;
+use iridium::cluster::{self, Message, Node, NodeConfig};
+use iridium::remote::{Server, ServerConfig, VmMode};
+use iridium::repl::transcript;
+use iridium::{analysis, assembler, repl, vm};
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::Duration;
+
+/// Bad arguments, or a file that could not be read or written.
+const EXIT_USAGE: i32 = 1;
+/// The source did not assemble, or bytecode did not verify.
+const EXIT_ASSEMBLY: i32 = 2;
+/// The program was stopped by a VM error.
+const EXIT_CRASH: i32 = 3;
 
 fn main() {
     let _cli_config = load_yaml!("cli.yml");
     let matches = App::from_yaml(_cli_config).get_matches();
 
-    match matches.value_of("INPUT_FILE") {
-        Some(filename) => {
-            let mut asm = assembler::Assembler::new();
-            let program = read_file(filename);
-            let program = asm.assemble(&program);
-
-            // match program {
-            //     Some(p) => {
-            //         let mut vm = vm::VM::new();
-            //         vm.add_bytes(p);
-            //         vm.run();
-            //         std::process::exit(0);
-            //     }
-            //     None => {}
-            // }
-            if let Some(p) = program {
-                let mut vm = vm::VM::new();
-                vm.add_bytes(p);
-                vm.run();
-                std::process::exit(0);
+    let code = match matches.subcommand() {
+        ("assemble", Some(m)) => exit_code(assemble(m)),
+        ("run", Some(m)) => exit_code(run(m)),
+        ("disasm", Some(m)) => exit_code(disasm(m)),
+        ("check", Some(m)) => check(m),
+        ("fmt", Some(m)) => format_files(m),
+        ("repl", Some(m)) => exit_code(repl(m)),
+        ("node", Some(m)) => exit_code(node(m)),
+        ("deploy", Some(m)) => exit_code(deploy(m)),
+        _ => match matches.value_of("INPUT_FILE") {
+            Some(_) => exit_code(run(&matches)),
+            None => start_repl(),
+        },
+    };
+    std::process::exit(code);
+}
+
+/// Exit code of a failed step, the error already reported on stderr.
+type Outcome<T> = Result<T, i32>;
+
+fn read_file(filename: &str) -> Outcome<Vec<u8>> {
+    std::fs::read(filename).map_err(|e| {
+        eprintln!("{}: {}", filename, e);
+        EXIT_USAGE
+    })
+}
+
+fn read_source(filename: &str) -> Outcome<String> {
+    String::from_utf8(read_file(filename)?).map_err(|_| {
+        eprintln!("{}: not a UTF-8 source file", filename);
+        EXIT_USAGE
+    })
+}
+
+/// The `-D NAME=VALUE` definitions given.
+fn defines(matches: &ArgMatches) -> Outcome<HashMap<String, i64>> {
+    let definitions = matches.values_of("DEFINE").into_iter().flatten();
+    definitions
+        .map(|definition| {
+            assembler::preprocessor::parse_define(definition).map_err(|e| {
+                eprintln!("-D {}: {}", definition, e);
+                EXIT_USAGE
+            })
+        })
+        .collect()
+}
+
+fn assemble_source(filename: &str, source: &str, matches: &ArgMatches) -> Outcome<Bytecode> {
+    let mut asm = assembler::Assembler::new().with_debug_info(filename);
+    for (name, value) in defines(matches)? {
+        asm = asm.with_define(&name, value);
+    }
+    if matches.is_present("LISTING") {
+        asm = asm.with_listing();
+    }
+    if matches.is_present("OPTIMIZE") {
+        asm = asm.with_optimizations();
+    }
+    let code = asm.try_assemble(source).map_err(|errors| {
+        for e in errors {
+            eprintln!("{}:{}", filename, e);
+        }
+        EXIT_ASSEMBLY
+    })?;
+    if let Some(listing) = asm.listing() {
+        print!("{}", listing);
+    }
+    Ok(Bytecode {
+        code,
+        ro: asm.ro.clone(),
+        debug_info: asm.debug_info().cloned(),
+    })
+}
+
+/// Assembles `filename`, or loads it as is if it is already bytecode.
+fn load(filename: &str, matches: &ArgMatches) -> Outcome<Bytecode> {
+    load_bytes(filename, &read_file(filename)?, matches)
+}
+
+/// `load`, for the contents of `filename` already read.
+fn load_bytes(filename: &str, bytes: &[u8], matches: &ArgMatches) -> Outcome<Bytecode> {
+    if Bytecode::is_bytecode(bytes) {
+        return Bytecode::from_bytes(bytes).ok_or_else(|| {
+            eprintln!("{}: corrupt bytecode file", filename);
+            EXIT_USAGE
+        });
+    }
+    let source = str::from_utf8(bytes).map_err(|_| {
+        eprintln!("{}: neither bytecode nor UTF-8 source", filename);
+        EXIT_USAGE
+    })?;
+    assemble_source(filename, source, matches)
+}
+
+fn exit_code(outcome: Outcome<i32>) -> i32 {
+    outcome.unwrap_or_else(|code| code)
+}
+
+fn assemble(matches: &ArgMatches) -> Outcome<i32> {
+    let filename = matches.value_of("INPUT_FILE").unwrap();
+    let bytecode = assemble_source(filename, &read_source(filename)?, matches)?;
+    let output = match matches.value_of("OUTPUT") {
+        Some(output) => output.to_string(),
+        None => Path::new(filename)
+            .with_extension("irb")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    std::fs::write(&output, bytecode.to_bytes()).map_err(|e| {
+        eprintln!("{}: {}", output, e);
+        EXIT_USAGE
+    })?;
+    Ok(0)
+}
+
+fn run(matches: &ArgMatches) -> Outcome<i32> {
+    let exit_register = match matches.value_of("EXIT_REGISTER") {
+        Some(r) => match r.trim_start_matches('$').parse::<usize>() {
+            Ok(r) if r < 32 => Some(r),
+            _ => {
+                eprintln!(
+                    "--exit-register must be a register from 0 to 31, not `{}`",
+                    r
+                );
+                return Err(EXIT_USAGE);
             }
+        },
+        None => None,
+    };
+    let bytecode = load(matches.value_of("INPUT_FILE").unwrap(), matches)?;
+
+    let mut vm = vm::VM::new();
+    if let Some(mode) = matches.value_of("OVERFLOW") {
+        vm.set_overflow_mode(mode.parse().map_err(|e| {
+            eprintln!("{}", e);
+            EXIT_USAGE
+        })?);
+    }
+    if let Some(debug) = bytecode.debug_info {
+        vm.set_debug_info(debug);
+    }
+    vm.add_bytes(bytecode.code);
+    vm.run();
+    if let Some(e) = vm.error() {
+        eprintln!("{}", e.render(vm.debug_info()));
+        return Err(EXIT_CRASH);
+    }
+    Ok(exit_register.map_or(0, |r| vm.registers[r]))
+}
+
+fn disasm(matches: &ArgMatches) -> Outcome<i32> {
+    let bytecode = load(matches.value_of("INPUT_FILE").unwrap(), matches)?;
+    let labels = bytecode
+        .debug_info
+        .as_ref()
+        .map_or(&[][..], |debug| &debug.labels[..]);
+    for insn in analysis::decode(&bytecode.code) {
+        for (name, _) in labels.iter().filter(|(_, offset)| *offset == insn.offset) {
+            println!("{}:", name);
         }
-        None => {
-            start_repl();
+        println!("    {}", insn);
+    }
+    Ok(0)
+}
+
+fn check(matches: &ArgMatches) -> i32 {
+    matches
+        .values_of("FILES")
+        .into_iter()
+        .flatten()
+        .map(|filename| exit_code(check_file(filename, matches)))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Assembles `filename`, or verifies it if it is bytecode, and with `--abi`
+/// warns about calling convention problems, naming routines by label when
+/// there is source to read them from.
+fn check_file(filename: &str, matches: &ArgMatches) -> Outcome<i32> {
+    let bytes = read_file(filename)?;
+    let bytecode = load_bytes(filename, &bytes, matches)?;
+    let is_bytecode = Bytecode::is_bytecode(&bytes);
+    if is_bytecode {
+        let errors = analysis::verify(&bytecode.code);
+        for e in &errors {
+            eprintln!("{}: {}", filename, e.render(bytecode.debug_info.as_ref()));
+        }
+        if !errors.is_empty() {
+            return Err(EXIT_ASSEMBLY);
+        }
+    }
+    if matches.is_present("ABI") {
+        // `load_bytes` has checked that source is UTF-8.
+        let source = match str::from_utf8(&bytes) {
+            Ok(source) if !is_bytecode => Some(
+                assembler::preprocessor::preprocess(source, &defines(matches)?)
+                    .unwrap_or_else(|_| source.to_string()),
+            ),
+            _ => None,
+        };
+        let parsed = source
+            .as_deref()
+            .map(assembler::program_parsers::program_with_sources);
+        let cfg = match parsed {
+            Some(Ok((mut p, sources))) => {
+                p.resolve_registers();
+                let (p, _) = p.expand_repeats(sources);
+                let mut names = vec![];
+                let (p, _) = p.qualify_labels(&mut names);
+                analysis::cfg::Cfg::from_program(&p)
+            }
+            _ => analysis::cfg::Cfg::from_bytecode(&bytecode.code),
+        };
+        for warning in analysis::abi::check(&cfg) {
+            eprintln!("{}: {}", filename, warning);
         }
     }
+    Ok(0)
 }
 
-fn read_file(tmp: &str) -> String {
-    use std::fs::File;
-    use std::io::Read;
-    use std::path::Path;
+/// Formats each file in place, or with `--check` lists the ones that would
+/// change. Returns the process exit code.
+fn format_files(matches: &ArgMatches) -> i32 {
+    let check = matches.is_present("CHECK");
+    let mut code = 0;
+    for filename in matches.values_of("FILES").into_iter().flatten() {
+        let source = match read_source(filename) {
+            Ok(source) => source,
+            Err(e) => {
+                code = e;
+                continue;
+            }
+        };
+        let formatted = match assembler::formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("{}: {}", filename, e);
+                code = EXIT_ASSEMBLY;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("would reformat {}", filename);
+            code = code.max(EXIT_USAGE);
+        } else if let Err(e) = std::fs::write(filename, formatted) {
+            eprintln!("{}: {}", filename, e);
+            code = EXIT_USAGE;
+        }
+    }
+    code
+}
 
-    let filename = Path::new(tmp);
-    match File::open(Path::new(&filename)) {
-        Ok(mut f) => {
-            let mut contents = String::new();
-            match f.read_to_string(&mut contents) {
-                Ok(_) => {
-                    contents
-                }
-                Err(e) => {
-                    println!("There was an error reading file: {:?}", e);
-                    std::process::exit(1);
+fn repl(matches: &ArgMatches) -> Outcome<i32> {
+    if let Some(addr) = matches.value_of("LISTEN") {
+        return listen(addr, matches);
+    }
+    if let Some(dir) = matches.value_of("TRANSCRIPTS") {
+        let results = transcript::check_dir(Path::new(dir)).map_err(|e| {
+            eprintln!("{}: {}", dir, e);
+            EXIT_USAGE
+        })?;
+        let mut failed = 0;
+        for (path, result) in &results {
+            match result {
+                Ok(()) => println!("ok {}", path.display()),
+                Err(mismatch) => {
+                    println!("FAILED {}\n{}", path.display(), mismatch);
+                    failed += 1;
                 }
             }
         }
+        println!("{} transcripts, {} failed", results.len(), failed);
+        return Ok(if failed == 0 { 0 } else { 1 });
+    }
+
+    let outcome = match matches.value_of("SCRIPT") {
+        Some("-") => repl::REPL::new().run_with(io::stdin().lock(), io::stdout()),
+        Some(script) => std::fs::File::open(script)
+            .and_then(|f| repl::REPL::new().run_with(BufReader::new(f), io::stdout())),
+        None => return Ok(start_repl()),
+    };
+    outcome.map(|_| 0).map_err(|e| {
+        eprintln!("{}: {}", matches.value_of("SCRIPT").unwrap_or("-"), e);
+        EXIT_USAGE
+    })
+}
+
+fn listen(addr: &str, matches: &ArgMatches) -> Outcome<i32> {
+    let run_limit = matches.value_of("RUN_LIMIT").unwrap();
+    let config = ServerConfig {
+        token: matches.value_of("TOKEN").map(str::to_string),
+        vm_mode: if matches.is_present("SHARED_VM") {
+            VmMode::Shared
+        } else {
+            VmMode::PerSession
+        },
+        load_dir: matches.value_of("LOAD_DIR").map(PathBuf::from),
+        run_limit: match run_limit.parse::<u64>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("--run-limit must be a positive number, not `{}`", run_limit);
+                return Err(EXIT_USAGE);
+            }
+        },
+        ..ServerConfig::default()
+    };
+    if config.token.is_none() {
+        eprintln!("warning: no token set, anyone who can connect gets a REPL");
+    }
+    let server = Server::bind(addr, config)
+        .and_then(|server| {
+            println!("Listening on {}", server.local_addr()?);
+            Ok(server)
+        })
+        .map_err(|e| {
+            eprintln!("{}: {}", addr, e);
+            EXIT_USAGE
+        })?;
+    server.serve().map(|_| 0).map_err(|e| {
+        eprintln!("{}", e);
+        EXIT_USAGE
+    })
+}
+
+fn node(matches: &ArgMatches) -> Outcome<i32> {
+    let heartbeat = matches.value_of("HEARTBEAT").unwrap();
+    let interval = match heartbeat.parse::<u64>() {
+        Ok(ms) if ms > 0 => Duration::from_millis(ms),
+        _ => {
+            eprintln!(
+                "--heartbeat-ms must be a positive number, not `{}`",
+                heartbeat
+            );
+            return Err(EXIT_USAGE);
+        }
+    };
+    let config = NodeConfig {
+        seed: matches.value_of("SEED").map(str::to_string),
+        heartbeat_interval: interval,
+        failure_timeout: interval * 3,
+        secret: matches.value_of("SECRET").map(str::to_string),
+        ..NodeConfig::new(matches.value_of("ID").unwrap())
+    };
+    if config.secret.is_none() {
+        eprintln!("warning: no secret set, anyone who can connect can deploy to this node");
+    }
+    let addr = matches.value_of("LISTEN").unwrap();
+    let node = Node::start(addr, config).map_err(|e| {
+        eprintln!("{}: {}", addr, e);
+        EXIT_USAGE
+    })?;
+    println!("Node {} listening on {}", node.id(), node.addr());
+    loop {
+        std::thread::park();
+    }
+}
+
+fn deploy(matches: &ArgMatches) -> Outcome<i32> {
+    let bytecode = load(matches.value_of("INPUT_FILE").unwrap(), matches)?;
+    let addr = matches.value_of("NODE").unwrap();
+    let message = Message::Deploy {
+        to: matches.value_of("TO").map(str::to_string),
+        bytecode: bytecode.to_bytes(),
+        secret: matches.value_of("SECRET").map(str::to_string),
+        forwarded: false,
+    };
+    match cluster::request(addr, &message) {
+        Ok(Message::Deployed { node, pid }) => {
+            println!("Started process {} on node {}", pid, node);
+            Ok(0)
+        }
+        Ok(Message::Error(e)) => {
+            eprintln!("{}: {}", addr, e);
+            Err(EXIT_USAGE)
+        }
+        Ok(reply) => {
+            eprintln!("{}: unexpected reply {}", addr, reply.to_json());
+            Err(EXIT_USAGE)
+        }
         Err(e) => {
-            println!("File not found: {:?}", e);
-            std::process::exit(1)
+            eprintln!("{}: {}", addr, e);
+            Err(EXIT_USAGE)
         }
     }
 }
 
-fn start_repl() {
+fn start_repl() -> i32 {
     let mut repl = repl::REPL::new();
     repl.run();
+    0
 }
 
 // TODO https://blog.subnetzero.io/post/building-language-vm-interlude-02/
@@ -0,0 +1,102 @@
+use super::debug_info::DebugInfo;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read};
+
+/// Marks the start of an assembled bytecode file.
+pub const BYTECODE_MAGIC: [u8; 4] = *b"IRBC";
+
+/// What `iridium assemble` writes out and `iridium run` loads back in.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Bytecode {
+    pub code: Vec<u8>,
+    /// Read-only data laid down by directives such as `.asciiz`.
+    pub ro: Vec<u8>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+impl Bytecode {
+    /// Whether `bytes` look like a bytecode file rather than source.
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(&BYTECODE_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut wtr = BYTECODE_MAGIC.to_vec();
+        wtr.write_u32::<LittleEndian>(self.code.len() as u32)
+            .unwrap();
+        wtr.extend_from_slice(&self.code);
+        wtr.write_u32::<LittleEndian>(self.ro.len() as u32).unwrap();
+        wtr.extend_from_slice(&self.ro);
+        if let Some(debug) = &self.debug_info {
+            wtr.extend_from_slice(&debug.to_bytes());
+        }
+        wtr
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Bytecode> {
+        let mut rdr = Cursor::new(bytes);
+        let mut magic = [0; 4];
+        rdr.read_exact(&mut magic).ok()?;
+        if magic != BYTECODE_MAGIC {
+            return None;
+        }
+        let code = read_section(&mut rdr)?;
+        let ro = read_section(&mut rdr)?;
+        let rest = &bytes[rdr.position() as usize..];
+        let debug_info = if rest.is_empty() {
+            None
+        } else {
+            Some(DebugInfo::from_bytes(rest)?)
+        };
+        Some(Bytecode {
+            code,
+            ro,
+            debug_info,
+        })
+    }
+}
+
+/// A length-prefixed run of bytes. The length is checked against what is
+/// left before anything is allocated for it.
+fn read_section(rdr: &mut Cursor<&[u8]>) -> Option<Vec<u8>> {
+    let len = rdr.read_u32::<LittleEndian>().ok()? as usize;
+    let start = rdr.position() as usize;
+    let section = rdr.get_ref().get(start..start.checked_add(len)?)?.to_vec();
+    rdr.set_position((start + len) as u64);
+    Some(section)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        let mut asm = Assembler::new().with_debug_info("hello.iasm");
+        let code = asm
+            .assemble(".data\nhello: .asciiz 'Hi'\n.code\nload $0 #1\nhlt")
+            .unwrap();
+        let bytecode = Bytecode {
+            code,
+            ro: asm.ro.clone(),
+            debug_info: asm.debug_info().cloned(),
+        };
+        let bytes = bytecode.to_bytes();
+        assert!(Bytecode::is_bytecode(&bytes));
+        assert_eq!(Bytecode::from_bytes(&bytes), Some(bytecode));
+    }
+
+    #[test]
+    fn test_bytecode_rejects_garbage() {
+        assert!(!Bytecode::is_bytecode(b"load $0 #1"));
+        assert_eq!(Bytecode::from_bytes(b"load $0 #1"), None);
+        let mut truncated = Bytecode::default().to_bytes();
+        truncated.pop();
+        assert_eq!(Bytecode::from_bytes(&truncated), None);
+        // Claims 4 GiB of code it does not have.
+        let mut huge = BYTECODE_MAGIC.to_vec();
+        huge.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 5]);
+        assert_eq!(Bytecode::from_bytes(&huge), None);
+    }
+}
@@ -0,0 +1,260 @@
+//! What nodes say to each other: one JSON object per line, one request and
+//! one reply per connection.
+
+use serde_json::{json, Value};
+use std::io;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Member {
+    pub id: String,
+    /// Where the node listens, e.g. `127.0.0.1:2254`.
+    pub addr: String,
+    /// False once the node has missed heartbeats for too long.
+    pub alive: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProcessState {
+    Running,
+    Halted,
+    Crashed(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProcessInfo {
+    pub pid: usize,
+    pub state: ProcessState,
+    pub registers: Vec<i32>,
+}
+
+/// Join, heartbeat and deploy messages carry the cluster secret, which a
+/// node that has one checks before doing anything else.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    /// Sent to a seed by a node joining the cluster; answered with `Members`.
+    Join {
+        id: String,
+        addr: String,
+        secret: Option<String>,
+    },
+    /// Sent to every known peer periodically; answered with `Members`.
+    Heartbeat {
+        id: String,
+        addr: String,
+        members: Vec<Member>,
+        secret: Option<String>,
+    },
+    /// Asks for the membership list.
+    ListMembers,
+    Members(Vec<Member>),
+    /// Starts `bytecode` on node `to`, or on the receiver if `to` is `None`.
+    /// A node passes a deploy for another on at most once, marking it
+    /// `forwarded`.
+    Deploy {
+        to: Option<String>,
+        bytecode: Vec<u8>,
+        secret: Option<String>,
+        forwarded: bool,
+    },
+    Deployed {
+        node: String,
+        pid: usize,
+    },
+    /// Asks after a process started on the receiver.
+    Status {
+        pid: usize,
+    },
+    Process(ProcessInfo),
+    Error(String),
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn member_json(m: &Member) -> Value {
+    json!({ "id": m.id, "addr": m.addr, "alive": m.alive })
+}
+
+fn members_json(members: &[Member]) -> Value {
+    Value::Array(members.iter().map(member_json).collect())
+}
+
+fn members_from(value: &Value) -> Option<Vec<Member>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|m| {
+            Some(Member {
+                id: m["id"].as_str()?.to_string(),
+                addr: m["addr"].as_str()?.to_string(),
+                alive: m["alive"].as_bool()?,
+            })
+        })
+        .collect()
+}
+
+fn string_from(value: &Value) -> Option<String> {
+    value.as_str().map(str::to_string)
+}
+
+impl Message {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Message::Join { id, addr, secret } => {
+                json!({ "type": "join", "id": id, "addr": addr, "secret": secret })
+            }
+            Message::Heartbeat {
+                id,
+                addr,
+                members,
+                secret,
+            } => json!({
+                "type": "heartbeat",
+                "id": id,
+                "addr": addr,
+                "members": members_json(members),
+                "secret": secret,
+            }),
+            Message::ListMembers => json!({ "type": "list_members" }),
+            Message::Members(members) => {
+                json!({ "type": "members", "members": members_json(members) })
+            }
+            Message::Deploy {
+                to,
+                bytecode,
+                secret,
+                forwarded,
+            } => json!({
+                "type": "deploy",
+                "to": to,
+                "bytecode": to_hex(bytecode),
+                "secret": secret,
+                "forwarded": forwarded,
+            }),
+            Message::Deployed { node, pid } => {
+                json!({ "type": "deployed", "node": node, "pid": pid })
+            }
+            Message::Status { pid } => json!({ "type": "status", "pid": pid }),
+            Message::Process(info) => {
+                let (state, error) = match &info.state {
+                    ProcessState::Running => ("running", None),
+                    ProcessState::Halted => ("halted", None),
+                    ProcessState::Crashed(e) => ("crashed", Some(e)),
+                };
+                json!({
+                    "type": "process",
+                    "pid": info.pid,
+                    "state": state,
+                    "error": error,
+                    "registers": info.registers,
+                })
+            }
+            Message::Error(message) => json!({ "type": "error", "message": message }),
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Option<Message> {
+        let pid = || value["pid"].as_u64().map(|p| p as usize);
+        Some(match value["type"].as_str()? {
+            "join" => Message::Join {
+                id: string_from(&value["id"])?,
+                addr: string_from(&value["addr"])?,
+                secret: string_from(&value["secret"]),
+            },
+            "heartbeat" => Message::Heartbeat {
+                id: string_from(&value["id"])?,
+                addr: string_from(&value["addr"])?,
+                members: members_from(&value["members"])?,
+                secret: string_from(&value["secret"]),
+            },
+            "list_members" => Message::ListMembers,
+            "members" => Message::Members(members_from(&value["members"])?),
+            "deploy" => Message::Deploy {
+                to: string_from(&value["to"]),
+                bytecode: from_hex(value["bytecode"].as_str()?)?,
+                secret: string_from(&value["secret"]),
+                forwarded: value["forwarded"].as_bool().unwrap_or(false),
+            },
+            "deployed" => Message::Deployed {
+                node: string_from(&value["node"])?,
+                pid: pid()?,
+            },
+            "status" => Message::Status { pid: pid()? },
+            "process" => Message::Process(ProcessInfo {
+                pid: pid()?,
+                state: match value["state"].as_str()? {
+                    "running" => ProcessState::Running,
+                    "halted" => ProcessState::Halted,
+                    "crashed" => ProcessState::Crashed(string_from(&value["error"])?),
+                    _ => return None,
+                },
+                registers: value["registers"]
+                    .as_array()?
+                    .iter()
+                    .map(|r| r.as_i64().map(|r| r as i32))
+                    .collect::<Option<_>>()?,
+            }),
+            "error" => Message::Error(string_from(&value["message"])?),
+            _ => return None,
+        })
+    }
+
+    /// The message as one line of JSON, newline included.
+    pub fn to_line(&self) -> String {
+        format!("{}\n", self.to_json())
+    }
+
+    pub fn from_line(line: &str) -> io::Result<Message> {
+        serde_json::from_str(line)
+            .ok()
+            .and_then(|value| Message::from_json(&value))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed cluster message"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let messages = vec![
+            Message::Heartbeat {
+                id: "a".to_string(),
+                addr: "127.0.0.1:1".to_string(),
+                members: vec![Member {
+                    id: "b".to_string(),
+                    addr: "127.0.0.1:2".to_string(),
+                    alive: false,
+                }],
+                secret: Some("s3cret".to_string()),
+            },
+            Message::Deploy {
+                to: None,
+                bytecode: vec![0x49, 0x00, 0xff],
+                secret: None,
+                forwarded: true,
+            },
+            Message::Process(ProcessInfo {
+                pid: 3,
+                state: ProcessState::Crashed("illegal opcode 200".to_string()),
+                registers: vec![-1, 2],
+            }),
+        ];
+        for message in messages {
+            assert_eq!(Message::from_line(&message.to_line()).unwrap(), message);
+        }
+        assert!(Message::from_line("{\"type\":\"deploy\",\"bytecode\":\"abc\"}").is_err());
+    }
+}
@@ -0,0 +1,635 @@
+//! Nodes that find each other, keep track of who is still up, and run
+//! bytecode on each other's behalf.
+//!
+//! A node joins by sending `Join` to a seed, which answers with everyone it
+//! knows. From then on each node sends every peer a `Heartbeat` carrying its
+//! own membership list, so news of new members spreads, and a peer that has
+//! not answered for `failure_timeout` is marked failed. Only direct contact
+//! brings a failed peer back; gossip about it is ignored.
+//!
+//! Deployed processes run under the node's `limits`, and a VM that panics
+//! leaves its process crashed rather than running. A node runs at most
+//! `max_processes` at once and turns further deploys away.
+//!
+//! Nodes given a `secret` only accept joins, heartbeats and deploys that
+//! carry the same one, and send it with their own.
+
+pub mod message;
+
+pub use self::message::{Member, Message, ProcessInfo, ProcessState};
+
+use crate::bytecode::Bytecode;
+use crate::remote::tokens_match;
+use crate::vm::{Limit, VM};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a request may take to connect, or to send or read a line.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest line a node reads, which bounds the bytecode a deploy can carry.
+pub const MAX_LINE_LEN: u64 = 16 * 1024 * 1024;
+
+/// How many instructions a deployed process may execute by default.
+pub const DEFAULT_INSTRUCTION_LIMIT: u64 = 1_000_000_000;
+
+/// How large a deployed process's heap may grow by default.
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
+
+/// How many deployed processes may run on a node at once by default.
+pub const DEFAULT_MAX_PROCESSES: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub id: String,
+    /// Address of any node already in the cluster.
+    pub seed: Option<String>,
+    pub heartbeat_interval: Duration,
+    /// How long a peer may go unheard from before it is marked failed.
+    pub failure_timeout: Duration,
+    /// Set on the VM of every process deployed here.
+    pub limits: Vec<Limit>,
+    pub max_processes: usize,
+    /// Shared by every node in the cluster and by whoever deploys to it.
+    pub secret: Option<String>,
+}
+
+impl NodeConfig {
+    pub fn new(id: &str) -> NodeConfig {
+        NodeConfig {
+            id: id.to_string(),
+            seed: None,
+            heartbeat_interval: Duration::from_secs(1),
+            failure_timeout: Duration::from_secs(3),
+            limits: vec![
+                Limit::Instructions(DEFAULT_INSTRUCTION_LIMIT),
+                Limit::Heap(DEFAULT_HEAP_LIMIT),
+            ],
+            max_processes: DEFAULT_MAX_PROCESSES,
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    addr: String,
+    last_seen: Instant,
+    alive: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    peers: BTreeMap<String, Peer>,
+    /// Processes started here, indexed by pid.
+    processes: Vec<ProcessInfo>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    config: NodeConfig,
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    stopped: AtomicBool,
+}
+
+/// A running node. Clones are handles to the same node.
+#[derive(Debug, Clone)]
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+/// Sends `message` to the node at `addr` and waits for its reply.
+pub fn request(addr: &str, message: &Message) -> io::Result<Message> {
+    request_within(addr, message, REQUEST_TIMEOUT)
+}
+
+/// `request`, with `timeout` to connect and for each read and write.
+fn request_within(addr: &str, message: &Message, timeout: Duration) -> io::Result<Message> {
+    let target = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolves to nothing")
+    })?;
+    let mut stream = TcpStream::connect_timeout(&target, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(message.to_line().as_bytes())?;
+    Message::from_line(&read_line(&stream)?)
+}
+
+/// Reads one line, refusing any longer than `MAX_LINE_LEN`.
+fn read_line(stream: &TcpStream) -> io::Result<String> {
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_LINE_LEN)).read_line(&mut line)?;
+    if line.len() as u64 == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line longer than {} bytes", MAX_LINE_LEN),
+        ));
+    }
+    Ok(line)
+}
+
+/// A process that panicked while holding the lock must not stop the node.
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Node {
+    /// Listens on `addr` (port 0 picks a free one) and, if `config` names a
+    /// seed, joins the seed's cluster before returning.
+    pub fn start(addr: &str, config: NodeConfig) -> io::Result<Node> {
+        let listener = TcpListener::bind(addr)?;
+        let node = Node {
+            shared: Arc::new(Shared {
+                addr: listener.local_addr()?,
+                config,
+                state: Arc::new(Mutex::new(State::default())),
+                stopped: AtomicBool::new(false),
+            }),
+        };
+
+        if let Some(seed) = &node.shared.config.seed {
+            let join = Message::Join {
+                id: node.id().to_string(),
+                addr: node.addr().to_string(),
+                secret: node.shared.config.secret.clone(),
+            };
+            match request(seed, &join)? {
+                Message::Members(members) => node.shared.merge(&members),
+                reply => return Err(unexpected(reply)),
+            }
+        }
+
+        let shared = Arc::clone(&node.shared);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if shared.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let shared = Arc::clone(&shared);
+                    // A peer hanging up mid-request is its own problem.
+                    thread::spawn(move || shared.serve(stream));
+                }
+            }
+        });
+
+        let shared = Arc::clone(&node.shared);
+        thread::spawn(move || loop {
+            thread::sleep(shared.config.heartbeat_interval);
+            if shared.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            shared.heartbeat();
+        });
+
+        Ok(node)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.shared.config.id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.shared.addr
+    }
+
+    /// This node followed by every peer it knows of, failed ones included.
+    pub fn members(&self) -> Vec<Member> {
+        self.shared.members()
+    }
+
+    /// Starts `bytecode` on node `to`, which may be this one. Returns the pid
+    /// of the new process there.
+    pub fn deploy(&self, to: &str, bytecode: &Bytecode) -> io::Result<usize> {
+        let deploy = Message::Deploy {
+            to: Some(to.to_string()),
+            bytecode: bytecode.to_bytes(),
+            secret: self.shared.config.secret.clone(),
+            forwarded: false,
+        };
+        match self.shared.handle(deploy) {
+            Message::Deployed { pid, .. } => Ok(pid),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// A process started on this node.
+    pub fn process(&self, pid: usize) -> Option<ProcessInfo> {
+        lock(&self.shared.state).processes.get(pid).cloned()
+    }
+
+    /// Stops answering and sending heartbeats, so peers see the node fail.
+    /// Processes already running carry on.
+    pub fn shutdown(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // Wakes the accept loop so it notices.
+        let _ = TcpStream::connect(self.shared.addr);
+    }
+}
+
+/// A reply that is an error, or not what the request calls for.
+fn unexpected(reply: Message) -> io::Error {
+    match reply {
+        Message::Error(message) => io::Error::other(message),
+        reply => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply {}", reply.to_json()),
+        ),
+    }
+}
+
+impl Shared {
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let reply = match read_line(&stream).and_then(|line| Message::from_line(&line)) {
+            Ok(message) => self.handle(message),
+            Err(e) => Message::Error(e.to_string()),
+        };
+        let mut writer = stream;
+        writer.write_all(reply.to_line().as_bytes())
+    }
+
+    fn handle(&self, message: Message) -> Message {
+        let secret = match &message {
+            Message::Join { secret, .. }
+            | Message::Heartbeat { secret, .. }
+            | Message::Deploy { secret, .. } => Some(secret.as_deref()),
+            _ => None,
+        };
+        if secret.is_some_and(|given| !self.admits(given)) {
+            return Message::Error("wrong cluster secret".to_string());
+        }
+
+        match message {
+            Message::Join { id, addr, .. } => {
+                self.saw(&id, &addr);
+                Message::Members(self.members())
+            }
+            Message::Heartbeat {
+                id, addr, members, ..
+            } => {
+                self.saw(&id, &addr);
+                self.merge(&members);
+                Message::Members(self.members())
+            }
+            Message::ListMembers => Message::Members(self.members()),
+            Message::Deploy {
+                to,
+                bytecode,
+                forwarded,
+                ..
+            } => match to {
+                // Whoever sent it on had `to` at this node's address, so
+                // passing it on again could go round in circles.
+                Some(to) if to != self.config.id && forwarded => Message::Error(format!(
+                    "node {} is not {}, and the deploy was already forwarded",
+                    self.config.id, to
+                )),
+                Some(to) if to != self.config.id => self.forward(&to, bytecode),
+                _ => self.spawn(&bytecode),
+            },
+            Message::Status { pid } => match lock(&self.state).processes.get(pid) {
+                Some(info) => Message::Process(info.clone()),
+                None => Message::Error(format!("no process {}", pid)),
+            },
+            message => Message::Error(format!("cannot handle {}", message.to_json())),
+        }
+    }
+
+    /// Whether a message carrying `given` may be acted on.
+    fn admits(&self, given: Option<&str>) -> bool {
+        match (&self.config.secret, given) {
+            (None, _) => true,
+            (Some(secret), Some(given)) => tokens_match(given, secret),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn members(&self) -> Vec<Member> {
+        let mut members = vec![Member {
+            id: self.config.id.clone(),
+            addr: self.addr.to_string(),
+            alive: true,
+        }];
+        members.extend(lock(&self.state).peers.iter().map(|(id, peer)| Member {
+            id: id.clone(),
+            addr: peer.addr.clone(),
+            alive: peer.alive,
+        }));
+        members
+    }
+
+    /// Heard from `id` directly, so it is up.
+    fn saw(&self, id: &str, addr: &str) {
+        let peer = Peer {
+            addr: addr.to_string(),
+            last_seen: Instant::now(),
+            alive: true,
+        };
+        lock(&self.state).peers.insert(id.to_string(), peer);
+    }
+
+    /// Takes in live members some other node knows of and this one does not.
+    fn merge(&self, members: &[Member]) {
+        let mut state = lock(&self.state);
+        for member in members {
+            if member.alive && member.id != self.config.id && !state.peers.contains_key(&member.id)
+            {
+                state.peers.insert(
+                    member.id.clone(),
+                    Peer {
+                        addr: member.addr.clone(),
+                        last_seen: Instant::now(),
+                        alive: true,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Sends a heartbeat to every peer at once, failed ones too in case they
+    /// are back, then marks those gone quiet for too long as failed. A peer
+    /// gets one heartbeat interval to answer, so a slow one cannot hold up
+    /// the round.
+    fn heartbeat(&self) {
+        let peers: Vec<(String, String)> = lock(&self.state)
+            .peers
+            .iter()
+            .map(|(id, peer)| (id.clone(), peer.addr.clone()))
+            .collect();
+        let beat = Message::Heartbeat {
+            id: self.config.id.clone(),
+            addr: self.addr.to_string(),
+            members: self.members(),
+            secret: self.config.secret.clone(),
+        };
+        let timeout = self.config.heartbeat_interval;
+        thread::scope(|scope| {
+            for (id, addr) in &peers {
+                let beat = &beat;
+                scope.spawn(move || {
+                    if let Ok(Message::Members(members)) = request_within(addr, beat, timeout) {
+                        self.saw(id, addr);
+                        self.merge(&members);
+                    }
+                });
+            }
+        });
+
+        let timeout = self.config.failure_timeout;
+        for peer in lock(&self.state).peers.values_mut() {
+            if peer.last_seen.elapsed() > timeout {
+                peer.alive = false;
+            }
+        }
+    }
+
+    fn forward(&self, to: &str, bytecode: Vec<u8>) -> Message {
+        let addr = match lock(&self.state).peers.get(to) {
+            Some(peer) if peer.alive => peer.addr.clone(),
+            Some(_) => return Message::Error(format!("node {} has failed", to)),
+            None => return Message::Error(format!("no node {}", to)),
+        };
+        let deploy = Message::Deploy {
+            to: Some(to.to_string()),
+            bytecode,
+            secret: self.config.secret.clone(),
+            forwarded: true,
+        };
+        request(&addr, &deploy).unwrap_or_else(|e| Message::Error(format!("node {}: {}", to, e)))
+    }
+
+    /// Runs `bytes` on a VM of its own, on a thread of its own.
+    fn spawn(&self, bytes: &[u8]) -> Message {
+        let bytecode = match Bytecode::from_bytes(bytes) {
+            Some(bytecode) => bytecode,
+            None => return Message::Error("not a bytecode file".to_string()),
+        };
+        let pid = {
+            let mut state = lock(&self.state);
+            let running = state
+                .processes
+                .iter()
+                .filter(|p| p.state == ProcessState::Running)
+                .count();
+            if running >= self.config.max_processes {
+                return Message::Error(format!(
+                    "node {} is already running {} processes",
+                    self.config.id, running
+                ));
+            }
+            let pid = state.processes.len();
+            state.processes.push(ProcessInfo {
+                pid,
+                state: ProcessState::Running,
+                registers: vec![],
+            });
+            pid
+        };
+
+        let mut vm = VM::new();
+        for limit in &self.config.limits {
+            vm.set_limit(*limit);
+        }
+        if let Some(debug) = bytecode.debug_info {
+            vm.set_debug_info(debug);
+        }
+        vm.add_bytes(bytecode.code);
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || {
+            let ran = panic::catch_unwind(AssertUnwindSafe(|| vm.run()));
+            let mut state = lock(&state);
+            let process = &mut state.processes[pid];
+            process.state = match (ran, vm.error()) {
+                (Err(cause), _) => {
+                    ProcessState::Crashed(format!("panicked: {}", panic_message(&*cause)))
+                }
+                (Ok(_), Some(e)) => ProcessState::Crashed(e.render(vm.debug_info())),
+                (Ok(_), None) => ProcessState::Halted,
+            };
+            process.registers = vm.registers.to_vec();
+        });
+        Message::Deployed {
+            node: self.config.id.clone(),
+            pid,
+        }
+    }
+}
+
+/// What a panic was raised with, when it was raised with a message.
+fn panic_message(cause: &(dyn std::any::Any + Send)) -> &str {
+    match cause.downcast_ref::<&str>() {
+        Some(s) => s,
+        None => cause
+            .downcast_ref::<String>()
+            .map_or("unknown cause", |s| s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn config(id: &str, seed: Option<&Node>) -> NodeConfig {
+        NodeConfig {
+            seed: seed.map(|n| n.addr().to_string()),
+            heartbeat_interval: Duration::from_millis(50),
+            failure_timeout: Duration::from_millis(300),
+            ..NodeConfig::new(id)
+        }
+    }
+
+    /// Polls `done` for a few seconds.
+    fn eventually(mut done: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    fn alive(node: &Node) -> Vec<String> {
+        node.members()
+            .into_iter()
+            .filter(|m| m.alive)
+            .map(|m| m.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_membership() {
+        let a = Node::start("127.0.0.1:0", config("a", None)).unwrap();
+        let b = Node::start("127.0.0.1:0", config("b", Some(&a))).unwrap();
+        assert_eq!(alive(&b), vec!["b", "a"]);
+        // `a` only hears of `c` through `b`.
+        let c = Node::start("127.0.0.1:0", config("c", Some(&b))).unwrap();
+        assert!(eventually(|| alive(&a) == vec!["a", "b", "c"]));
+
+        c.shutdown();
+        assert!(eventually(|| alive(&a) == vec!["a", "b"]));
+        assert!(eventually(|| alive(&b) == vec!["b", "a"]));
+        assert_eq!(a.members().len(), 3);
+    }
+
+    #[test]
+    fn test_deploy() {
+        let a = Node::start("127.0.0.1:0", config("a", None)).unwrap();
+        let b = Node::start("127.0.0.1:0", config("b", Some(&a))).unwrap();
+        let bytecode = Bytecode {
+            code: Assembler::new().assemble("load $0 #42\nhlt").unwrap(),
+            ..Bytecode::default()
+        };
+
+        let pid = a.deploy("b", &bytecode).unwrap();
+        assert!(eventually(|| b
+            .process(pid)
+            .is_some_and(|p| p.state == ProcessState::Halted)));
+        assert_eq!(b.process(pid).unwrap().registers[0], 42);
+        assert_eq!(a.process(pid), None);
+
+        let status = request(&b.addr().to_string(), &Message::Status { pid }).unwrap();
+        assert!(matches!(status, Message::Process(p) if p.registers[0] == 42));
+
+        let err = a.deploy("z", &bytecode).unwrap_err();
+        assert_eq!(err.to_string(), "no node z");
+    }
+
+    #[test]
+    fn test_secret() {
+        let secret = |id: &str, seed: Option<&Node>, secret: &str| NodeConfig {
+            secret: Some(secret.to_string()),
+            ..config(id, seed)
+        };
+        let a = Node::start("127.0.0.1:0", secret("a", None, "s3cret")).unwrap();
+        let b = Node::start("127.0.0.1:0", secret("b", Some(&a), "s3cret")).unwrap();
+        assert_eq!(alive(&b), vec!["b", "a"]);
+
+        let err = Node::start("127.0.0.1:0", secret("c", Some(&a), "guess")).unwrap_err();
+        assert_eq!(err.to_string(), "wrong cluster secret");
+        let err = Node::start("127.0.0.1:0", config("c", Some(&a))).unwrap_err();
+        assert_eq!(err.to_string(), "wrong cluster secret");
+
+        let deploy = Message::Deploy {
+            to: None,
+            bytecode: Bytecode::default().to_bytes(),
+            secret: None,
+            forwarded: false,
+        };
+        assert_eq!(
+            request(&b.addr().to_string(), &deploy).unwrap(),
+            Message::Error("wrong cluster secret".to_string())
+        );
+        assert!(eventually(|| alive(&a) == vec!["a", "b"]));
+    }
+
+    #[test]
+    fn test_forward_once() {
+        let a = Node::start("127.0.0.1:0", config("a", None)).unwrap();
+        // As a node would send it on if it had `b` at `a`'s address.
+        let deploy = Message::Deploy {
+            to: Some("b".to_string()),
+            bytecode: Bytecode::default().to_bytes(),
+            secret: None,
+            forwarded: true,
+        };
+        assert_eq!(
+            request(&a.addr().to_string(), &deploy).unwrap(),
+            Message::Error("node a is not b, and the deploy was already forwarded".to_string())
+        );
+    }
+
+    #[test]
+    fn test_max_processes() {
+        let a = Node::start(
+            "127.0.0.1:0",
+            NodeConfig {
+                max_processes: 2,
+                ..config("a", None)
+            },
+        )
+        .unwrap();
+        let assemble = |source| Bytecode {
+            code: Assembler::new().assemble(source).unwrap(),
+            ..Bytecode::default()
+        };
+        let spin = assemble("spin: jmp @spin");
+        a.deploy("a", &spin).unwrap();
+        a.deploy("a", &spin).unwrap();
+        let err = a.deploy("a", &spin).unwrap_err();
+        assert_eq!(err.to_string(), "node a is already running 2 processes");
+    }
+
+    #[test]
+    fn test_deploy_limits() {
+        let a = Node::start(
+            "127.0.0.1:0",
+            NodeConfig {
+                limits: vec![Limit::Instructions(100)],
+                ..config("a", None)
+            },
+        )
+        .unwrap();
+        let spin = Bytecode {
+            code: Assembler::new().assemble("spin: jmp @spin").unwrap(),
+            ..Bytecode::default()
+        };
+        let pid = a.deploy("a", &spin).unwrap();
+        assert!(eventually(|| a.process(pid).unwrap().state
+            == ProcessState::Crashed(
+                "limit of 100 instructions reached at pc 0".to_string()
+            )));
+    }
+}
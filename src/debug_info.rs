@@ -0,0 +1,228 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{Cursor, Read};
+
+/// Marks the start of a serialized debug-info section.
+pub const DEBUG_INFO_MAGIC: [u8; 4] = *b"IRDI";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineEntry {
+    pub offset: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Maps code offsets back to the source they were assembled from.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DebugInfo {
+    pub file: String,
+    /// One entry per instruction, ordered by offset.
+    pub lines: Vec<LineEntry>,
+    /// Code labels and the offsets they were declared at, ordered by offset.
+    pub labels: Vec<(String, u32)>,
+}
+
+/// A resolved position in the source, e.g. `loop.iasm:12:3 (in label `test`)`.
+#[derive(Debug, PartialEq)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    pub column: u32,
+    pub label: Option<&'a str>,
+}
+
+impl<'a> fmt::Display for SourceLocation<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)?;
+        if let Some(label) = self.label {
+            write!(f, " (in label `{}`)", label)?;
+        }
+        Ok(())
+    }
+}
+
+impl DebugInfo {
+    pub fn new(file: &str) -> DebugInfo {
+        DebugInfo {
+            file: file.to_string(),
+            ..DebugInfo::default()
+        }
+    }
+
+    /// Records that the instruction at `offset` came from byte `start` of `source`.
+    pub fn add_line(&mut self, offset: u32, source: &str, start: usize) {
+        let (line, column) = line_column(source, start);
+        self.lines.push(LineEntry {
+            offset,
+            line,
+            column,
+        });
+    }
+
+    pub fn add_label(&mut self, name: &str, offset: u32) {
+        self.labels.push((name.to_string(), offset));
+    }
+
+    /// Shifts every offset by `base`, for code that is loaded after other code.
+    pub fn relocate(&mut self, base: u32) {
+        self.lines.iter_mut().for_each(|l| l.offset += base);
+        self.labels
+            .iter_mut()
+            .for_each(|(_, offset)| *offset += base);
+    }
+
+    /// The source location of the instruction containing `pc`.
+    pub fn location(&self, pc: usize) -> Option<SourceLocation<'_>> {
+        let pc = pc as u32;
+        let entry = self.lines.iter().rev().find(|l| l.offset <= pc)?;
+        let label = self
+            .labels
+            .iter()
+            .rev()
+            .find(|(_, offset)| *offset <= pc)
+            .map(|(name, _)| name.as_str());
+        Some(SourceLocation {
+            file: &self.file,
+            line: entry.line,
+            column: entry.column,
+            label,
+        })
+    }
+
+    /// Renders `pc` as a source location, falling back to the raw offset.
+    pub fn describe(debug: Option<&DebugInfo>, pc: usize) -> String {
+        match debug.and_then(|d| d.location(pc)) {
+            Some(location) => format!("at {}", location),
+            None => format!("at pc {}", pc),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut wtr = DEBUG_INFO_MAGIC.to_vec();
+        write_str(&mut wtr, &self.file);
+        wtr.write_u32::<LittleEndian>(self.lines.len() as u32)
+            .unwrap();
+        for l in &self.lines {
+            wtr.write_u32::<LittleEndian>(l.offset).unwrap();
+            wtr.write_u32::<LittleEndian>(l.line).unwrap();
+            wtr.write_u32::<LittleEndian>(l.column).unwrap();
+        }
+        wtr.write_u32::<LittleEndian>(self.labels.len() as u32)
+            .unwrap();
+        for (name, offset) in &self.labels {
+            write_str(&mut wtr, name);
+            wtr.write_u32::<LittleEndian>(*offset).unwrap();
+        }
+        wtr
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<DebugInfo> {
+        let mut rdr = Cursor::new(bytes);
+        let mut magic = [0; 4];
+        rdr.read_exact(&mut magic).ok()?;
+        if magic != DEBUG_INFO_MAGIC {
+            return None;
+        }
+        let mut debug = DebugInfo::new(&read_str(&mut rdr)?);
+        for _ in 0..rdr.read_u32::<LittleEndian>().ok()? {
+            debug.lines.push(LineEntry {
+                offset: rdr.read_u32::<LittleEndian>().ok()?,
+                line: rdr.read_u32::<LittleEndian>().ok()?,
+                column: rdr.read_u32::<LittleEndian>().ok()?,
+            });
+        }
+        for _ in 0..rdr.read_u32::<LittleEndian>().ok()? {
+            let name = read_str(&mut rdr)?;
+            debug
+                .labels
+                .push((name, rdr.read_u32::<LittleEndian>().ok()?));
+        }
+        Some(debug)
+    }
+}
+
+/// 1-based line and column of byte `start` in `source`.
+pub fn line_column(source: &str, start: usize) -> (u32, u32) {
+    let before = &source[..start];
+    let line = before.matches('\n').count() + 1;
+    let column = match before.rfind('\n') {
+        Some(newline) => start - newline,
+        None => start + 1,
+    };
+    (line as u32, column as u32)
+}
+
+fn write_str(wtr: &mut Vec<u8>, s: &str) {
+    wtr.write_u32::<LittleEndian>(s.len() as u32).unwrap();
+    wtr.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(rdr: &mut Cursor<&[u8]>) -> Option<String> {
+    let len = rdr.read_u32::<LittleEndian>().ok()? as usize;
+    let start = rdr.position() as usize;
+    let bytes = rdr.get_ref().get(start..start.checked_add(len)?)?;
+    rdr.set_position((start + len) as u64);
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_debug_info() -> DebugInfo {
+        let source = "load $0 #1\ntest: inc $0\n  hlt";
+        let mut debug = DebugInfo::new("loop.iasm");
+        debug.add_line(0, source, 0);
+        debug.add_line(4, source, 11);
+        debug.add_label("test", 4);
+        debug.add_line(6, source, 26);
+        debug
+    }
+
+    #[test]
+    fn test_line_column() {
+        assert_eq!(line_column("load $0 #1\ninc $0", 0), (1, 1));
+        assert_eq!(line_column("load $0 #1\ninc $0", 11), (2, 1));
+        assert_eq!(line_column("load $0 #1\n  inc $0", 13), (2, 3));
+    }
+
+    #[test]
+    fn test_location() {
+        let debug = test_debug_info();
+        assert_eq!(debug.location(0).unwrap().to_string(), "loop.iasm:1:1");
+        assert_eq!(
+            debug.location(5).unwrap().to_string(),
+            "loop.iasm:2:1 (in label `test`)"
+        );
+        assert_eq!(
+            DebugInfo::describe(Some(&debug), 6),
+            "at loop.iasm:3:3 (in label `test`)"
+        );
+        assert_eq!(DebugInfo::describe(None, 6), "at pc 6");
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let debug = test_debug_info();
+        let bytes = debug.to_bytes();
+        assert_eq!(&bytes[..4], &DEBUG_INFO_MAGIC);
+        assert_eq!(DebugInfo::from_bytes(&bytes), Some(debug));
+        assert_eq!(DebugInfo::from_bytes(&[0, 1, 2]), None);
+
+        // Names longer than a u16 survive, and a length past the end fails.
+        let long = DebugInfo::new(&"x".repeat(70_000));
+        assert_eq!(DebugInfo::from_bytes(&long.to_bytes()), Some(long));
+        assert_eq!(DebugInfo::from_bytes(&bytes[..bytes.len() - 1]), None);
+        let mut huge = DEBUG_INFO_MAGIC.to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(DebugInfo::from_bytes(&huge), None);
+    }
+
+    #[test]
+    fn test_relocate() {
+        let mut debug = test_debug_info();
+        debug.relocate(100);
+        assert_eq!(debug.location(99), None);
+        assert_eq!(debug.location(104).unwrap().line, 2);
+    }
+}
@@ -18,11 +18,391 @@ pub enum Opcode {
     LT,
     GT,
     JMPE,
-    ALOC,
+    // 16 is unassigned; encodings follow `match_opcode`.
+    ALOC = 17,
     INC,
+    JMPO,
+    REM,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    SAR,
+    ROL,
+    ROR,
+    JMPNE,
+    BEQ,
+    BNE,
+    BLT,
+    BLE,
+    BGT,
+    BGE,
+    ADDI,
+    SUBI,
+    MULI,
+    CMPI,
+    LOAD32,
+    CALL,
+    RET,
+    PUSH,
+    POP,
     IGL,
 }
 
+/// How an instruction uses one of its operand slots when it holds a register.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandRole {
+    Unused,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Operand bytes following an opcode, as the VM reads them.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Slot {
+    /// One byte naming a register.
+    Register,
+    /// A 16-bit big-endian value, signed for `Opcode::signed_immediate`.
+    Immediate,
+    /// A 32-bit big-endian signed value.
+    Wide,
+    /// A `JumpMode` byte, then a 16-bit value or a register byte by mode.
+    Target,
+}
+
+/// How a jump names where it goes, chosen by the assembler from the operand:
+/// `#offset`, `@label` or `$r`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JumpMode {
+    /// An unsigned 16-bit code offset.
+    Absolute = 0,
+    /// A signed 16-bit displacement from the start of the jump instruction.
+    Relative = 1,
+    /// The code offset held in a register.
+    Register = 2,
+}
+
+impl JumpMode {
+    pub fn from_u8(byte: u8) -> Option<JumpMode> {
+        match byte {
+            0 => Some(JumpMode::Absolute),
+            1 => Some(JumpMode::Relative),
+            2 => Some(JumpMode::Register),
+            _ => None,
+        }
+    }
+}
+
+/// Every opcode the assembler accepts, in encoding order.
+pub const OPCODES: [Opcode; 45] = [
+    Opcode::LOAD,
+    Opcode::ADD,
+    Opcode::SUB,
+    Opcode::MUL,
+    Opcode::DIV,
+    Opcode::HLT,
+    Opcode::JMP,
+    Opcode::JMPF,
+    Opcode::JMPB,
+    Opcode::EQ,
+    Opcode::NEQ,
+    Opcode::GTE,
+    Opcode::LTE,
+    Opcode::LT,
+    Opcode::GT,
+    Opcode::JMPE,
+    Opcode::ALOC,
+    Opcode::INC,
+    Opcode::JMPO,
+    Opcode::REM,
+    Opcode::AND,
+    Opcode::OR,
+    Opcode::XOR,
+    Opcode::NOT,
+    Opcode::SHL,
+    Opcode::SHR,
+    Opcode::SAR,
+    Opcode::ROL,
+    Opcode::ROR,
+    Opcode::JMPNE,
+    Opcode::BEQ,
+    Opcode::BNE,
+    Opcode::BLT,
+    Opcode::BLE,
+    Opcode::BGT,
+    Opcode::BGE,
+    Opcode::ADDI,
+    Opcode::SUBI,
+    Opcode::MULI,
+    Opcode::CMPI,
+    Opcode::LOAD32,
+    Opcode::CALL,
+    Opcode::RET,
+    Opcode::PUSH,
+    Opcode::POP,
+];
+
+impl Opcode {
+    /// The assembler mnemonic, e.g. `load`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Opcode::LOAD => "load",
+            Opcode::ADD => "add",
+            Opcode::SUB => "sub",
+            Opcode::MUL => "mul",
+            Opcode::DIV => "div",
+            Opcode::HLT => "hlt",
+            Opcode::JMP => "jmp",
+            Opcode::JMPF => "jmpf",
+            Opcode::JMPB => "jmpb",
+            Opcode::EQ => "eq",
+            Opcode::NEQ => "neq",
+            Opcode::GTE => "gte",
+            Opcode::LTE => "lte",
+            Opcode::LT => "lt",
+            Opcode::GT => "gt",
+            Opcode::JMPE => "jmpe",
+            Opcode::ALOC => "aloc",
+            Opcode::INC => "inc",
+            Opcode::JMPO => "jmpo",
+            Opcode::REM => "rem",
+            Opcode::AND => "and",
+            Opcode::OR => "or",
+            Opcode::XOR => "xor",
+            Opcode::NOT => "not",
+            Opcode::SHL => "shl",
+            Opcode::SHR => "shr",
+            Opcode::SAR => "sar",
+            Opcode::ROL => "rol",
+            Opcode::ROR => "ror",
+            Opcode::JMPNE => "jmpne",
+            Opcode::BEQ => "beq",
+            Opcode::BNE => "bne",
+            Opcode::BLT => "blt",
+            Opcode::BLE => "ble",
+            Opcode::BGT => "bgt",
+            Opcode::BGE => "bge",
+            Opcode::ADDI => "addi",
+            Opcode::SUBI => "subi",
+            Opcode::MULI => "muli",
+            Opcode::CMPI => "cmpi",
+            Opcode::LOAD32 => "load32",
+            Opcode::CALL => "call",
+            Opcode::RET => "ret",
+            Opcode::PUSH => "push",
+            Opcode::POP => "pop",
+            Opcode::IGL => "igl",
+        }
+    }
+
+    /// Operand syntax and a one-line description, for editors and help text.
+    pub fn summary(self) -> (&'static str, &'static str) {
+        match self {
+            Opcode::LOAD => (
+                "load $r #imm",
+                "Load an immediate into $r, as load32 if it needs more than 16 bits",
+            ),
+            Opcode::ADD => ("add $a $b $d", "$d = $a + $b"),
+            Opcode::SUB => ("sub $a $b $d", "$d = $a - $b"),
+            Opcode::MUL => ("mul $a $b $d", "$d = $a * $b"),
+            Opcode::DIV => (
+                "div $a $b $d",
+                "$d = $a / $b, keeping the remainder; traps on zero",
+            ),
+            Opcode::HLT => ("hlt", "Stop execution"),
+            Opcode::JMP => ("jmp @label|#offset|$r", "Jump to the label or offset"),
+            Opcode::JMPF => ("jmpf $r", "Jump forward by $r bytes"),
+            Opcode::JMPB => ("jmpb $r", "Jump backward by $r bytes"),
+            Opcode::EQ => ("eq $a $b", "Set the equal flag if $a == $b"),
+            Opcode::NEQ => ("neq $a $b", "Set the equal flag if $a != $b"),
+            Opcode::GTE => ("gte $a $b", "Set the equal flag if $a >= $b"),
+            Opcode::LTE => ("lte $a $b", "Set the equal flag if $a <= $b"),
+            Opcode::LT => ("lt $a $b", "Set the equal flag if $a < $b"),
+            Opcode::GT => ("gt $a $b", "Set the equal flag if $a > $b"),
+            Opcode::JMPE => ("jmpe @label|#offset|$r", "Jump if the equal flag is set"),
+            Opcode::ALOC => ("aloc $r", "Grow the heap by $r bytes"),
+            Opcode::INC => ("inc $r", "Increment $r by one"),
+            Opcode::JMPO => ("jmpo @label|#offset|$r", "Jump if the overflow flag is set"),
+            Opcode::REM => ("rem $r", "Load the remainder of the last DIV into $r"),
+            Opcode::AND => ("and $a $b $d", "$d = $a & $b"),
+            Opcode::OR => ("or $a $b $d", "$d = $a | $b"),
+            Opcode::XOR => ("xor $a $b $d", "$d = $a ^ $b"),
+            Opcode::NOT => ("not $a $d", "$d = !$a"),
+            Opcode::SHL => ("shl $a $b $d", "$d = $a << $b, 0 once $b >= 32"),
+            Opcode::SHR => (
+                "shr $a $b $d",
+                "$d = $a >> $b filling with zeros, 0 once $b >= 32",
+            ),
+            Opcode::SAR => (
+                "sar $a $b $d",
+                "$d = $a >> $b filling with the sign bit, all sign once $b >= 32",
+            ),
+            Opcode::ROL => ("rol $a $b $d", "$d = $a rotated left by $b mod 32"),
+            Opcode::ROR => ("ror $a $b $d", "$d = $a rotated right by $b mod 32"),
+            Opcode::JMPNE => ("jmpne @label|#offset|$r", "Jump if the equal flag is clear"),
+            Opcode::BEQ => ("beq $a $b @label|#offset|$r", "Jump if $a == $b"),
+            Opcode::BNE => ("bne $a $b @label|#offset|$r", "Jump if $a != $b"),
+            Opcode::BLT => ("blt $a $b @label|#offset|$r", "Jump if $a < $b"),
+            Opcode::BLE => ("ble $a $b @label|#offset|$r", "Jump if $a <= $b"),
+            Opcode::BGT => ("bgt $a $b @label|#offset|$r", "Jump if $a > $b"),
+            Opcode::BGE => ("bge $a $b @label|#offset|$r", "Jump if $a >= $b"),
+            Opcode::ADDI => (
+                "addi $a $d #imm",
+                "$d = $a + imm, a signed 16-bit immediate",
+            ),
+            Opcode::SUBI => (
+                "subi $a $d #imm",
+                "$d = $a - imm, a signed 16-bit immediate",
+            ),
+            Opcode::MULI => (
+                "muli $a $d #imm",
+                "$d = $a * imm, a signed 16-bit immediate",
+            ),
+            Opcode::CMPI => ("cmpi $a #imm", "Set the equal flag if $a == imm"),
+            Opcode::LOAD32 => ("load32 $r #imm", "Load a 32-bit signed immediate into $r"),
+            Opcode::CALL => (
+                "call @label|#offset|$r",
+                "Put the return offset in $ra and jump",
+            ),
+            Opcode::RET => ("ret", "Jump to the offset in $ra"),
+            Opcode::PUSH => (
+                "push $r",
+                "Store $r below $sp on the heap and lower $sp by 4",
+            ),
+            Opcode::POP => ("pop $r", "Load $r from $sp on the heap and raise $sp by 4"),
+            Opcode::IGL => ("igl", "Illegal instruction"),
+        }
+    }
+
+    /// The operands the VM reads after the opcode, in order.
+    pub fn layout(self) -> &'static [Slot] {
+        match self {
+            Opcode::LOAD | Opcode::CMPI => &[Slot::Register, Slot::Immediate],
+            Opcode::LOAD32 => &[Slot::Register, Slot::Wide],
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => {
+                &[Slot::Register, Slot::Register, Slot::Immediate]
+            }
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::ROL
+            | Opcode::ROR => &[Slot::Register, Slot::Register, Slot::Register],
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GTE
+            | Opcode::LTE
+            | Opcode::LT
+            | Opcode::GT
+            | Opcode::NOT => &[Slot::Register, Slot::Register],
+            Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::ALOC
+            | Opcode::INC
+            | Opcode::REM
+            | Opcode::PUSH
+            | Opcode::POP => &[Slot::Register],
+            Opcode::JMP | Opcode::JMPE | Opcode::JMPO | Opcode::JMPNE | Opcode::CALL => {
+                &[Slot::Target]
+            }
+            Opcode::BEQ | Opcode::BNE | Opcode::BLT | Opcode::BLE | Opcode::BGT | Opcode::BGE => {
+                &[Slot::Register, Slot::Register, Slot::Target]
+            }
+            Opcode::HLT | Opcode::RET | Opcode::IGL => &[],
+        }
+    }
+
+    /// Zero bytes the VM skips after the operands, which pad the comparisons
+    /// and `inc` out to four bytes.
+    pub fn padding(self) -> u32 {
+        match self {
+            Opcode::EQ | Opcode::NEQ | Opcode::GTE | Opcode::LTE | Opcode::LT | Opcode::GT => 1,
+            Opcode::INC => 2,
+            _ => 0,
+        }
+    }
+
+    /// Whether the 16-bit immediate is read as signed.
+    pub fn signed_immediate(self) -> bool {
+        matches!(
+            self,
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI | Opcode::CMPI
+        )
+    }
+
+    /// Which operand is a jump target with a `JumpMode`, if any.
+    pub fn target_slot(self) -> Option<usize> {
+        self.layout().iter().position(|s| *s == Slot::Target)
+    }
+
+    /// Jumps that are only taken on some condition.
+    pub fn is_conditional_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JMPE
+                | Opcode::JMPO
+                | Opcode::JMPNE
+                | Opcode::BEQ
+                | Opcode::BNE
+                | Opcode::BLT
+                | Opcode::BLE
+                | Opcode::BGT
+                | Opcode::BGE
+        )
+    }
+
+    /// The role of each of the three operand slots.
+    pub fn operand_roles(self) -> [OperandRole; 3] {
+        use self::OperandRole::*;
+        match self {
+            Opcode::LOAD | Opcode::LOAD32 => [Write, Unused, Unused],
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => [Read, Write, Unused],
+            Opcode::CMPI => [Read, Unused, Unused],
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::ROL
+            | Opcode::ROR => [Read, Read, Write],
+            Opcode::NOT => [Read, Write, Unused],
+            Opcode::EQ | Opcode::NEQ | Opcode::GTE | Opcode::LTE | Opcode::LT | Opcode::GT => {
+                [Read, Read, Unused]
+            }
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JMPE
+            | Opcode::JMPO
+            | Opcode::JMPNE
+            | Opcode::CALL
+            | Opcode::ALOC
+            | Opcode::PUSH => [Read, Unused, Unused],
+            Opcode::POP => [Write, Unused, Unused],
+            Opcode::BEQ | Opcode::BNE | Opcode::BLT | Opcode::BLE | Opcode::BGT | Opcode::BGE => {
+                [Read, Read, Read]
+            }
+            Opcode::INC => [ReadWrite, Unused, Unused],
+            Opcode::REM => [Write, Unused, Unused],
+            Opcode::HLT | Opcode::RET | Opcode::IGL => [Unused, Unused, Unused],
+        }
+    }
+}
+
 enum V<'a> {
     Word(&'a str),
     Int(u8),
@@ -48,13 +428,40 @@ fn match_opcode(v: V) -> Opcode {
         V::Int(15) | V::Word("jmpe") => Opcode::JMPE,
         V::Int(17) | V::Word("aloc") => Opcode::ALOC,
         V::Int(18) | V::Word("inc") => Opcode::INC,
+        V::Int(19) | V::Word("jmpo") => Opcode::JMPO,
+        V::Int(20) | V::Word("rem") => Opcode::REM,
+        V::Int(21) | V::Word("and") => Opcode::AND,
+        V::Int(22) | V::Word("or") => Opcode::OR,
+        V::Int(23) | V::Word("xor") => Opcode::XOR,
+        V::Int(24) | V::Word("not") => Opcode::NOT,
+        V::Int(25) | V::Word("shl") => Opcode::SHL,
+        V::Int(26) | V::Word("shr") => Opcode::SHR,
+        V::Int(27) | V::Word("sar") => Opcode::SAR,
+        V::Int(28) | V::Word("rol") => Opcode::ROL,
+        V::Int(29) | V::Word("ror") => Opcode::ROR,
+        V::Int(30) | V::Word("jmpne") => Opcode::JMPNE,
+        V::Int(31) | V::Word("beq") => Opcode::BEQ,
+        V::Int(32) | V::Word("bne") => Opcode::BNE,
+        V::Int(33) | V::Word("blt") => Opcode::BLT,
+        V::Int(34) | V::Word("ble") => Opcode::BLE,
+        V::Int(35) | V::Word("bgt") => Opcode::BGT,
+        V::Int(36) | V::Word("bge") => Opcode::BGE,
+        V::Int(37) | V::Word("addi") => Opcode::ADDI,
+        V::Int(38) | V::Word("subi") => Opcode::SUBI,
+        V::Int(39) | V::Word("muli") => Opcode::MULI,
+        V::Int(40) | V::Word("cmpi") => Opcode::CMPI,
+        V::Int(41) | V::Word("load32") => Opcode::LOAD32,
+        V::Int(42) | V::Word("call") => Opcode::CALL,
+        V::Int(43) | V::Word("ret") => Opcode::RET,
+        V::Int(44) | V::Word("push") => Opcode::PUSH,
+        V::Int(45) | V::Word("pop") => Opcode::POP,
         _ => Opcode::IGL,
     }
 }
 
 impl<'a> From<CompleteStr<'a>> for Opcode {
     fn from(v: CompleteStr<'a>) -> Self {
-        match_opcode(V::Word(v.0))
+        match_opcode(V::Word(&v.0.to_lowercase()))
     }
 }
 
@@ -91,11 +498,49 @@ mod tests {
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
 
+    #[test]
+    fn test_mnemonics_round_trip() {
+        for opcode in OPCODES.iter() {
+            assert_eq!(Opcode::from(CompleteStr(opcode.mnemonic())), *opcode);
+        }
+    }
+
+    #[test]
+    fn test_operand_roles() {
+        assert_eq!(
+            Opcode::ADD.operand_roles(),
+            [OperandRole::Read, OperandRole::Read, OperandRole::Write]
+        );
+        assert_eq!(Opcode::INC.operand_roles()[0], OperandRole::ReadWrite);
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(Opcode::EQ.layout(), &[Slot::Register, Slot::Register]);
+        assert_eq!(Opcode::JMPE.target_slot(), Some(0));
+        assert_eq!(Opcode::BLT.target_slot(), Some(2));
+        assert_eq!(Opcode::JMPF.target_slot(), None);
+        assert_eq!(
+            JumpMode::from_u8(JumpMode::Register as u8),
+            Some(JumpMode::Register)
+        );
+        assert_eq!(JumpMode::from_u8(3), None);
+    }
+
     #[test]
     fn test_str_to_opcode() {
         let opcode = Opcode::from(CompleteStr("load"));
         assert_eq!(opcode, Opcode::LOAD);
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
+        assert_eq!(Opcode::from(Opcode::ALOC as u8), Opcode::ALOC);
+        assert_eq!(Opcode::from(Opcode::INC as u8), Opcode::INC);
+        assert_eq!(Opcode::from(Opcode::REM as u8), Opcode::REM);
+        assert_eq!(Opcode::from(Opcode::ROR as u8), Opcode::ROR);
+        assert_eq!(Opcode::from(Opcode::BGE as u8), Opcode::BGE);
+        assert_eq!(Opcode::from(Opcode::LOAD32 as u8), Opcode::LOAD32);
+        assert_eq!(Opcode::from(Opcode::POP as u8), Opcode::POP);
+        let opcode = Opcode::from(CompleteStr("Load"));
+        assert_eq!(opcode, Opcode::LOAD);
     }
 }
@@ -1,4 +1,11 @@
+pub mod abi;
+pub mod analysis;
 pub mod assembler;
+pub mod bytecode;
+pub mod cluster;
+pub mod debug_info;
 pub mod instruction;
+pub mod lsp;
+pub mod remote;
 pub mod repl;
 pub mod vm;
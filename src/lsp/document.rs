@@ -0,0 +1,407 @@
+use crate::assembler::expression::Expr;
+use crate::assembler::preprocessor::{conditional, preprocess};
+use crate::assembler::program_parsers::{program_with_sources, statement};
+use crate::assembler::scope::{self, Scope};
+use crate::assembler::strings;
+use crate::assembler::{Assembler, Symbol, Token};
+use crate::instruction::Opcode;
+use nom::types::CompleteStr;
+use std::collections::HashMap;
+
+/// A zero-based line and character position, as LSP counts them.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Range {
+    fn on_line(line: usize, start: usize, end: usize) -> Range {
+        Range {
+            start: Position {
+                line: line as u32,
+                character: start as u32,
+            },
+            end: Position {
+                line: line as u32,
+                character: end as u32,
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// A label declaration (`test:`) or usage (`@test`) in the source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LabelRef {
+    /// The label's unique name, such as `main.loop` for `.loop`.
+    pub name: String,
+    pub range: Range,
+    pub declaration: bool,
+}
+
+/// What the cursor is on.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Word {
+    Label(String),
+    Mnemonic(Opcode),
+    Register(u8),
+}
+
+/// An open `.iasm` file and everything worked out about it.
+#[derive(Debug)]
+pub struct Document {
+    pub text: String,
+    pub diagnostics: Vec<Diagnostic>,
+    pub labels: Vec<LabelRef>,
+    symbols: Vec<(String, u32)>,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl Document {
+    pub fn new(text: &str) -> Document {
+        let mut doc = Document {
+            text: text.to_string(),
+            diagnostics: vec![],
+            labels: vec![],
+            symbols: vec![],
+        };
+        // Excluded regions need not parse, so check what would be assembled
+        // with nothing defined. If the conditions cannot be worked out that
+        // way, check every line but the conditional directives themselves.
+        let checked = preprocess(text, &HashMap::new()).unwrap_or_else(|_| text.to_string());
+        let mut scope = Scope::new();
+        for (line_no, line) in checked.lines().enumerate() {
+            if conditional(line).is_none() {
+                doc.check_line(line_no, line, &mut scope);
+            }
+        }
+        doc.check_labels();
+
+        let has_errors = doc
+            .diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error);
+        if !has_errors {
+            if let Ok((p, sources)) = program_with_sources(&checked) {
+                let (p, _) = p.expand_repeats(sources);
+                let mut names = vec![];
+                let (p, _) = p.qualify_labels(&mut names);
+                let mut asm = Assembler::new();
+                asm.phase1_extract_labels(&p);
+                doc.symbols = asm
+                    .symbols
+                    .sorted()
+                    .into_iter()
+                    .map(|s: &Symbol| (s.name().to_string(), s.offset()))
+                    .collect();
+            }
+        }
+        doc
+    }
+
+    fn error(&mut self, range: Range, message: String) {
+        self.diagnostics.push(Diagnostic {
+            range,
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    fn check_line<'a>(&mut self, line_no: usize, line: &'a str, scope: &mut Scope<'a>) {
+        let indent = line.len() - line.trim_start().len();
+        let content = line.trim();
+        if content.is_empty() {
+            return;
+        }
+        let whole = Range::on_line(line_no, indent, indent + content.len());
+
+        let ins = match statement(CompleteStr(content)) {
+            Ok((rest, ins)) => {
+                if !rest.trim().is_empty() {
+                    let at = indent + content.len() - rest.len();
+                    let rest = rest.trim();
+                    self.error(
+                        Range::on_line(line_no, at, at + rest.len()),
+                        format!("unexpected `{}`", rest),
+                    );
+                }
+                ins
+            }
+            Err(_) => {
+                self.error(whole, "unable to parse statement".to_string());
+                return;
+            }
+        };
+
+        if let Some(name) = ins.label_name() {
+            let qualified = scope.declare(name);
+            if let Some(at) = line.find(&format!("{}:", name)) {
+                self.labels.push(LabelRef {
+                    name: qualified.into_owned(),
+                    range: Range::on_line(line_no, at, at + name.len()),
+                    declaration: true,
+                });
+            }
+        }
+        if ins.opcode() == Some(Opcode::IGL) {
+            let start = match ins.label_name() {
+                Some(_) => {
+                    let after = line.find(':').map_or(0, |i| i + 1);
+                    after + line[after..].len() - line[after..].trim_start().len()
+                }
+                None => indent,
+            };
+            let end = start
+                + line[start..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(line.len() - start);
+            self.error(
+                Range::on_line(line_no, start, end),
+                format!("unknown mnemonic `{}`", &line[start..end]),
+            );
+        }
+
+        let mut usages = vec![];
+        for operand in [ins.operand1, ins.operand2, ins.operand3].iter().flatten() {
+            match operand {
+                Token::LabelUsage { name } => usages.push(*name),
+                Token::IrString { .. } if !ins.is_directive() => {
+                    self.error(whole, "strings are only allowed in directives".to_string());
+                }
+                Token::IrString { text } => {
+                    if let Err(e) = strings::decode(text) {
+                        let at = line.find(text).unwrap_or(indent) + e.at;
+                        self.error(
+                            Range::on_line(line_no, at, at + e.escape.len()),
+                            format!("invalid escape `{}`", e.escape),
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        usages.extend(ins.values().iter().flat_map(Expr::labels));
+        let mut from = 0;
+        for name in usages {
+            let usage = format!("@{}", name);
+            if let Some(at) = line[from..].find(&usage).map(|at| at + from) {
+                self.labels.push(LabelRef {
+                    name: scope.resolve(name).into_owned(),
+                    range: Range::on_line(line_no, at + 1, at + usage.len()),
+                    declaration: false,
+                });
+                from = at + usage.len();
+            }
+        }
+    }
+
+    fn check_labels(&mut self) {
+        let mut problems = vec![];
+        for (index, label) in self.labels.iter().enumerate() {
+            let first = self
+                .labels
+                .iter()
+                .position(|l| l.declaration && l.name == label.name);
+            match (label.declaration, first) {
+                (false, None) => problems.push(Diagnostic {
+                    range: label.range,
+                    severity: Severity::Error,
+                    message: format!("undefined label `{}`", self.text_at(label.range)),
+                }),
+                (true, Some(first)) if first != index => problems.push(Diagnostic {
+                    range: label.range,
+                    severity: Severity::Warning,
+                    message: format!(
+                        "label `{}` is already declared on line {}",
+                        label.name,
+                        self.labels[first].range.start.line + 1
+                    ),
+                }),
+                _ => {}
+            }
+        }
+        self.diagnostics.append(&mut problems);
+    }
+
+    /// The text within a range on one line.
+    fn text_at(&self, range: Range) -> &str {
+        let line = self.text.lines().nth(range.start.line as usize);
+        let (start, end) = (range.start.character, range.end.character);
+        line.and_then(|l| l.get(start as usize..end as usize))
+            .unwrap_or_default()
+    }
+
+    /// The token under `p`, if it means anything to the assembler.
+    pub fn word_at(&self, p: Position) -> Option<Word> {
+        let on_label = self.labels.iter().find(|l| {
+            let r = l.range;
+            r.start.line == p.line && (r.start.character..=r.end.character).contains(&p.character)
+        });
+        if let Some(label) = on_label {
+            return Some(Word::Label(label.name.clone()));
+        }
+        let line = self.text.lines().nth(p.line as usize)?;
+        let at = (p.character as usize).min(line.len());
+        let start = line[..at]
+            .rfind(|c: char| !is_word_char(c))
+            .map_or(0, |i| i + 1);
+        let end = line[at..]
+            .find(|c: char| !is_word_char(c))
+            .map_or(line.len(), |i| i + at);
+        let word = &line[start..end];
+        if word.is_empty() {
+            return None;
+        }
+        let before = line[..start].chars().last();
+        let after = line[end..].chars().next();
+        match (before, after) {
+            (Some('@'), _) | (_, Some(':')) => Some(Word::Label(word.to_string())),
+            (Some('$'), _) => word.parse().ok().map(Word::Register),
+            (Some('#'), _) => None,
+            _ => match Opcode::from(CompleteStr(word)) {
+                Opcode::IGL if self.declaration(word).is_some() => {
+                    Some(Word::Label(word.to_string()))
+                }
+                Opcode::IGL => None,
+                opcode => Some(Word::Mnemonic(opcode)),
+            },
+        }
+    }
+
+    pub fn declaration(&self, name: &str) -> Option<&LabelRef> {
+        self.labels.iter().find(|l| l.declaration && l.name == name)
+    }
+
+    pub fn references(&self, name: &str, include_declaration: bool) -> Vec<&LabelRef> {
+        self.labels
+            .iter()
+            .filter(|l| l.name == name && (include_declaration || !l.declaration))
+            .collect()
+    }
+
+    /// The label's offset from the assembler's symbol table.
+    pub fn label_offset(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, offset)| *offset)
+    }
+
+    pub fn label_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .labels
+            .iter()
+            .filter(|l| l.declaration)
+            .map(|l| l.name.as_str())
+            // Numeric labels are only ever referred to as `1f` or `1b`.
+            .filter(|name| !scope::is_numeric(name.split('.').next().unwrap_or_default()))
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    #[test]
+    fn test_clean_document() {
+        let doc = Document::new("load $0 #1\ntest: inc $0\njmpe @test\nhlt\n");
+        assert!(doc.diagnostics.is_empty());
+        assert_eq!(doc.label_offset("test"), Some(4));
+        assert_eq!(doc.references("test", true).len(), 2);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let doc = Document::new("load $0 #1\nfrob $0\njmpe @nowhere\ntest: hlt\ntest: hlt\n$$");
+        let messages: Vec<&str> = doc.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "unknown mnemonic `frob`",
+                "unable to parse statement",
+                "undefined label `nowhere`",
+                "label `test` is already declared on line 4",
+            ]
+        );
+        assert_eq!(doc.diagnostics[0].range, Range::on_line(1, 0, 4));
+        assert_eq!(doc.diagnostics[2].range, Range::on_line(2, 6, 13));
+    }
+
+    #[test]
+    fn test_conditional_regions() {
+        let doc = Document::new(".ifdef DEBUG\nnot ( yet\n.else\nstart: hlt\n.endif\n");
+        assert!(doc.diagnostics.is_empty());
+        assert_eq!(doc.label_offset("start"), Some(0));
+        let doc = Document::new(".if LEVEL\nhlt\n.endif\nfrob\n");
+        assert_eq!(doc.diagnostics.len(), 1);
+        assert_eq!(doc.diagnostics[0].range, Range::on_line(3, 0, 4));
+    }
+
+    #[test]
+    fn test_invalid_escape() {
+        let doc = Document::new(".data\nmsg: .asciiz \"hi\\q\"\n");
+        assert_eq!(doc.diagnostics.len(), 1);
+        assert_eq!(doc.diagnostics[0].message, "invalid escape `\\q`");
+        assert_eq!(doc.diagnostics[0].range, Range::on_line(1, 16, 18));
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let doc = Document::new("a: jmp @.x\n.x: jmp @1f\nb: jmp @.x\n.x: hlt\n1: jmp @1b\n");
+        assert!(doc.diagnostics.is_empty());
+        assert_eq!(doc.references("a.x", true).len(), 2);
+        assert_eq!(doc.references("b.x", true).len(), 2);
+        assert_eq!(doc.word_at(pos(1, 9)), Some(Word::Label("1.1".to_string())));
+        assert_eq!(doc.word_at(pos(2, 8)), Some(Word::Label("b.x".to_string())));
+        assert_eq!(doc.label_offset("b.x"), Some(12));
+        assert_eq!(doc.label_offset("1.1"), Some(13));
+        let doc = Document::new("a: jmp @.y\n");
+        assert_eq!(doc.diagnostics[0].message, "undefined label `.y`");
+    }
+
+    #[test]
+    fn test_word_at() {
+        let doc = Document::new("test: inc $12\njmpe @test\n");
+        assert_eq!(
+            doc.word_at(pos(0, 1)),
+            Some(Word::Label("test".to_string()))
+        );
+        assert_eq!(doc.word_at(pos(0, 7)), Some(Word::Mnemonic(Opcode::INC)));
+        assert_eq!(doc.word_at(pos(0, 12)), Some(Word::Register(12)));
+        assert_eq!(
+            doc.word_at(pos(1, 8)),
+            Some(Word::Label("test".to_string()))
+        );
+        assert_eq!(doc.word_at(pos(5, 0)), None);
+    }
+}
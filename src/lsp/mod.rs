@@ -0,0 +1,469 @@
+//! A Language Server Protocol server for `.iasm` files, spoken over any
+//! reader/writer pair (stdio in the `iridium-lsp` binary).
+
+pub mod document;
+
+use self::document::{Document, Position, Range, Word};
+use crate::assembler::pseudo::PSEUDOS;
+use crate::instruction::OPCODES;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// LSP `CompletionItemKind` values.
+const KIND_KEYWORD: u8 = 14;
+const KIND_VARIABLE: u8 = 6;
+const KIND_REFERENCE: u8 = 18;
+
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+    exited: bool,
+}
+
+fn position_json(p: Position) -> Value {
+    json!({ "line": p.line, "character": p.character })
+}
+
+fn range_json(r: Range) -> Value {
+    json!({ "start": position_json(r.start), "end": position_json(r.end) })
+}
+
+fn position_from(params: &Value) -> Position {
+    Position {
+        line: params["position"]["line"].as_u64().unwrap_or(0) as u32,
+        character: params["position"]["character"].as_u64().unwrap_or(0) as u32,
+    }
+}
+
+fn uri_from(params: &Value) -> String {
+    params["textDocument"]["uri"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Whether the client has sent `exit`.
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// Handles one incoming message, returning the responses and
+    /// notifications to send back.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = message.get("id").cloned();
+
+        if self.shutdown && method != "exit" {
+            return id.map_or(vec![], |id| {
+                vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": INVALID_REQUEST, "message": "server is shutting down" },
+                })]
+            });
+        }
+
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["@", "$"] },
+                },
+                "serverInfo": { "name": "iridium-lsp" },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Value::Null)
+            }
+            "exit" => {
+                self.exited = true;
+                return vec![];
+            }
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                return self.update(uri_from(params), text);
+            }
+            "textDocument/didChange" => {
+                // Only full syncs are advertised, so the last change is the text.
+                let changes = params["contentChanges"].as_array();
+                let text = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                    .unwrap_or_default();
+                return self.update(uri_from(params), text);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri_from(params));
+                return vec![];
+            }
+            "textDocument/definition" => Some(self.definition(params)),
+            "textDocument/references" => Some(self.references(params)),
+            "textDocument/hover" => Some(self.hover(params)),
+            "textDocument/completion" => Some(self.completion(params)),
+            _ => None,
+        };
+
+        match (id, result) {
+            (Some(id), Some(result)) => {
+                vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+            }
+            (Some(id), None) => vec![json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": METHOD_NOT_FOUND, "message": format!("unknown method `{}`", method) },
+            })],
+            // Unknown notifications are ignored.
+            (None, _) => vec![],
+        }
+    }
+
+    fn update(&mut self, uri: String, text: &str) -> Vec<Value> {
+        let doc = Document::new(text);
+        let diagnostics: Vec<Value> = doc
+            .diagnostics
+            .iter()
+            .map(|d| {
+                json!({
+                    "range": range_json(d.range),
+                    "severity": d.severity as u8,
+                    "source": "iridium",
+                    "message": d.message,
+                })
+            })
+            .collect();
+        self.documents.insert(uri.clone(), doc);
+        vec![json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })]
+    }
+
+    fn label_at(&self, params: &Value) -> Option<(String, &Document)> {
+        let doc = self.documents.get(&uri_from(params))?;
+        match doc.word_at(position_from(params))? {
+            Word::Label(name) => Some((name, doc)),
+            _ => None,
+        }
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let uri = uri_from(params);
+        self.label_at(params)
+            .and_then(|(name, doc)| doc.declaration(&name).cloned())
+            .map_or(
+                Value::Null,
+                |decl| json!({ "uri": uri, "range": range_json(decl.range) }),
+            )
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let uri = uri_from(params);
+        let include_declaration = params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true);
+        let locations: Vec<Value> = self
+            .label_at(params)
+            .map(|(name, doc)| {
+                doc.references(&name, include_declaration)
+                    .iter()
+                    .map(|l| json!({ "uri": uri, "range": range_json(l.range) }))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Value::Array(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let doc = match self.documents.get(&uri_from(params)) {
+            Some(doc) => doc,
+            None => return Value::Null,
+        };
+        let text = match doc.word_at(position_from(params)) {
+            Some(Word::Mnemonic(opcode)) => {
+                let (syntax, description) = opcode.summary();
+                format!("`{}`\n\n{}", syntax, description)
+            }
+            Some(Word::Label(name)) => match doc.label_offset(&name) {
+                Some(offset) => format!("label `{}` at offset {} (0x{:04X})", name, offset, offset),
+                None => format!("label `{}`", name),
+            },
+            Some(Word::Register(r)) => format!("register `${}`", r),
+            None => return Value::Null,
+        };
+        json!({ "contents": { "kind": "markdown", "value": text } })
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let doc = match self.documents.get(&uri_from(params)) {
+            Some(doc) => doc,
+            None => return json!([]),
+        };
+        let p = position_from(params);
+        let line = doc.text.lines().nth(p.line as usize).unwrap_or_default();
+        let before = &line[..(p.character as usize).min(line.len())];
+        let sigil = before
+            .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            .chars()
+            .last();
+
+        let items: Vec<Value> = match sigil {
+            Some('@') => doc
+                .label_names()
+                .iter()
+                .map(|name| json!({ "label": name, "kind": KIND_REFERENCE }))
+                .collect(),
+            Some('$') => (0..32)
+                .map(|r| json!({ "label": r.to_string(), "kind": KIND_VARIABLE }))
+                .collect(),
+            _ => OPCODES
+                .iter()
+                .map(|opcode| (opcode.mnemonic(), opcode.summary()))
+                .chain(PSEUDOS.iter().map(|p| (p.mnemonic(), p.summary())))
+                .map(|(mnemonic, (syntax, description))| {
+                    json!({
+                        "label": mnemonic,
+                        "kind": KIND_KEYWORD,
+                        "detail": syntax,
+                        "documentation": description,
+                    })
+                })
+                .collect(),
+        };
+        Value::Array(items)
+    }
+}
+
+/// Reads one `Content-Length` framed message; `None` at end of input.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serves requests from `input` until the client exits or hangs up.
+pub fn serve<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut server = Server::new();
+    while let Some(message) = read_message(&mut input)? {
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exited() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A minimal in-process client: queues framed messages, then runs the
+    /// server over them and collects everything it wrote back.
+    #[derive(Default)]
+    struct Client {
+        input: Vec<u8>,
+        next_id: i64,
+    }
+
+    impl Client {
+        fn request(&mut self, method: &str, params: Value) -> i64 {
+            self.next_id += 1;
+            let message =
+                json!({ "jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params });
+            write_message(&mut self.input, &message).unwrap();
+            self.next_id
+        }
+
+        fn notify(&mut self, method: &str, params: Value) {
+            let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+            write_message(&mut self.input, &message).unwrap();
+        }
+
+        fn run(self) -> Vec<Value> {
+            let mut output = vec![];
+            serve(Cursor::new(self.input), &mut output).unwrap();
+            let mut replies = vec![];
+            let mut output = Cursor::new(output);
+            while let Some(reply) = read_message(&mut output).unwrap() {
+                replies.push(reply);
+            }
+            replies
+        }
+    }
+
+    fn response(replies: &[Value], id: i64) -> &Value {
+        &replies.iter().find(|r| r["id"] == json!(id)).unwrap()["result"]
+    }
+
+    const URI: &str = "file:///loop.iasm";
+    const SOURCE: &str = "load $0 #0\nload $2 #9\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt\n";
+
+    fn open(client: &mut Client, text: &str) {
+        client.notify(
+            "textDocument/didOpen",
+            json!({ "textDocument": { "uri": URI, "languageId": "iasm", "version": 1, "text": text } }),
+        );
+    }
+
+    fn at(line: u32, character: u32) -> Value {
+        json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+    }
+
+    #[test]
+    fn test_initialize_and_shutdown() {
+        let mut client = Client::default();
+        let init = client.request("initialize", json!({ "capabilities": {} }));
+        let shutdown = client.request("shutdown", Value::Null);
+        client.notify("exit", Value::Null);
+        // Nothing after `exit` is answered.
+        client.request("shutdown", Value::Null);
+        let replies = client.run();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(
+            response(&replies, init)["capabilities"]["hoverProvider"],
+            json!(true)
+        );
+        assert_eq!(response(&replies, shutdown), &Value::Null);
+    }
+
+    #[test]
+    fn test_diagnostics_published() {
+        let mut client = Client::default();
+        open(&mut client, "load $0 #1\njmpe @missing\n");
+        client.notify(
+            "textDocument/didChange",
+            json!({ "textDocument": { "uri": URI, "version": 2 }, "contentChanges": [{ "text": SOURCE }] }),
+        );
+        let replies = client.run();
+        assert_eq!(
+            replies[0]["method"],
+            json!("textDocument/publishDiagnostics")
+        );
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0]["message"],
+            json!("undefined label `missing`")
+        );
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({ "line": 1, "character": 6 })
+        );
+        assert_eq!(replies[1]["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let mut client = Client::default();
+        open(&mut client, SOURCE);
+        let definition = client.request("textDocument/definition", at(4, 7));
+        let mut params = at(2, 1);
+        params["context"] = json!({ "includeDeclaration": false });
+        let references = client.request("textDocument/references", params);
+        let replies = client.run();
+        assert_eq!(
+            response(&replies, definition)["range"]["start"],
+            json!({ "line": 2, "character": 0 })
+        );
+        let references = response(&replies, references).as_array().unwrap();
+        assert_eq!(references.len(), 1);
+        assert_eq!(
+            references[0]["range"]["start"],
+            json!({ "line": 4, "character": 6 })
+        );
+    }
+
+    #[test]
+    fn test_hover() {
+        let mut client = Client::default();
+        open(&mut client, SOURCE);
+        let mnemonic = client.request("textDocument/hover", at(0, 1));
+        let label = client.request("textDocument/hover", at(4, 8));
+        let nothing = client.request("textDocument/hover", at(0, 9));
+        let replies = client.run();
+        assert_eq!(
+            response(&replies, mnemonic)["contents"]["value"],
+            json!("`load $r #imm`\n\nLoad an immediate into $r, as load32 if it needs more than 16 bits")
+        );
+        assert_eq!(
+            response(&replies, label)["contents"]["value"],
+            json!("label `test` at offset 8 (0x0008)")
+        );
+        assert_eq!(response(&replies, nothing), &Value::Null);
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut client = Client::default();
+        open(&mut client, "test: hlt\njmpe @\nadd $\nlo");
+        let labels = client.request("textDocument/completion", at(1, 6));
+        let registers = client.request("textDocument/completion", at(2, 5));
+        let mnemonics = client.request("textDocument/completion", at(3, 2));
+        let replies = client.run();
+        assert_eq!(
+            response(&replies, labels),
+            &json!([{ "label": "test", "kind": 18 }])
+        );
+        assert_eq!(response(&replies, registers).as_array().unwrap().len(), 32);
+        assert!(response(&replies, mnemonics)
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|item| item["label"] == json!("load")));
+        assert!(response(&replies, mnemonics)
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|item| item["label"] == json!("mov") && item["detail"] == json!("mov $a $d")));
+    }
+
+    #[test]
+    fn test_unknown_request() {
+        let mut client = Client::default();
+        let id = client.request("workspace/symbol", json!({}));
+        let replies = client.run();
+        assert_eq!(replies[0]["id"], json!(id));
+        assert_eq!(replies[0]["error"]["code"], json!(METHOD_NOT_FOUND));
+    }
+}
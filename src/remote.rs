@@ -0,0 +1,386 @@
+//! Serves REPL sessions over TCP, one per connection, so a running node can
+//! be attached to from another terminal (e.g. with `nc`).
+//!
+//! The protocol is the REPL itself, line by line. When a token is set, the
+//! first line a client sends must be that token.
+//!
+//! Remote sessions cannot `.load_file` unless the server names a directory
+//! to load from, and each run or step stops after `run_limit` instructions.
+//! A client that sends an entry longer than `MAX_ENTRY_LEN`, or takes longer
+//! than `login_timeout` to send the token, is disconnected.
+
+use super::repl::{FileAccess, REPL};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Whether connections get a VM each or all drive the same one.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum VmMode {
+    #[default]
+    PerSession,
+    Shared,
+}
+
+/// How many instructions a remote run or step may execute by default.
+pub const DEFAULT_RUN_LIMIT: u64 = 10_000_000;
+
+/// Longest entry, all of its lines together, that a session reads.
+pub const MAX_ENTRY_LEN: u64 = 64 * 1024;
+
+/// How long a client has to send the token by default.
+pub const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub token: Option<String>,
+    pub vm_mode: VmMode,
+    /// The directory `.load_file` reads from; without one it is disabled.
+    pub load_dir: Option<PathBuf>,
+    pub run_limit: u64,
+    /// How long a client has to send the token, when one is set.
+    pub login_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            token: None,
+            vm_mode: VmMode::default(),
+            load_dir: None,
+            run_limit: DEFAULT_RUN_LIMIT,
+            login_timeout: DEFAULT_LOGIN_TIMEOUT,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// A REPL for a session, with the restrictions set here.
+    fn repl(&self) -> REPL {
+        let files = match &self.load_dir {
+            Some(dir) => FileAccess::Within(dir.clone()),
+            None => FileAccess::Denied,
+        };
+        REPL::new()
+            .with_file_access(files)
+            .with_run_limit(self.run_limit)
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    config: ServerConfig,
+    shared: Arc<Mutex<REPL>>,
+}
+
+/// Compares without bailing out at the first difference, so response times
+/// do not give away how much of a guess was right.
+pub(crate) fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// A session's REPL, whether its own or the shared one.
+enum SessionRepl {
+    Own(Box<REPL>),
+    Shared(Arc<Mutex<REPL>>),
+}
+
+impl SessionRepl {
+    fn with<T>(&mut self, f: impl FnOnce(&mut REPL) -> T) -> T {
+        match self {
+            SessionRepl::Own(repl) => f(repl),
+            SessionRepl::Shared(repl) => f(&mut lock(repl)),
+        }
+    }
+}
+
+/// A session that panicked mid-command must not take the others down.
+fn lock(repl: &Mutex<REPL>) -> MutexGuard<'_, REPL> {
+    repl.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Server {
+    /// Listens on `addr`; port 0 picks a free one, see `local_addr`.
+    pub fn bind(addr: &str, config: ServerConfig) -> io::Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            shared: Arc::new(Mutex::new(config.repl())),
+            config,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections forever, each served on its own thread.
+    pub fn serve(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let config = self.config.clone();
+            let repl = match config.vm_mode {
+                VmMode::PerSession => SessionRepl::Own(Box::new(config.repl())),
+                VmMode::Shared => SessionRepl::Shared(Arc::clone(&self.shared)),
+            };
+            thread::spawn(move || {
+                // A client going away mid-session is not the server's problem.
+                let _ = session(stream, &config, repl);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn session(stream: TcpStream, config: &ServerConfig, mut repl: SessionRepl) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writeln!(writer, "Iridium remote REPL")?;
+
+    if let Some(token) = &config.token {
+        write!(writer, "Token: ")?;
+        writer.set_read_timeout(Some(config.login_timeout))?;
+        let mut given = String::new();
+        read_line(&mut reader, &mut given, MAX_ENTRY_LEN)?;
+        if !tokens_match(given.trim_end_matches(['\r', '\n']), token) {
+            writeln!(writer, "Authentication failed")?;
+            return Ok(());
+        }
+        writer.set_read_timeout(None)?;
+    }
+
+    loop {
+        write!(writer, ">>> ")?;
+        let mut entry = String::new();
+        loop {
+            let mut line = String::new();
+            let room = MAX_ENTRY_LEN.saturating_sub(entry.len() as u64);
+            match read_line(&mut reader, &mut line, room) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    return writeln!(writer, "Entry longer than {} bytes", MAX_ENTRY_LEN);
+                }
+                Err(e) => return Err(e),
+            }
+            if !entry.is_empty() {
+                entry.push('\n');
+            }
+            entry.push_str(line.trim_end_matches(['\r', '\n']));
+            if repl.with(|r| r.is_complete(&entry)) {
+                break;
+            }
+        }
+        // Output is sent once the REPL is free again, so that a client that
+        // stops reading does not hold up a shared one.
+        let mut output = vec![];
+        let go_on = repl.with(|r| r.execute(&entry, &mut output))?;
+        writer.write_all(&output)?;
+        if !go_on {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a line of at most `max` bytes, failing with `InvalidData` on a
+/// longer one.
+fn read_line(reader: &mut impl BufRead, line: &mut String, max: u64) -> io::Result<usize> {
+    let read = reader.take(max).read_line(line)?;
+    if read as u64 == max && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr: SocketAddr) -> Client {
+            let stream = TcpStream::connect(addr).unwrap();
+            Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            }
+        }
+
+        /// Everything up to and including `marker`.
+        fn read_until(&mut self, marker: &str) -> String {
+            let mut text = String::new();
+            let mut byte = [0];
+            while !text.ends_with(marker) {
+                if self.reader.read(&mut byte).unwrap() == 0 {
+                    break;
+                }
+                text.push(byte[0] as char);
+            }
+            text
+        }
+
+        /// Sends `line` and returns the output up to the next prompt.
+        fn send(&mut self, line: &str) -> String {
+            writeln!(self.writer, "{}", line).unwrap();
+            let output = self.read_until(">>> ");
+            output.trim_end_matches(">>> ").to_string()
+        }
+    }
+
+    fn start(config: ServerConfig) -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0", config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve());
+        addr
+    }
+
+    fn login(addr: SocketAddr) -> Client {
+        let mut client = Client::connect(addr);
+        client.read_until(">>> ");
+        client
+    }
+
+    #[test]
+    fn test_sessions_have_their_own_vm() {
+        let addr = start(ServerConfig::default());
+        let mut a = login(addr);
+        let mut b = login(addr);
+        a.send("load $0 #7");
+        assert_eq!(a.send(".registers $0"), "$0 = 7\n");
+        assert_eq!(b.send(".registers $0"), "$0 = 0\n");
+    }
+
+    #[test]
+    fn test_sessions_can_share_a_vm() {
+        let addr = start(ServerConfig {
+            vm_mode: VmMode::Shared,
+            ..ServerConfig::default()
+        });
+        let mut a = login(addr);
+        let mut b = login(addr);
+        a.send("load $0 #7");
+        assert_eq!(b.send(".registers $0"), "$0 = 7\n");
+    }
+
+    #[test]
+    fn test_token() {
+        let addr = start(ServerConfig {
+            token: Some("s3cret".to_string()),
+            ..ServerConfig::default()
+        });
+        let mut rejected = Client::connect(addr);
+        assert_eq!(
+            rejected.read_until("Token: "),
+            "Iridium remote REPL\nToken: "
+        );
+        writeln!(rejected.writer, "guess").unwrap();
+        assert_eq!(rejected.read_until("\n\n"), "Authentication failed\n");
+
+        let mut accepted = Client::connect(addr);
+        accepted.read_until("Token: ");
+        assert_eq!(accepted.send("s3cret"), "");
+        assert_eq!(accepted.send(".registers $1"), "$1 = 0\n");
+
+        // Saying nothing does not hold a session open.
+        let addr = start(ServerConfig {
+            token: Some("s3cret".to_string()),
+            login_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        });
+        let mut silent = Client::connect(addr);
+        silent.read_until("Token: ");
+        assert_eq!(silent.read_until("\n\n"), "");
+    }
+
+    #[test]
+    fn test_entry_too_long() {
+        let addr = start(ServerConfig::default());
+        let mut client = login(addr);
+        // Exactly the limit and no newline, so the server has read it all.
+        let long = vec![b'x'; MAX_ENTRY_LEN as usize];
+        client.writer.write_all(&long).unwrap();
+        assert_eq!(
+            client.read_until("\n\n"),
+            format!("Entry longer than {} bytes\n", MAX_ENTRY_LEN)
+        );
+    }
+
+    #[test]
+    fn test_runs_are_limited() {
+        let addr = start(ServerConfig {
+            vm_mode: VmMode::Shared,
+            run_limit: 100,
+            ..ServerConfig::default()
+        });
+        let mut a = login(addr);
+        let mut b = login(addr);
+        // A label opens a multi-line entry, which a blank line ends.
+        writeln!(a.writer, "spin: jmp @spin").unwrap();
+        assert_eq!(a.send(""), "limit of 100 instructions reached at pc 0\n");
+        assert_eq!(
+            a.send(".run"),
+            "limit of 200 instructions reached at pc 0\n"
+        );
+        assert_eq!(b.send(".pc"), "pc 0\n");
+    }
+
+    #[test]
+    fn test_load_file() {
+        let dir = std::env::temp_dir().join(format!("iridium-load-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("seven.iasm"), "load $0 #7\n").unwrap();
+
+        let mut denied = login(start(ServerConfig::default()));
+        assert_eq!(
+            denied.send(".load_file /etc/passwd"),
+            "Unable to open /etc/passwd: loading files is disabled\n"
+        );
+
+        let mut allowed = login(start(ServerConfig {
+            load_dir: Some(dir.clone()),
+            ..ServerConfig::default()
+        }));
+        allowed.send(".load_file seven.iasm");
+        allowed.send(".run");
+        assert_eq!(allowed.send(".registers $0"), "$0 = 7\n");
+        assert_eq!(
+            allowed.send(".load_file ../../etc/passwd"),
+            "Unable to open ../../etc/passwd: outside the directory files are loaded from\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disconnects() {
+        let addr = start(ServerConfig {
+            vm_mode: VmMode::Shared,
+            ..ServerConfig::default()
+        });
+        let mut quitter = login(addr);
+        writeln!(quitter.writer, "load $2 #3\n.quit").unwrap();
+        assert_eq!(quitter.read_until("\n\n"), ">>> exiting\n");
+
+        // Dropped halfway through a multi-line entry.
+        let mut dropped = login(addr);
+        writeln!(dropped.writer, "top: inc $0").unwrap();
+        drop(dropped);
+
+        let mut next = login(addr);
+        assert_eq!(next.send(".registers $2"), "$2 = 3\n");
+        assert!(tokens_match("abc", "abc"));
+        assert!(!tokens_match("abc", "abd"));
+        assert!(!tokens_match("ab", "abc"));
+    }
+}
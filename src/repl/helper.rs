@@ -0,0 +1,146 @@
+use super::COMMANDS;
+use crate::abi;
+use crate::assembler::pseudo::PSEUDOS;
+use crate::instruction::{Opcode, OPCODES};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+
+/// Tab completion and multi-line entry for the line editor.
+#[derive(Debug, Default)]
+pub struct ReplHelper {
+    /// Labels declared so far this session.
+    pub labels: Vec<String>,
+}
+
+impl ReplHelper {
+    /// Where the word before `pos` starts, and what could finish it.
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map_or(0, |at| at + 1);
+        let word = &line[start..pos];
+        let options: Vec<String> = if word.starts_with('.') && start == 0 {
+            COMMANDS.iter().map(|(c, _, _)| c.to_string()).collect()
+        } else if word.starts_with('$') {
+            let numbers = (0..abi::REGISTERS).map(|r| r.to_string());
+            let names = (0..abi::REGISTERS).map(abi::register_name);
+            numbers.chain(names).map(|r| format!("${}", r)).collect()
+        } else if word.starts_with('@') {
+            self.labels.iter().map(|l| format!("@{}", l)).collect()
+        } else {
+            OPCODES
+                .iter()
+                .filter(|o| **o != Opcode::IGL)
+                .map(|o| o.mnemonic())
+                .chain(PSEUDOS.iter().map(|p| p.mnemonic()))
+                .map(str::to_string)
+                .collect()
+        };
+        let matching = options
+            .into_iter()
+            .filter(|o| o.starts_with(word))
+            .collect();
+        (start, matching)
+    }
+
+    /// Whether `input` is ready to run. Code that opens with a label, or
+    /// jumps to one not declared yet, keeps reading until a blank line.
+    pub fn is_complete(&self, input: &str) -> bool {
+        let first = input.trim_start();
+        if first.starts_with('.') || input.ends_with('\n') {
+            return true;
+        }
+        let mut declared: Vec<&str> = self.labels.iter().map(String::as_str).collect();
+        let mut opens_with_label = None;
+        let mut undefined = vec![];
+        for word in input.split_whitespace() {
+            if let Some(label) = word.strip_suffix(':') {
+                opens_with_label.get_or_insert(true);
+                declared.push(label);
+            } else if let Some(label) = word.strip_prefix('@') {
+                undefined.push(label);
+            }
+            opens_with_label.get_or_insert(false);
+        }
+        !opens_with_label.unwrap_or(false) && undefined.iter().all(|l| declared.contains(l))
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if self.is_complete(ctx.input()) {
+            Ok(ValidationResult::Valid(None))
+        } else {
+            Ok(ValidationResult::Incomplete)
+        }
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper() -> ReplHelper {
+        ReplHelper {
+            labels: vec!["start".to_string(), "stop".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let h = helper();
+        assert_eq!(h.candidates(".reg", 4), (0, vec![".registers".to_string()]));
+        assert_eq!(h.candidates(".s", 2).1, vec![".set", ".step", ".symbols"]);
+        assert_eq!(h.candidates("load $3", 7).1, vec!["$3", "$30", "$31"]);
+        assert_eq!(
+            h.candidates("load $s1", 8).1,
+            vec!["$s1", "$s10", "$s11", "$s12", "$s13", "$s14"]
+        );
+        assert_eq!(h.candidates("push $r", 7).1, vec!["$ra"]);
+        assert_eq!(h.candidates("add $", 5).1.len(), 64);
+        assert_eq!(
+            h.candidates("jmpe @st", 8),
+            (5, vec!["@start".to_string(), "@stop".to_string()])
+        );
+        assert_eq!(
+            h.candidates("jm", 2).1,
+            vec!["jmp", "jmpf", "jmpb", "jmpe", "jmpo", "jmpne"]
+        );
+        assert_eq!(h.candidates("ne", 2).1, vec!["neq", "neg"]);
+    }
+
+    #[test]
+    fn test_is_complete() {
+        let h = helper();
+        assert!(h.is_complete("load $0 #1"));
+        assert!(h.is_complete("jmpe @start"));
+        assert!(!h.is_complete("jmpe @later"));
+        assert!(!h.is_complete("test: inc $0\nneq $0 $2\njmpe @test"));
+        assert!(h.is_complete("test: inc $0\nneq $0 $2\njmpe @test\n"));
+        assert!(h.is_complete(".load_file"));
+    }
+}
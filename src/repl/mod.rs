@@ -1,19 +1,122 @@
-use super::assembler::program_parsers::program;
-use super::assembler::Assembler;
-use super::vm::VM;
-use nom::types::CompleteStr;
-use std::io::{self, Read, Write};
+mod helper;
+pub mod transcript;
+
+use self::helper::ReplHelper;
+use super::assembler::{Assembler, SymbolTable};
+use super::vm::{Limit, VM};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Dot-commands: name, arguments, and what `.help` says about them.
+pub const COMMANDS: &[(&str, &str, &str)] = &[
+    (".clear_program", "", "Clear the program and its labels"),
+    (".events", "[N]", "Show the last N VM events, 10 by default"),
+    (
+        ".flags",
+        "",
+        "Show the equal and overflow flags and the remainder of the last DIV",
+    ),
+    (
+        ".heap",
+        "[start..end]",
+        "Dump the heap, or part of it, in hex",
+    ),
+    (".help", "[command]", "List the commands, or explain one"),
+    (".history", "", "Show the commands entered this session"),
+    (
+        ".load_file",
+        "[path]",
+        "Assemble a file and append it to the program",
+    ),
+    (
+        ".pc",
+        "",
+        "Show the program counter and where it is in the source",
+    ),
+    (
+        ".overflow",
+        "[mode]",
+        "Show or set what arithmetic does on overflow",
+    ),
+    (".program", "", "Show the program bytes"),
+    (".quit", "", "Leave the REPL"),
+    (
+        ".registers",
+        "[$r ...]",
+        "Show all registers, or just the ones given",
+    ),
+    (
+        ".reset",
+        "",
+        "Clear registers, heap, flags and pc, keeping the program",
+    ),
+    (
+        ".run",
+        "",
+        "Run from the program counter until the program stops",
+    ),
+    (".set", "$r value", "Set a register"),
+    (".step", "[N]", "Execute N instructions, one by default"),
+    (".symbols", "", "List the labels and their offsets"),
+    (".trace", "", "Show the most recently executed instructions"),
+];
+
+/// Which files `.load_file` may read.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum FileAccess {
+    /// Any file the process can read.
+    #[default]
+    Any,
+    /// Only files under this directory; relative paths start there.
+    Within(PathBuf),
+    Denied,
+}
 
 #[derive(Default)]
 pub struct REPL {
     command_buffer: Vec<String>,
     vm: VM,
     asm: Assembler,
+    files: FileAccess,
+    /// Most instructions a single run or step may execute.
+    run_limit: Option<u64>,
 }
 
 impl REPL {
     pub fn new() -> REPL {
-        REPL::default()
+        let mut repl = REPL::default();
+        repl.vm.enable_tracing();
+        repl
+    }
+
+    /// Restrict which files `.load_file` may read.
+    pub fn with_file_access(mut self, files: FileAccess) -> REPL {
+        self.files = files;
+        self
+    }
+
+    /// Stop each run or step after `limit` instructions, with
+    /// `VMError::LimitReached`.
+    pub fn with_run_limit(mut self, limit: u64) -> REPL {
+        self.run_limit = Some(limit);
+        self
+    }
+
+    /// Sets the VM's instruction limit `run_limit` past what it has
+    /// executed so far, ahead of a run or step.
+    fn limit_run(&mut self) {
+        if let Some(limit) = self.run_limit {
+            let at = self.vm.instructions_executed().saturating_add(limit);
+            self.vm.set_limit(Limit::Instructions(at));
+        }
+    }
+
+    fn run_vm(&mut self) {
+        self.limit_run();
+        self.vm.run();
     }
 
     // hex speaking repl
@@ -26,88 +129,413 @@ impl REPL {
     //         .collect::<Vec<u8>>()
     // }
 
-    pub fn run(&mut self) {
-        println!("<welcome_message>");
-        loop {
-            let mut buffer = String::new();
-            let stdin = io::stdin();
-
-            print!(">>> ");
-            io::stdout().flush().expect("Unable to flush stdout");
-
-            stdin
-                .read_line(&mut buffer)
-                .expect("Unable to read line from user");
-
-            let buffer = buffer.trim();
-            self.command_buffer.push(buffer.to_string());
-
-            match buffer {
-                ".load_file" => {
-                    print!("Enter path to source file:");
-
-                    let mut buf = String::new();
-                    let filepath = {
-                        stdin
-                            .read_line(&mut buf)
-                            .expect("unable to read line from user");
-                        let buf = buf.trim();
-                        std::path::Path::new(buf)
-                    };
-
-                    let mut source = String::new();
-                    let mut handle = std::fs::File::open(filepath).expect("file not found");
-                    handle
-                        .read_to_string(&mut source)
-                        .expect("error reading from file");
-
-                    match program(CompleteStr(&source)) {
-                        Ok((_rest, program)) => {
-                            self.vm
-                                .program
-                                .append(&mut program.to_bytes(&self.asm.symbols));
-                        }
-                        Err(e) => {
-                            println!("Unable to parse input: {:?}", e);
-                        }
+    /// Where history is kept between sessions: `$IRIDIUM_HISTORY`, or
+    /// `~/.iridium_history`.
+    fn history_path() -> Option<PathBuf> {
+        match std::env::var_os("IRIDIUM_HISTORY") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".iridium_history"))
+            }
+        }
+    }
+
+    /// Assembles `source` to follow the code already in the VM, resolving
+    /// labels against everything entered so far, and appends it.
+    fn assemble_into_vm(
+        &mut self,
+        source: &str,
+        file: Option<&str>,
+        out: &mut dyn Write,
+    ) -> io::Result<bool> {
+        let mut asm = Assembler::new().with_base_offset(self.vm.program.len() as u32);
+        if let Some(file) = file {
+            asm = asm.with_debug_info(file);
+        }
+        asm.symbols = std::mem::take(&mut self.asm.symbols);
+        let assembled = asm.try_assemble(source);
+        self.asm.symbols = std::mem::take(&mut asm.symbols);
+        match assembled {
+            Ok(mut bytecode) => {
+                if let Some(debug) = asm.debug_info() {
+                    self.vm.set_debug_info(debug.clone());
+                }
+                self.vm.program.append(&mut bytecode);
+                Ok(true)
+            }
+            Err(errors) => {
+                for e in errors {
+                    writeln!(out, "Error assembling the code: {}", e)?;
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    fn label_names(&self) -> Vec<String> {
+        self.asm
+            .symbols
+            .sorted()
+            .iter()
+            .map(|s| s.name().to_string())
+            .collect()
+    }
+
+    /// Reports the error that stopped the VM, if one did.
+    fn report_error(&self, out: &mut dyn Write) -> io::Result<()> {
+        if let Some(e) = self.vm.error() {
+            writeln!(out, "{}", e.render(self.vm.debug_info()))?;
+        }
+        Ok(())
+    }
+
+    /// Where `path` is, if `.load_file` may read it.
+    fn loadable(&self, path: &str) -> io::Result<PathBuf> {
+        let refused = |reason| io::Error::new(io::ErrorKind::PermissionDenied, reason);
+        match &self.files {
+            FileAccess::Any => Ok(PathBuf::from(path)),
+            FileAccess::Within(dir) => {
+                // Canonical paths, so neither `..` nor symlinks get out.
+                let dir = dir.canonicalize()?;
+                let full = dir.join(path).canonicalize()?;
+                if full.starts_with(&dir) {
+                    Ok(full)
+                } else {
+                    Err(refused("outside the directory files are loaded from"))
+                }
+            }
+            FileAccess::Denied => Err(refused("loading files is disabled")),
+        }
+    }
+
+    fn load_file(&mut self, path: &str, out: &mut dyn Write) -> io::Result<()> {
+        let filepath = Path::new(path);
+
+        let mut source = String::new();
+        let read = self
+            .loadable(path)
+            .and_then(std::fs::File::open)
+            .and_then(|mut f| f.read_to_string(&mut source));
+        if let Err(e) = read {
+            return writeln!(out, "Unable to open {}: {}", filepath.display(), e);
+        }
+
+        if !self.assemble_into_vm(&source, Some(&filepath.to_string_lossy()), out)? {
+            writeln!(out, "Unable to assemble input")?;
+        }
+        Ok(())
+    }
+
+    fn symbols(&self, out: &mut dyn Write) -> io::Result<()> {
+        let symbols = self.asm.symbols.sorted();
+        if symbols.is_empty() {
+            writeln!(out, "No symbols")?;
+        }
+        for s in symbols {
+            writeln!(
+                out,
+                "{:<16} {:<6} {:04X} {}",
+                s.name(),
+                s.symbol_type(),
+                s.offset(),
+                s.section()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// `.registers [$r ...]`
+    fn registers(&self, args: &str, out: &mut dyn Write) -> io::Result<()> {
+        if args.is_empty() {
+            writeln!(out, "In VM's registers:")?;
+            return writeln!(out, "{:?}", &self.vm.registers);
+        }
+        for word in args.split_whitespace() {
+            let register = word
+                .strip_prefix('$')
+                .and_then(|r| r.parse::<usize>().ok())
+                .filter(|r| *r < self.vm.registers.len());
+            match register {
+                Some(r) => writeln!(out, "${} = {}", r, self.vm.registers[r])?,
+                None => writeln!(out, "No register {}", word)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// `.heap [start..end]`, sixteen bytes to a row.
+    fn heap(&self, args: &str, out: &mut dyn Write) -> io::Result<()> {
+        let heap = self.vm.heap();
+        let range = if args.is_empty() {
+            Some((0, heap.len()))
+        } else {
+            let mut bounds = args.splitn(2, "..").map(|b| b.trim().parse::<usize>());
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(start)), Some(Ok(end))) if start <= end => Some((start, end)),
+                _ => None,
+            }
+        };
+        let (start, end) = match range {
+            Some((start, end)) => (start.min(heap.len()), end.min(heap.len())),
+            None => return writeln!(out, "Usage: .heap [start..end]"),
+        };
+        writeln!(out, "Heap is {} bytes", heap.len())?;
+        for (row, bytes) in heap[start..end].chunks(16).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(out, "{:04X}: {}", start + row * 16, hex.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// `.step [N]`
+    fn step(&mut self, args: &str, out: &mut dyn Write) -> io::Result<()> {
+        let count = if args.is_empty() {
+            Ok(1)
+        } else {
+            args.parse::<usize>()
+        };
+        match count {
+            Ok(count) => {
+                self.limit_run();
+                for _ in 0..count {
+                    if !self.vm.run_once() {
+                        break;
                     }
                 }
-                ".clear_program" => {
-                    println!("Clearing the following program:");
-                    println!("{:?}", &self.vm.program);
-                    self.vm.program.clear();
+                self.report_error(out)?;
+                self.pc(out)
+            }
+            Err(_) => writeln!(out, "Usage: .step [N]"),
+        }
+    }
+
+    /// `.events [N]`
+    fn events(&self, args: &str, out: &mut dyn Write) -> io::Result<()> {
+        let count = if args.is_empty() {
+            Ok(10)
+        } else {
+            args.parse::<usize>()
+        };
+        match count {
+            Ok(count) => {
+                let events = self.vm.events();
+                for event in events.iter().skip(events.len().saturating_sub(count)) {
+                    writeln!(out, "{}", event)?;
                 }
-                ".program" => {
-                    println!("In VM's program vector:");
-                    println!("{:?}", &self.vm.program);
+                Ok(())
+            }
+            Err(_) => writeln!(out, "Usage: .events [N]"),
+        }
+    }
+
+    fn pc(&self, out: &mut dyn Write) -> io::Result<()> {
+        let pc = self.vm.pc();
+        match self.vm.debug_info().and_then(|d| d.location(pc)) {
+            Some(location) => writeln!(out, "pc {} at {}", pc, location),
+            None => writeln!(out, "pc {}", pc),
+        }
+    }
+
+    /// `.set $r value`
+    fn set(&mut self, args: &str, out: &mut dyn Write) -> io::Result<()> {
+        let mut words = args.split_whitespace();
+        let register = words
+            .next()
+            .and_then(|r| r.strip_prefix('$'))
+            .and_then(|r| r.parse::<usize>().ok())
+            .filter(|r| *r < self.vm.registers.len());
+        let value = words.next().and_then(|v| v.parse::<i32>().ok());
+        match (register, value, words.next()) {
+            (Some(register), Some(value), None) => self.vm.registers[register] = value,
+            _ => writeln!(out, "Usage: .set $r value")?,
+        }
+        Ok(())
+    }
+
+    /// `.help [command]`
+    fn help(args: &str, out: &mut dyn Write) -> io::Result<()> {
+        let wanted = match args {
+            "" => None,
+            name if name.starts_with('.') => Some(name.to_string()),
+            name => Some(format!(".{}", name)),
+        };
+        let mut found = false;
+        for (name, usage, description) in COMMANDS {
+            if wanted.as_ref().is_none_or(|w| w == name) {
+                let command = format!("{} {}", name, usage);
+                writeln!(out, "{:<24} {}", command.trim_end(), description)?;
+                found = true;
+            }
+        }
+        if !found {
+            writeln!(out, "Unknown command {}", args)?;
+        }
+        Ok(())
+    }
+
+    /// Runs one complete entry: a dot-command, or code to assemble and run.
+    /// Returns whether the session goes on.
+    pub fn execute(&mut self, entry: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let buffer = entry.trim();
+        if buffer.is_empty() {
+            return Ok(true);
+        }
+        self.command_buffer.push(buffer.to_string());
+
+        let mut words = buffer.splitn(2, char::is_whitespace);
+        let command = words.next().unwrap_or("");
+        let args = words.next().unwrap_or("").trim();
+        match command {
+            ".load_file" if args.is_empty() => writeln!(out, "Usage: .load_file path")?,
+            ".load_file" => self.load_file(args, out)?,
+            ".clear_program" => {
+                writeln!(out, "Clearing the following program:")?;
+                writeln!(out, "{:?}", &self.vm.program)?;
+                self.vm.program.clear();
+                self.vm.reset();
+                self.asm.symbols = SymbolTable::new();
+            }
+            ".program" => {
+                writeln!(out, "In VM's program vector:")?;
+                writeln!(out, "{:?}", &self.vm.program)?;
+            }
+            ".registers" => self.registers(args, out)?,
+            ".quit" => {
+                writeln!(out, "exiting")?;
+                return Ok(false);
+            }
+            ".history" => {
+                writeln!(out, "{:?}", &self.command_buffer)?;
+            }
+            ".trace" => {
+                writeln!(out, "Executed instructions:")?;
+                for line in self.vm.render_trace() {
+                    writeln!(out, "{}", line)?;
                 }
-                ".registers" => {
-                    println!("In VM's registers:");
-                    println!("{:?}", &self.vm.registers);
+            }
+            ".symbols" => self.symbols(out)?,
+            ".heap" => self.heap(args, out)?,
+            ".reset" => {
+                self.vm.reset();
+                writeln!(out, "VM reset, program kept")?;
+            }
+            ".run" => {
+                self.run_vm();
+                self.report_error(out)?;
+            }
+            ".step" => self.step(args, out)?,
+            ".pc" => self.pc(out)?,
+            ".events" => self.events(args, out)?,
+            ".flags" => {
+                writeln!(out, "equal_flag: {}", self.vm.equal_flag())?;
+                writeln!(out, "overflow_flag: {}", self.vm.overflow_flag())?;
+                writeln!(out, "remainder: {}", self.vm.remainder())?;
+            }
+            ".set" => self.set(args, out)?,
+            ".overflow" if args.is_empty() => writeln!(out, "{}", self.vm.overflow_mode())?,
+            ".overflow" => match args.parse() {
+                Ok(mode) => self.vm.set_overflow_mode(mode),
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            ".help" => REPL::help(args, out)?,
+            _ => {
+                // // hex speaking repl
+                // self.parse_hex(buffer)
+                //     .iter()
+                //     .for_each(|byte| self.vm.add_byte(*byte));
+                // self.vm.run_once();
+
+                if self.assemble_into_vm(buffer, None, out)? {
+                    self.run_vm();
+                    self.report_error(out)?;
+                } else {
+                    writeln!(out, "Unable to parse input")?;
                 }
-                ".quit" => {
-                    println!("exiting");
-                    std::process::exit(0);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Runs a session without a terminal, reading entries from `input` and
+    /// writing everything to `output`. Code that opens with a label is read
+    /// up to the next blank line, as it is interactively.
+    pub fn run_with<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        let mut pending = String::new();
+        for line in input.lines() {
+            let line = line?;
+            if !pending.is_empty() {
+                pending.push('\n');
+            }
+            pending.push_str(&line);
+            if !self.is_complete(&pending) {
+                continue;
+            }
+            let entry = std::mem::take(&mut pending);
+            if !self.execute(&entry, &mut output)? {
+                return Ok(());
+            }
+        }
+        self.execute(&pending, &mut output)?;
+        Ok(())
+    }
+
+    /// Whether `entry` can run as it is, or more lines should be read first.
+    pub fn is_complete(&self, entry: &str) -> bool {
+        ReplHelper {
+            labels: self.label_names(),
+        }
+        .is_complete(entry)
+    }
+
+    pub fn run(&mut self) {
+        println!("<welcome_message>");
+        let mut rl: Editor<ReplHelper, DefaultHistory> =
+            Editor::new().expect("Unable to start the line editor");
+        rl.set_helper(Some(ReplHelper::default()));
+        let history = REPL::history_path();
+        if let Some(path) = &history {
+            let _ = rl.load_history(path);
+        }
+
+        let mut stdout = io::stdout();
+        loop {
+            if let Some(helper) = rl.helper_mut() {
+                helper.labels = self.label_names();
+            }
+            let mut buffer = match rl.readline(">>> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    println!("Unable to read line from user: {}", e);
+                    break;
                 }
-                ".history" => {
-                    println!("{:?}", &self.command_buffer);
+            };
+            if buffer.trim().is_empty() {
+                continue;
+            }
+            let _ = rl.add_history_entry(buffer.trim());
+
+            if buffer.trim() == ".load_file" {
+                match rl.readline("Enter path to source file: ") {
+                    Ok(path) => buffer = format!(".load_file {}", path.trim()),
+                    Err(_) => continue,
                 }
-                _ => {
-                    // // hex speaking repl
-                    // self.parse_hex(buffer)
-                    //     .iter()
-                    //     .for_each(|byte| self.vm.add_byte(*byte));
-                    // self.vm.run_once();
-
-                    if let Ok((_rest, parsed_program)) = program(CompleteStr(buffer)) {
-                        let bytecode = parsed_program.to_bytes(&self.asm.symbols);
-                        bytecode.iter().for_each(|byte| self.vm.add_byte(*byte));
-                        self.vm.run_once();
-                    } else {
-                        println!("Unable to parse input");
-                    }
+            }
+            match self.execute(&buffer, &mut stdout) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    println!("Unable to write output: {}", e);
+                    break;
                 }
             }
         }
+
+        if let Some(path) = &history {
+            if let Err(e) = rl.save_history(path) {
+                println!("Unable to save history: {}", e);
+            }
+        }
     }
 }
@@ -0,0 +1,228 @@
+//! REPL sessions written down as text, to be replayed as regression tests.
+//!
+//! Each entry starts with a `>>> ` line, may go on over `... ` lines, and is
+//! followed by the output it should produce:
+//!
+//! ```text
+//! Loads a constant.
+//! >>> load $0 #5
+//! >>> .registers $0
+//! $0 = 5
+//! ```
+//!
+//! Anything before the first entry describes the transcript and is ignored.
+
+use super::REPL;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extension of transcript files picked up by `check_dir`.
+pub const EXTENSION: &str = "repl";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Entry {
+    /// One-based line of the `>>>` in the transcript.
+    pub line: usize,
+    pub input: String,
+    pub expected: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Transcript {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DiffLine {
+    Same(String),
+    Expected(String),
+    Actual(String),
+}
+
+/// An entry whose output was not what the transcript says.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub line: usize,
+    pub input: String,
+    pub diff: Vec<DiffLine>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "line {}: output of `{}` differs (- expected, + actual)",
+            self.line,
+            self.input.replace('\n', " / ")
+        )?;
+        for line in &self.diff {
+            match line {
+                DiffLine::Same(text) => writeln!(f, "  {}", text)?,
+                DiffLine::Expected(text) => writeln!(f, "- {}", text)?,
+                DiffLine::Actual(text) => writeln!(f, "+ {}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transcript {
+    pub fn parse(text: &str) -> Transcript {
+        let mut transcript = Transcript::default();
+        for (index, line) in text.lines().enumerate() {
+            if let Some(input) = line.strip_prefix(">>>") {
+                transcript.entries.push(Entry {
+                    line: index + 1,
+                    input: input.trim().to_string(),
+                    expected: vec![],
+                });
+                continue;
+            }
+            let entry = match transcript.entries.last_mut() {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match line.strip_prefix("...") {
+                Some(more) if entry.expected.is_empty() => {
+                    entry.input.push('\n');
+                    entry.input.push_str(more.trim());
+                }
+                _ => entry.expected.push(line.to_string()),
+            }
+        }
+        transcript
+    }
+
+    /// Replays the entries in a fresh REPL, stopping at the first mismatch.
+    pub fn run(&self) -> Result<(), Mismatch> {
+        let mut repl = REPL::new();
+        for entry in &self.entries {
+            let mut output = vec![];
+            let going_on = repl
+                .execute(&entry.input, &mut output)
+                .expect("writing to a Vec cannot fail");
+            let actual: Vec<String> = String::from_utf8_lossy(&output)
+                .lines()
+                .map(str::to_string)
+                .collect();
+            if actual != entry.expected {
+                return Err(Mismatch {
+                    line: entry.line,
+                    input: entry.input.clone(),
+                    diff: diff(&entry.expected, &actual),
+                });
+            }
+            if !going_on {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Line diff of `expected` against `actual`, by longest common subsequence.
+pub fn diff(expected: &[String], actual: &[String]) -> Vec<DiffLine> {
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            lines.push(DiffLine::Same(expected[i].clone()));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(DiffLine::Expected(expected[i].clone()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Actual(actual[j].clone()));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Runs every `.repl` transcript in `dir`, in name order.
+pub fn check_dir(dir: &Path) -> io::Result<Vec<(PathBuf, Result<(), Mismatch>)>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|p| p.extension().is_some_and(|e| e == EXTENSION));
+    paths.sort();
+
+    let mut results = vec![];
+    for path in paths {
+        let transcript = Transcript::parse(&fs::read_to_string(&path)?);
+        results.push((path, transcript.run()));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let t = Transcript::parse(
+            "About.\n>>> load $0 #1\n>>> top: inc $0\n... hlt\n...\n>>> .registers $0\n$0 = 2\n",
+        );
+        assert_eq!(t.entries.len(), 3);
+        assert_eq!(t.entries[1].input, "top: inc $0\nhlt\n");
+        assert_eq!(t.entries[2].line, 6);
+        assert_eq!(t.entries[2].expected, vec!["$0 = 2"]);
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let t = Transcript::parse(">>> load $0 #1\n>>> .registers $0 $1\n$0 = 2\n$1 = 0\n");
+        let mismatch = t.run().unwrap_err();
+        assert_eq!(mismatch.line, 2);
+        assert_eq!(
+            mismatch.to_string(),
+            "line 2: output of `.registers $0 $1` differs (- expected, + actual)\n- $0 = 2\n+ $0 = 1\n  $1 = 0\n"
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let d = diff(&lines("a\nb\nc"), &lines("a\nc\nd"));
+        assert_eq!(
+            d,
+            vec![
+                DiffLine::Same("a".to_string()),
+                DiffLine::Expected("b".to_string()),
+                DiffLine::Same("c".to_string()),
+                DiffLine::Actual("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcripts() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/transcripts");
+        let results = check_dir(&dir).unwrap();
+        assert!(!results.is_empty());
+        for (path, result) in results {
+            if let Err(mismatch) = result {
+                panic!("{}: {}", path.display(), mismatch);
+            }
+        }
+    }
+}
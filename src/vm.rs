@@ -1,18 +1,245 @@
-use super::instruction::Opcode;
+pub mod events;
 
-#[derive(Debug, Default)]
+pub use self::events::{Limit, VMEvent, VMEventKind, EVENT_LOG_LEN};
+
+use super::abi;
+use super::debug_info::DebugInfo;
+use super::instruction::{JumpMode, Opcode};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use uuid::Uuid;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VMError {
+    /// The byte at `pc` is not a known opcode.
+    IllegalOpcode {
+        pc: usize,
+        byte: u8,
+    },
+    /// The instruction starting at `pc` runs past the end of the program.
+    UnexpectedEnd {
+        pc: usize,
+    },
+    /// The instruction at `pc` would go past a limit set on the VM.
+    LimitReached {
+        pc: usize,
+        limit: Limit,
+    },
+    /// Arithmetic at `pc` overflowed with `OverflowMode::Trapping`.
+    Overflow {
+        pc: usize,
+    },
+    DivideByZero {
+        pc: usize,
+    },
+    /// The jump at `pc` has a mode byte that is not a `JumpMode`.
+    IllegalJumpMode {
+        pc: usize,
+        byte: u8,
+    },
+    /// The jump at `pc` would leave the program.
+    JumpOutOfRange {
+        pc: usize,
+        target: i64,
+    },
+    /// The `push` or `pop` at `pc` would go outside the heap.
+    StackOutOfBounds {
+        pc: usize,
+        sp: i32,
+    },
+    /// The instruction at `pc` names a register above `$31`.
+    IllegalRegister {
+        pc: usize,
+        byte: u8,
+    },
+    /// The `aloc` at `pc` asked for a negative number of bytes, or for more
+    /// than an `i32` can address.
+    BadAllocation {
+        pc: usize,
+        bytes: i32,
+    },
+}
+
+impl VMError {
+    /// Offset of the instruction that failed.
+    pub fn pc(&self) -> usize {
+        match self {
+            VMError::IllegalOpcode { pc, .. }
+            | VMError::UnexpectedEnd { pc }
+            | VMError::LimitReached { pc, .. }
+            | VMError::Overflow { pc }
+            | VMError::DivideByZero { pc }
+            | VMError::IllegalJumpMode { pc, .. }
+            | VMError::JumpOutOfRange { pc, .. }
+            | VMError::StackOutOfBounds { pc, .. }
+            | VMError::IllegalRegister { pc, .. }
+            | VMError::BadAllocation { pc, .. } => *pc,
+        }
+    }
+
+    /// The error followed by where it happened, e.g. `... at loop.iasm:12:3`.
+    pub fn render(&self, debug: Option<&DebugInfo>) -> String {
+        format!("{} {}", self, DebugInfo::describe(debug, self.pc()))
+    }
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VMError::IllegalOpcode { byte, .. } => write!(f, "illegal opcode {}", byte),
+            VMError::UnexpectedEnd { .. } => write!(f, "instruction runs past end of program"),
+            VMError::LimitReached { limit, .. } => write!(f, "limit of {} reached", limit),
+            VMError::Overflow { .. } => write!(f, "integer overflow"),
+            VMError::DivideByZero { .. } => write!(f, "division by zero"),
+            VMError::IllegalJumpMode { byte, .. } => write!(f, "illegal jump mode {}", byte),
+            VMError::JumpOutOfRange { target, .. } => {
+                write!(f, "jump to {} is outside the program", target)
+            }
+            VMError::StackOutOfBounds { sp, .. } => {
+                write!(f, "stack pointer {} is outside the heap", sp)
+            }
+            VMError::IllegalRegister { byte, .. } => write!(f, "illegal register {}", byte),
+            VMError::BadAllocation { bytes, .. } => {
+                write!(f, "cannot allocate {} heap bytes", bytes)
+            }
+        }
+    }
+}
+
+/// What arithmetic does when the result does not fit in an `i32`. The
+/// overflow flag is set either way.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OverflowMode {
+    #[default]
+    Wrapping,
+    Saturating,
+    /// Stops execution with `VMError::Overflow`.
+    Trapping,
+}
+
+impl OverflowMode {
+    pub const NAMES: [&'static str; 3] = ["wrapping", "saturating", "trapping"];
+}
+
+impl fmt::Display for OverflowMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(OverflowMode::NAMES[*self as usize])
+    }
+}
+
+impl std::str::FromStr for OverflowMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OverflowMode, String> {
+        match s {
+            "wrapping" => Ok(OverflowMode::Wrapping),
+            "saturating" => Ok(OverflowMode::Saturating),
+            "trapping" => Ok(OverflowMode::Trapping),
+            _ => Err(format!(
+                "unknown overflow mode `{}`, expected one of {}",
+                s,
+                OverflowMode::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Result of a bitwise, shift or rotate instruction on `a` and `b`, or
+/// `None` if `opcode` is not one. `NOT` ignores `b`.
+///
+/// Shift amounts are taken as unsigned, so negative ones count as huge. A
+/// shift by 32 or more moves every bit out: `SHL` and `SHR` give 0 and `SAR`
+/// gives 0 or -1 by the sign of `a`. Rotates go round by the amount mod 32.
+pub fn bitwise(opcode: Opcode, a: i32, b: i32) -> Option<i32> {
+    let amount = b as u32;
+    Some(match opcode {
+        Opcode::AND => a & b,
+        Opcode::OR => a | b,
+        Opcode::XOR => a ^ b,
+        Opcode::NOT => !a,
+        Opcode::SHL => a.checked_shl(amount).unwrap_or(0),
+        Opcode::SHR => (a as u32).checked_shr(amount).unwrap_or(0) as i32,
+        Opcode::SAR => a.checked_shr(amount).unwrap_or(a >> 31),
+        Opcode::ROL => a.rotate_left(amount),
+        Opcode::ROR => a.rotate_right(amount),
+        _ => return None,
+    })
+}
+
+/// How many trace entries a VM keeps; older ones are dropped.
+pub const TRACE_LEN: usize = 1024;
+
+/// One executed instruction, as recorded when tracing is enabled.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub opcode: Opcode,
+}
+
+#[derive(Debug)]
 pub struct VM {
+    id: Uuid,
     pub registers: [i32; 32],
     pub program: Vec<u8>,
     heap: Vec<u8>,
     pc: usize,
     remainder: u32,
     equal_flag: bool,
+    /// Whether the last arithmetic instruction overflowed.
+    overflow_flag: bool,
+    overflow_mode: OverflowMode,
+    /// Start of the instruction being executed.
+    instruction_pc: usize,
+    error: Option<VMError>,
+    debug_info: Option<DebugInfo>,
+    tracing: bool,
+    /// The most recently executed instructions, oldest first.
+    trace: VecDeque<TraceEntry>,
+    /// The most recent events, oldest first.
+    events: VecDeque<VMEvent>,
+    subscribers: Vec<Sender<VMEvent>>,
+    /// Instructions executed since creation or the last reset.
+    executed: u64,
+    instruction_limit: Option<u64>,
+    heap_limit: Option<usize>,
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM::default()
+        VM {
+            id: Uuid::new_v4(),
+            registers: [0; 32],
+            program: vec![],
+            heap: vec![],
+            pc: 0,
+            remainder: 0,
+            equal_flag: false,
+            overflow_flag: false,
+            overflow_mode: OverflowMode::default(),
+            instruction_pc: 0,
+            error: None,
+            debug_info: None,
+            tracing: false,
+            trace: VecDeque::new(),
+            events: VecDeque::new(),
+            subscribers: vec![],
+            executed: 0,
+            instruction_limit: None,
+            heap_limit: None,
+        }
+    }
+
+    /// Identifies the VM in its events.
+    pub fn id(&self) -> Uuid {
+        self.id
     }
 
     /// New VM with registers preset with values.
@@ -33,6 +260,134 @@ impl VM {
         self.program.append(&mut b);
     }
 
+    /// Debug info used to render errors and traces as source locations.
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Offset of the next instruction to execute.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn overflow_flag(&self) -> bool {
+        self.overflow_flag
+    }
+
+    pub fn overflow_mode(&self) -> OverflowMode {
+        self.overflow_mode
+    }
+
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow_mode = mode;
+    }
+
+    /// Remainder left by the last `DIV`.
+    pub fn remainder(&self) -> u32 {
+        self.remainder
+    }
+
+    /// Back to a fresh start, keeping the program and its debug info.
+    pub fn reset(&mut self) {
+        self.registers = [0; 32];
+        self.heap.clear();
+        self.pc = 0;
+        self.remainder = 0;
+        self.equal_flag = false;
+        self.overflow_flag = false;
+        self.instruction_pc = 0;
+        self.error = None;
+        self.executed = 0;
+        self.trace.clear();
+    }
+
+    /// Stops execution with `VMError::LimitReached` once `limit` is hit.
+    pub fn set_limit(&mut self, limit: Limit) {
+        match limit {
+            Limit::Instructions(n) => self.instruction_limit = Some(n),
+            Limit::Heap(n) => self.heap_limit = Some(n),
+        }
+    }
+
+    /// Instructions executed since creation or the last reset.
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// The last `EVENT_LOG_LEN` events, oldest first.
+    pub fn events(&self) -> &VecDeque<VMEvent> {
+        &self.events
+    }
+
+    /// A channel that gets every event from now on. Dropping the receiver
+    /// unsubscribes.
+    pub fn subscribe(&mut self) -> Receiver<VMEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// A new VM running `program`, whose events go to this VM's subscribers
+    /// too.
+    pub fn spawn(&mut self, program: Vec<u8>) -> VM {
+        let mut child = VM::new();
+        child.program = program;
+        child.subscribers = self.subscribers.clone();
+        self.emit(VMEventKind::Spawn { child: child.id });
+        child
+    }
+
+    fn emit(&mut self, kind: VMEventKind) {
+        let event = VMEvent::new(self.id, kind);
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        if self.events.len() == EVENT_LOG_LEN {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// The error that stopped the last `run` or `run_once`, if any.
+    pub fn error(&self) -> Option<&VMError> {
+        self.error.as_ref()
+    }
+
+    /// Start recording executed instructions, keeping the last `TRACE_LEN`.
+    pub fn enable_tracing(&mut self) {
+        self.tracing = true;
+    }
+
+    pub fn trace(&self) -> &VecDeque<TraceEntry> {
+        &self.trace
+    }
+
+    /// The trace, one line per instruction, with source locations if known.
+    pub fn render_trace(&self) -> Vec<String> {
+        self.trace()
+            .iter()
+            .map(|t| {
+                format!(
+                    "{:04X} {:?} {}",
+                    t.pc,
+                    t.opcode,
+                    DebugInfo::describe(self.debug_info.as_ref(), t.pc)
+                )
+            })
+            .collect()
+    }
+
     // pub fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
     //     let mut prepension = vec![];
     //     for byte in &PIE_HEADER_PREFIX {
@@ -49,160 +404,374 @@ impl VM {
     // }
 
     pub fn run(&mut self) {
-        let mut executing = Some(());
+        self.error = None;
+        self.emit(VMEventKind::Start);
+        while self.step() {}
+    }
 
-        while executing.is_some() {
-            executing = self.execute_instruction();
+    /// Executes one instruction. Returns whether there is more to run.
+    pub fn run_once(&mut self) -> bool {
+        self.error = None;
+        if self.executed == 0 {
+            self.emit(VMEventKind::Start);
         }
+        self.step()
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Executes one instruction, recording any error. Returns whether to go on.
+    fn step(&mut self) -> bool {
+        match self.execute_instruction() {
+            Ok(true) => true,
+            Ok(false) => {
+                self.emit(VMEventKind::Halt);
+                false
+            }
+            Err(e) => {
+                self.emit(match &e {
+                    VMError::LimitReached { limit, .. } => VMEventKind::LimitReached(*limit),
+                    e => VMEventKind::Crash(e.clone()),
+                });
+                self.error = Some(e);
+                false
+            }
+        }
     }
 
-    fn execute_instruction(&mut self) -> Option<()> {
+    fn execute_instruction(&mut self) -> Result<bool, VMError> {
         if self.pc >= self.program.len() {
-            return None;
+            return Ok(false);
         }
 
-        match self.decode_opcode() {
+        self.instruction_pc = self.pc;
+        if let Some(max) = self.instruction_limit.filter(|max| self.executed >= *max) {
+            return Err(VMError::LimitReached {
+                pc: self.pc,
+                limit: Limit::Instructions(max),
+            });
+        }
+        self.executed += 1;
+        let opcode = self.decode_opcode();
+        if self.tracing {
+            if self.trace.len() == TRACE_LEN {
+                self.trace.pop_front();
+            }
+            self.trace.push_back(TraceEntry {
+                pc: self.instruction_pc,
+                opcode,
+            });
+        }
+
+        match opcode {
             Opcode::LOAD => {
-                let i = self.next_8_bits() as usize;
-                let number = i32::from(self.next_16_bits());
+                let i = self.next_register()?;
+                let number = i32::from(self.next_16_bits()?);
 
                 self.registers[i] = number;
             }
-            Opcode::ADD => {
-                let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
-                );
-
-                self.registers[self.next_8_bits() as usize] = r1 + r2;
+            Opcode::LOAD32 => {
+                let i = self.next_register()?;
+                self.registers[i] = self.next_32_bits()? as i32;
             }
-            Opcode::SUB => {
-                let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
-                );
-
-                self.registers[self.next_8_bits() as usize] = r1 - r2;
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => {
+                let r1 = self.registers[self.next_register()?];
+                let d = self.next_register()?;
+                let imm = i32::from(self.next_16_bits()? as i16);
+                let op = match opcode {
+                    Opcode::ADDI => Opcode::ADD,
+                    Opcode::SUBI => Opcode::SUB,
+                    _ => Opcode::MUL,
+                };
+                self.registers[d] = self.arithmetic(op, r1, imm)?;
             }
-            Opcode::MUL => {
-                let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
-                );
-
-                self.registers[self.next_8_bits() as usize] = r1 * r2;
+            Opcode::CMPI => {
+                let r1 = self.registers[self.next_register()?];
+                self.equal_flag = r1 == i32::from(self.next_16_bits()? as i16);
+            }
+            Opcode::ADD | Opcode::SUB | Opcode::MUL => {
+                let (r1, r2, d) = self.three_registers()?;
+                self.registers[d] = self.arithmetic(opcode, r1, r2)?;
             }
             Opcode::DIV => {
-                let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
-                );
-
-                self.registers[self.next_8_bits() as usize] = r1 / r2;
-                self.remainder = (r1 % r2) as u32;
+                let (r1, r2, d) = self.three_registers()?;
+                if r2 == 0 {
+                    return Err(VMError::DivideByZero {
+                        pc: self.instruction_pc,
+                    });
+                }
+                self.registers[d] = self.arithmetic(opcode, r1, r2)?;
+                self.remainder = r1.wrapping_rem(r2) as u32;
+            }
+            Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::ROL
+            | Opcode::ROR => {
+                let (r1, r2, d) = self.three_registers()?;
+                self.registers[d] = bitwise(opcode, r1, r2).expect("bitwise opcode");
+            }
+            Opcode::NOT => {
+                let r1 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = !r1;
             }
             Opcode::HLT => {
-                println!("HLT encountered");
-                return None;
+                return Ok(false);
             }
             Opcode::JMP => {
-                let r1 = self.registers[self.next_8_bits() as usize];
-                self.pc = r1 as usize;
+                let target = self.next_target()?;
+                self.jump(target)?;
             }
             Opcode::JMPF => {
-                let r1 = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc += r1;
+                let r1 = self.registers[self.next_register()?];
+                self.jump(self.pc as i64 + i64::from(r1))?;
             }
             Opcode::JMPB => {
-                let r1 = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc -= r1;
+                let r1 = self.registers[self.next_register()?];
+                self.jump(self.pc as i64 - i64::from(r1))?;
             }
             Opcode::EQ => {
                 let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
+                    self.registers[self.next_register()?],
+                    self.registers[self.next_register()?],
                 );
                 self.equal_flag = r1 == r2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::NEQ => {
                 let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
+                    self.registers[self.next_register()?],
+                    self.registers[self.next_register()?],
                 );
                 self.equal_flag = r1 != r2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::GTE => {
                 let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
+                    self.registers[self.next_register()?],
+                    self.registers[self.next_register()?],
                 );
                 self.equal_flag = r1 >= r2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::LTE => {
                 let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
+                    self.registers[self.next_register()?],
+                    self.registers[self.next_register()?],
                 );
                 self.equal_flag = r1 <= r2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::LT => {
                 let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
+                    self.registers[self.next_register()?],
+                    self.registers[self.next_register()?],
                 );
                 self.equal_flag = r1 < r2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::GT => {
                 let (r1, r2) = (
-                    self.registers[self.next_8_bits() as usize],
-                    self.registers[self.next_8_bits() as usize],
+                    self.registers[self.next_register()?],
+                    self.registers[self.next_register()?],
                 );
                 self.equal_flag = r1 > r2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::JMPE => {
+                let target = self.next_target()?;
                 if self.equal_flag {
-                    let r1 = self.registers[self.next_8_bits() as usize];
-                    self.pc = r1 as usize;
+                    self.jump(target)?;
                 }
             }
-            Opcode::ALOC => {
-                let new_len = {
-                    let r1 = self.next_8_bits() as usize;
-                    let bytes = self.registers[r1];
-                    let new_len = self.heap.len() as i32 + bytes;
-                    new_len as usize
+            Opcode::JMPNE => {
+                let target = self.next_target()?;
+                if !self.equal_flag {
+                    self.jump(target)?;
+                }
+            }
+            Opcode::BEQ | Opcode::BNE | Opcode::BLT | Opcode::BLE | Opcode::BGT | Opcode::BGE => {
+                let r1 = self.registers[self.next_register()?];
+                let r2 = self.registers[self.next_register()?];
+                let target = self.next_target()?;
+                let taken = match opcode {
+                    Opcode::BEQ => r1 == r2,
+                    Opcode::BNE => r1 != r2,
+                    Opcode::BLT => r1 < r2,
+                    Opcode::BLE => r1 <= r2,
+                    Opcode::BGT => r1 > r2,
+                    _ => r1 >= r2,
                 };
+                if taken {
+                    self.jump(target)?;
+                }
+            }
+            Opcode::ALOC => {
+                let bytes = self.registers[self.next_register()?];
+                let new_len = usize::try_from(bytes)
+                    .ok()
+                    .and_then(|bytes| self.heap.len().checked_add(bytes))
+                    .filter(|len| *len <= i32::MAX as usize)
+                    .ok_or(VMError::BadAllocation {
+                        pc: self.instruction_pc,
+                        bytes,
+                    })?;
+                if let Some(max) = self.heap_limit.filter(|max| new_len > *max) {
+                    return Err(VMError::LimitReached {
+                        pc: self.instruction_pc,
+                        limit: Limit::Heap(max),
+                    });
+                }
+                let from = self.heap.len();
                 self.heap.resize(new_len, 0);
+                if new_len > from {
+                    self.emit(VMEventKind::HeapGrow { from, to: new_len });
+                }
             }
             Opcode::INC => {
-                let r1 = self.next_8_bits() as usize;
-                self.registers[r1] += 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                let r1 = self.next_register()?;
+                self.registers[r1] = self.arithmetic(Opcode::ADD, self.registers[r1], 1)?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+            }
+            Opcode::JMPO => {
+                let target = self.next_target()?;
+                if self.overflow_flag {
+                    self.jump(target)?;
+                }
+            }
+            Opcode::REM => {
+                self.registers[self.next_register()?] = self.remainder as i32;
+            }
+            Opcode::CALL => {
+                let target = self.next_target()?;
+                let next = self.pc as i32;
+                self.jump(target)?;
+                self.registers[abi::RA as usize] = next;
+            }
+            Opcode::RET => {
+                self.jump(i64::from(self.registers[abi::RA as usize]))?;
+            }
+            Opcode::PUSH => {
+                let value = self.registers[self.next_register()?];
+                let at = self.stack_slot(-4)?;
+                self.heap[at..at + 4].copy_from_slice(&value.to_be_bytes());
+                self.registers[abi::SP as usize] = at as i32;
+            }
+            Opcode::POP => {
+                let r = self.next_register()?;
+                let at = self.stack_slot(0)?;
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&self.heap[at..at + 4]);
+                self.registers[abi::SP as usize] = at as i32 + 4;
+                self.registers[r] = i32::from_be_bytes(bytes);
             }
             _ => {
-                println!("Unrecognized opcode found. Terminating!");
-                return None;
+                return Err(VMError::IllegalOpcode {
+                    pc: self.instruction_pc,
+                    byte: self.program[self.instruction_pc],
+                });
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Operands `$a $b $d`: the values of `$a` and `$b`, and `d`. All are
+    /// read before anything can trap, so execution can resume after it.
+    fn three_registers(&mut self) -> Result<(i32, i32, usize), VMError> {
+        let a = self.registers[self.next_register()?];
+        let b = self.registers[self.next_register()?];
+        Ok((a, b, self.next_register()?))
+    }
+
+    /// `a op b` under the overflow mode, setting the overflow flag.
+    fn arithmetic(&mut self, opcode: Opcode, a: i32, b: i32) -> Result<i32, VMError> {
+        let (wrapped, overflowed) = match opcode {
+            Opcode::ADD => a.overflowing_add(b),
+            Opcode::SUB => a.overflowing_sub(b),
+            Opcode::MUL => a.overflowing_mul(b),
+            _ => a.overflowing_div(b),
+        };
+        self.overflow_flag = overflowed;
+        match self.overflow_mode {
+            _ if !overflowed => Ok(wrapped),
+            OverflowMode::Wrapping => Ok(wrapped),
+            OverflowMode::Saturating => Ok(match opcode {
+                Opcode::ADD => a.saturating_add(b),
+                Opcode::SUB => a.saturating_sub(b),
+                Opcode::MUL => a.saturating_mul(b),
+                _ => a.saturating_div(b),
+            }),
+            OverflowMode::Trapping => Err(VMError::Overflow {
+                pc: self.instruction_pc,
+            }),
+        }
+    }
+
+    /// Heap offset of the stack slot `offset` bytes from `$sp`, checking
+    /// that all four of its bytes are in the heap.
+    fn stack_slot(&self, offset: i64) -> Result<usize, VMError> {
+        let sp = self.registers[abi::SP as usize];
+        let at = i64::from(sp) + offset;
+        if at < 0 || at + 4 > self.heap.len() as i64 {
+            return Err(VMError::StackOutOfBounds {
+                pc: self.instruction_pc,
+                sp,
+            });
+        }
+        Ok(at as usize)
+    }
+
+    /// Reads a jump target operand, returning the code offset it names.
+    fn next_target(&mut self) -> Result<i64, VMError> {
+        let byte = self.next_8_bits()?;
+        match JumpMode::from_u8(byte) {
+            Some(JumpMode::Absolute) => Ok(i64::from(self.next_16_bits()?)),
+            Some(JumpMode::Relative) => {
+                let displacement = self.next_16_bits()? as i16;
+                Ok(self.instruction_pc as i64 + i64::from(displacement))
             }
+            Some(JumpMode::Register) => Ok(i64::from(self.registers[self.next_register()?])),
+            None => Err(VMError::IllegalJumpMode {
+                pc: self.instruction_pc,
+                byte,
+            }),
+        }
+    }
+
+    /// Continues at `target`, which may be the end of the program but not
+    /// beyond it.
+    fn jump(&mut self, target: i64) -> Result<(), VMError> {
+        if target < 0 || target > self.program.len() as i64 {
+            return Err(VMError::JumpOutOfRange {
+                pc: self.instruction_pc,
+                target,
+            });
         }
+        self.pc = target as usize;
+        Ok(())
+    }
 
-        Some(())
+    /// Reads a register operand, returning its index into `registers`.
+    fn next_register(&mut self) -> Result<usize, VMError> {
+        let byte = self.next_8_bits()?;
+        if usize::from(byte) >= self.registers.len() {
+            return Err(VMError::IllegalRegister {
+                pc: self.instruction_pc,
+                byte,
+            });
+        }
+        Ok(usize::from(byte))
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+    fn next_8_bits(&mut self) -> Result<u8, VMError> {
+        let result = *self.program.get(self.pc).ok_or(VMError::UnexpectedEnd {
+            pc: self.instruction_pc,
+        })?;
         self.pc += 1;
-        result
+        Ok(result)
     }
 
     // fn program_byte_at(&self, index: usize) -> Option<u16> {
@@ -213,11 +782,14 @@ impl VM {
     //     }
     // }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result =
-            ((u16::from(self.program[self.pc])) << 8) | u16::from(self.program[self.pc + 1]);
-        self.pc += 2;
-        result
+    fn next_16_bits(&mut self) -> Result<u16, VMError> {
+        let (high, low) = (self.next_8_bits()?, self.next_8_bits()?);
+        Ok((u16::from(high) << 8) | u16::from(low))
+    }
+
+    fn next_32_bits(&mut self) -> Result<u32, VMError> {
+        let (high, low) = (self.next_16_bits()?, self.next_16_bits()?);
+        Ok((u32::from(high) << 16) | u32::from(low))
     }
 
     fn decode_opcode(&mut self) -> Opcode {
@@ -337,17 +909,79 @@ mod tests {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 7;
         test_vm.equal_flag = true;
-        test_vm.program = vec![15, 0, 0, 0, 42, 0, 0, 0, 99, 0, 0, 0];
+        test_vm.program = vec![15, 2, 0, 42, 0, 0, 0, 99, 0, 0, 0];
         test_vm.run_once();
         assert_eq!(test_vm.pc, 7);
     }
+    #[test]
+    fn test_opcode_jmpne() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![30, 2, 0, 30, 2, 0, 0, 5];
+        test_vm.equal_flag = true;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 3);
+        test_vm.equal_flag = false;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 7);
+    }
+
+    #[test]
+    fn test_opcode_branches() {
+        // $0 = 5, $1 = 10; each branch goes back to the start when taken.
+        let cases = [
+            (Opcode::BEQ, false),
+            (Opcode::BNE, true),
+            (Opcode::BLT, true),
+            (Opcode::BLE, true),
+            (Opcode::BGT, false),
+            (Opcode::BGE, false),
+        ];
+        for (opcode, taken) in cases.iter() {
+            let mut test_vm = VM::get_test_vm();
+            test_vm.program = vec![*opcode as u8, 0, 1, 1, 0, 0];
+            test_vm.run_once();
+            assert_eq!(test_vm.pc, if *taken { 0 } else { 6 }, "{:?}", opcode);
+            assert!(!test_vm.equal_flag);
+        }
+    }
+
     #[test]
     fn test_opcode_jmp() {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 4;
-        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.program = vec![6, 2, 0, 5, 5];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_jmp_modes() {
+        let mut test_vm = VM::get_test_vm();
+        // jmp #4, then jmp -4 back to the start.
+        test_vm.program = vec![6, 0, 0, 4, 6, 1, 0xff, 0xfc];
         test_vm.run_once();
         assert_eq!(test_vm.pc, 4);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_opcode_jmp_errors() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![6, 0, 0, 9];
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::JumpOutOfRange { pc: 0, target: 9 })
+        );
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![6, 7, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::IllegalJumpMode { pc: 0, byte: 7 })
+        );
     }
 
     #[test]
@@ -429,11 +1063,106 @@ mod tests {
         assert_eq!(test_vm.pc, 1);
     }
 
+    #[test]
+    fn test_illegal_opcode_error() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![5, 200, 0, 0];
+        test_vm.pc = 1;
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::IllegalOpcode { pc: 1, byte: 200 })
+        );
+    }
+
+    #[test]
+    fn test_illegal_register_error() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 0, 1, 0, 40, 0, 1];
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::IllegalRegister { pc: 4, byte: 40 })
+        );
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_unexpected_end_error() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 1];
+        test_vm.run();
+        assert_eq!(test_vm.error(), Some(&VMError::UnexpectedEnd { pc: 0 }));
+    }
+
+    #[test]
+    fn test_error_with_debug_info() {
+        let source = "load $0 #1\ntest: igl";
+        let mut debug = DebugInfo::new("loop.iasm");
+        debug.add_line(0, source, 0);
+        debug.add_line(4, source, 11);
+        debug.add_label("test", 4);
+
+        let mut test_vm = VM::new();
+        test_vm.set_debug_info(debug);
+        test_vm.program = vec![0, 0, 0, 1, 200];
+        test_vm.run();
+        let rendered = test_vm.error().unwrap().render(test_vm.debug_info());
+        assert_eq!(
+            rendered,
+            "illegal opcode 200 at loop.iasm:2:1 (in label `test`)"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut test_vm = VM::new();
+        test_vm.enable_tracing();
+        test_vm.program = vec![0, 0, 0, 1, 5];
+        test_vm.run();
+        assert_eq!(
+            test_vm.trace(),
+            &[
+                TraceEntry {
+                    pc: 0,
+                    opcode: Opcode::LOAD
+                },
+                TraceEntry {
+                    pc: 4,
+                    opcode: Opcode::HLT
+                },
+            ]
+        );
+        assert_eq!(test_vm.render_trace()[1], "0004 HLT at pc 4");
+
+        // A long loop keeps only the last `TRACE_LEN` instructions.
+        test_vm.reset();
+        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.set_limit(Limit::Instructions(3 * TRACE_LEN as u64));
+        test_vm.run();
+        assert_eq!(test_vm.trace().len(), TRACE_LEN);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut test_vm = VM::new();
+        test_vm.enable_tracing();
+        test_vm.program = vec![0, 0, 0, 1, 5];
+        assert!(test_vm.run_once());
+        assert_eq!(test_vm.pc(), 4);
+        assert!(!test_vm.run_once());
+        test_vm.reset();
+        assert_eq!(test_vm.pc(), 0);
+        assert_eq!(test_vm.registers[0], 0);
+        assert!(test_vm.trace().is_empty());
+        assert_eq!(test_vm.program.len(), 5);
+    }
+
     #[test]
     fn test_create_vm() {
         let test_vm = VM::new();
         assert_eq!(test_vm.registers[31], 0);
-        assert_eq!(test_vm.program, vec![]);
+        assert_eq!(test_vm.program, Vec::<u8>::new());
         assert_eq!(test_vm.pc, 0);
         assert_eq!(test_vm.remainder, 0);
         assert_eq!(test_vm.equal_flag, false);
@@ -446,4 +1175,315 @@ mod tests {
         test_vm.run_once();
         assert_eq!(test_vm.heap.len(), 1024);
     }
+
+    #[test]
+    fn test_events() {
+        let mut test_vm = VM::get_test_vm();
+        let events = test_vm.subscribe();
+        test_vm.registers[0] = 16;
+        test_vm.program = vec![17, 0, 0, 0, 5, 200];
+        test_vm.run();
+        test_vm.pc = 5;
+        test_vm.run();
+
+        let kinds: Vec<VMEventKind> = events.try_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                VMEventKind::Start,
+                VMEventKind::HeapGrow { from: 0, to: 16 },
+                VMEventKind::Halt,
+                VMEventKind::Start,
+                VMEventKind::Crash(VMError::IllegalOpcode { pc: 5, byte: 200 }),
+            ]
+        );
+        assert_eq!(test_vm.events().len(), 5);
+        assert!(test_vm.events().iter().all(|e| e.vm == test_vm.id()));
+        assert!(test_vm.events()[4]
+            .to_string()
+            .ends_with(&format!("{} crash: illegal opcode 200", test_vm.id())));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut test_vm = VM::new();
+        test_vm.set_limit(Limit::Instructions(2));
+        test_vm.program = vec![18, 0, 0, 0, 18, 0, 0, 0, 18, 0, 0, 0];
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::LimitReached {
+                pc: 8,
+                limit: Limit::Instructions(2)
+            })
+        );
+        assert_eq!(
+            test_vm.events().back().unwrap().kind,
+            VMEventKind::LimitReached(Limit::Instructions(2))
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.set_limit(Limit::Heap(8));
+        test_vm.registers[0] = 16;
+        test_vm.program = vec![17, 0, 0, 0];
+        test_vm.run();
+        assert!(test_vm.heap().is_empty());
+        assert_eq!(
+            test_vm.error().unwrap().to_string(),
+            "limit of 8 heap bytes reached"
+        );
+    }
+
+    #[test]
+    fn test_bad_allocation() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -16;
+        test_vm.program = vec![17, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::BadAllocation { pc: 0, bytes: -16 })
+        );
+
+        // Past what a register can address, though each request alone fits.
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 16];
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![17, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.error().unwrap().to_string(),
+            "cannot allocate 2147483647 heap bytes"
+        );
+        assert_eq!(test_vm.heap().len(), 16);
+    }
+
+    #[test]
+    fn test_spawn() {
+        let mut parent = VM::new();
+        let events = parent.subscribe();
+        let mut child = parent.spawn(vec![5]);
+        child.run();
+        let seen: Vec<(Uuid, VMEventKind)> = events.try_iter().map(|e| (e.vm, e.kind)).collect();
+        assert_eq!(
+            seen,
+            vec![
+                (parent.id(), VMEventKind::Spawn { child: child.id() }),
+                (child.id(), VMEventKind::Start),
+                (child.id(), VMEventKind::Halt),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overflow_modes() {
+        // add $0 $1 $2
+        let program = vec![1, 0, 1, 2];
+        let run = |mode| {
+            let mut test_vm = VM::new();
+            test_vm.set_overflow_mode(mode);
+            test_vm.registers[0] = i32::MAX;
+            test_vm.registers[1] = 1;
+            test_vm.program = program.clone();
+            test_vm.run();
+            test_vm
+        };
+        let wrapped = run(OverflowMode::Wrapping);
+        assert_eq!(wrapped.registers[2], i32::MIN);
+        assert!(wrapped.overflow_flag());
+        assert_eq!(run(OverflowMode::Saturating).registers[2], i32::MAX);
+        let trapped = run(OverflowMode::Trapping);
+        assert_eq!(trapped.registers[2], 0);
+        assert_eq!(trapped.error(), Some(&VMError::Overflow { pc: 0 }));
+
+        let mut test_vm = VM::new();
+        test_vm.set_overflow_mode(OverflowMode::Trapping);
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![3, 0, 1, 2];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 6);
+        assert!(!test_vm.overflow_flag());
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![4, 0, 2, 3];
+        test_vm.run();
+        assert_eq!(test_vm.error(), Some(&VMError::DivideByZero { pc: 0 }));
+
+        // i32::MIN / -1 overflows rather than panicking.
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.set_overflow_mode(OverflowMode::Saturating);
+        test_vm.program = vec![4, 0, 1, 2];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], i32::MAX);
+        assert_eq!(test_vm.remainder(), 0);
+    }
+
+    #[test]
+    fn test_opcode_jmpo() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[2] = 0;
+        test_vm.program = vec![19, 2, 0, 19, 2, 2];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc(), 3);
+        test_vm.overflow_flag = true;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc(), 0);
+    }
+
+    #[test]
+    fn test_opcode_load32() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![41, 3, 0xff, 0xfe, 0x79, 0x60];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], -100_000);
+        assert_eq!(test_vm.pc, 6);
+    }
+
+    #[test]
+    fn test_opcode_immediates() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![
+            37, 0, 2, 0xff, 0xfd, 39, 0, 3, 0, 4, 38, 0, 4, 0, 10, 40, 4, 0xff, 0xfb,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 2);
+        assert_eq!(test_vm.registers[3], 20);
+        assert_eq!(test_vm.registers[4], -5);
+        assert!(test_vm.equal_flag);
+
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![37, 0, 1, 0, 1];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[1], i32::MIN);
+        assert!(test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let mut test_vm = VM::new();
+        // call #6; hlt; hlt; ret
+        test_vm.program = vec![42, 0, 0, 6, 5, 5, 43];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 6);
+        assert_eq!(test_vm.registers[abi::RA as usize], 4);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[abi::SP as usize] = 8;
+        // push $0; push $1; pop $2; pop $3
+        test_vm.program = vec![44, 0, 44, 1, 45, 2, 45, 3];
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[abi::SP as usize], 0);
+        assert_eq!(&test_vm.heap[..4], &[0, 0, 0, 10]);
+        test_vm.run();
+        assert_eq!(&test_vm.registers[2..4], &[10, 5]);
+        assert_eq!(test_vm.registers[abi::SP as usize], 8);
+
+        test_vm.program = vec![45, 0];
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::StackOutOfBounds { pc: 0, sp: 8 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_rem() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 17;
+        test_vm.registers[1] = 5;
+        test_vm.program = vec![4, 0, 1, 2, 20, 3];
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 3);
+        assert_eq!(test_vm.registers[3], 2);
+    }
+
+    /// Runs `opcode $0 $1 $2` with `$0 = a` and `$1 = b`.
+    fn run_binary(opcode: Opcode, a: i32, b: i32) -> i32 {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = a;
+        test_vm.registers[1] = b;
+        test_vm.program = vec![opcode as u8, 0, 1, 2];
+        test_vm.run();
+        assert_eq!(test_vm.error(), None);
+        test_vm.registers[2]
+    }
+
+    #[test]
+    fn test_bitwise() {
+        use self::Opcode::*;
+        let min = i32::MIN;
+        let cases = [
+            (AND, 0b1100, 0b1010, 0b1000),
+            (AND, -1, 0x1234, 0x1234),
+            (OR, 0b1100, 0b1010, 0b1110),
+            (OR, min, 1, min | 1),
+            (XOR, 0b1100, 0b1010, 0b0110),
+            (XOR, -1, 0x0f0f, !0x0f0f),
+            (SHL, 1, 0, 1),
+            (SHL, 1, 4, 16),
+            (SHL, 1, 31, min),
+            (SHL, 1, 32, 0),
+            (SHL, -1, 33, 0),
+            (SHL, 1, -1, 0),
+            (SHR, 256, 4, 16),
+            (SHR, -1, 28, 0xf),
+            (SHR, min, 31, 1),
+            (SHR, -1, 32, 0),
+            (SHR, -1, -5, 0),
+            (SAR, 256, 4, 16),
+            (SAR, -256, 4, -16),
+            (SAR, min, 31, -1),
+            (SAR, -7, 32, -1),
+            (SAR, 7, 32, 0),
+            (SAR, -7, -1, -1),
+            (ROL, 1, 1, 2),
+            (ROL, min, 1, 1),
+            (ROL, 0x1234_5678, 8, 0x3456_7812),
+            (ROL, 0x1234_5678, 32, 0x1234_5678),
+            (ROL, 0x1234_5678, 40, 0x3456_7812),
+            (ROR, 1, 1, min),
+            (ROR, 0x1234_5678, 8, 0x7812_3456),
+            (ROR, 0x1234_5678, 0, 0x1234_5678),
+            (ROR, 0x1234_5678, -24, 0x7812_3456),
+        ];
+        for (opcode, a, b, expected) in cases.iter() {
+            assert_eq!(
+                bitwise(*opcode, *a, *b),
+                Some(*expected),
+                "{:?} {} {}",
+                opcode,
+                a,
+                b
+            );
+            assert_eq!(run_binary(*opcode, *a, *b), *expected, "{:?}", opcode);
+        }
+        assert_eq!(bitwise(ADD, 1, 2), None);
+    }
+
+    #[test]
+    fn test_opcode_not() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0x0f0f;
+        test_vm.program = vec![Opcode::NOT as u8, 0, 1, Opcode::NOT as u8, 2, 3];
+        test_vm.run();
+        assert_eq!(test_vm.registers[1], !0x0f0f);
+        assert_eq!(test_vm.registers[3], -1);
+        assert_eq!(test_vm.pc(), 6);
+    }
 }
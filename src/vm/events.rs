@@ -0,0 +1,88 @@
+use super::VMError;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// How many events a VM keeps; older ones are dropped.
+pub const EVENT_LOG_LEN: usize = 1024;
+
+/// A bound set on a VM, which it stops at when reached.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Limit {
+    /// Instructions executed since the VM was created or reset.
+    Instructions(u64),
+    /// Heap size in bytes.
+    Heap(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Instructions(n) => write!(f, "{} instructions", n),
+            Limit::Heap(n) => write!(f, "{} heap bytes", n),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VMEventKind {
+    /// Execution began, by `run` or the first `run_once`.
+    Start,
+    /// Execution stopped without error, at `HLT` or the end of the program.
+    Halt,
+    Crash(VMError),
+    HeapGrow {
+        from: usize,
+        to: usize,
+    },
+    /// The VM started a child VM with this ID.
+    Spawn {
+        child: Uuid,
+    },
+    LimitReached(Limit),
+}
+
+impl fmt::Display for VMEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VMEventKind::Start => write!(f, "start"),
+            VMEventKind::Halt => write!(f, "halt"),
+            VMEventKind::Crash(e) => write!(f, "crash: {}", e),
+            VMEventKind::HeapGrow { from, to } => write!(f, "heap grew from {} to {}", from, to),
+            VMEventKind::Spawn { child } => write!(f, "spawned {}", child),
+            VMEventKind::LimitReached(limit) => write!(f, "limit reached: {}", limit),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VMEvent {
+    pub vm: Uuid,
+    pub at: SystemTime,
+    pub kind: VMEventKind,
+}
+
+impl VMEvent {
+    pub fn new(vm: Uuid, kind: VMEventKind) -> VMEvent {
+        VMEvent {
+            vm,
+            at: SystemTime::now(),
+            kind,
+        }
+    }
+}
+
+/// `<seconds since the epoch>.<millis> <vm> <kind>`
+impl fmt::Display for VMEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03} {} {}",
+            since.as_secs(),
+            since.subsec_millis(),
+            self.vm,
+            self.kind
+        )
+    }
+}
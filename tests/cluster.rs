@@ -0,0 +1,105 @@
+//! Cluster nodes as separate processes talking over localhost.
+
+use iridium::cluster::{request, Message, ProcessState};
+use std::io::{BufRead, BufReader};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SECRET: &str = "s3cret";
+
+/// A node process, killed when dropped.
+struct NodeProcess {
+    child: Child,
+    /// Held open so what the node prints does not hit a closed pipe.
+    _stdout: BufReader<ChildStdout>,
+    addr: String,
+}
+
+impl NodeProcess {
+    fn start(id: &str, seed: Option<&NodeProcess>) -> NodeProcess {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_iridium"));
+        command.args(["node", "--id", id, "--listen", "127.0.0.1:0"]);
+        command.args(["--heartbeat-ms", "100", "--secret", SECRET]);
+        if let Some(seed) = seed {
+            command.args(["--seed", &seed.addr]);
+        }
+        let mut child = command.stdout(Stdio::piped()).spawn().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        let addr = line.trim().rsplit(' ').next().unwrap().to_string();
+        NodeProcess {
+            child,
+            _stdout: stdout,
+            addr,
+        }
+    }
+
+    fn alive(&self) -> Vec<String> {
+        match request(&self.addr, &Message::ListMembers).unwrap() {
+            Message::Members(members) => members
+                .into_iter()
+                .filter(|m| m.alive)
+                .map(|m| m.id)
+                .collect(),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+}
+
+impl Drop for NodeProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn eventually(mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[test]
+fn test_cluster() {
+    let a = NodeProcess::start("a", None);
+    let b = NodeProcess::start("b", Some(&a));
+    let c = NodeProcess::start("c", Some(&b));
+    assert!(eventually(|| a.alive() == vec!["a", "b", "c"]));
+
+    let code = iridium::assembler::Assembler::new()
+        .assemble("load $0 #42\nhlt")
+        .unwrap();
+    let bytecode = iridium::bytecode::Bytecode {
+        code,
+        ..Default::default()
+    };
+    let deploy = |secret: &str| Message::Deploy {
+        to: Some("c".to_string()),
+        bytecode: bytecode.to_bytes(),
+        secret: Some(secret.to_string()),
+        forwarded: false,
+    };
+    assert_eq!(
+        request(&a.addr, &deploy("guess")).unwrap(),
+        Message::Error("wrong cluster secret".to_string())
+    );
+    let pid = match request(&a.addr, &deploy(SECRET)).unwrap() {
+        Message::Deployed { node, pid } if node == "c" => pid,
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    assert!(eventually(|| matches!(
+        request(&c.addr, &Message::Status { pid }).unwrap(),
+        Message::Process(p) if p.state == ProcessState::Halted && p.registers[0] == 42
+    )));
+
+    drop(c);
+    assert!(eventually(|| a.alive() == vec!["a", "b"]));
+    assert!(eventually(|| b.alive() == vec!["b", "a"]));
+}